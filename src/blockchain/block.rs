@@ -1,19 +1,133 @@
 use crate::blockchain;
 use crate::blockchain::transaction::Transaction;
-use crate::util::constants::DIFFICULTY;
-use crate::util::traits::Hashable;
+use crate::util::encoding::{DecodeError, Decoder, Encoder};
+use crate::util::hex_bytes;
+use crate::util::traits::{Decodable, Hashable};
 use crate::util::types::Bytes;
 
 use chrono::Utc;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 
-#[derive(Clone, Debug, Serialize)]
+/// The fields of a block that its hash covers, with the Merkle root of its
+/// transactions in place of the transactions themselves
+///
+/// Nodes download the headers of a chain first, and check their proof of work
+/// before they fetch the blocks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockHeader {
+    pub index: i32,
+    pub timestamp: i64,
+    pub prev_hash: Bytes,
+    pub merkle_root: Bytes,
+    pub nonce: u64,
+    difficulty: u128,
+    pub hash: Bytes,
+}
+
+impl BlockHeader {
+    /// Returns the target the block hash has to be below
+    pub fn difficulty(&self) -> u128 {
+        self.difficulty
+    }
+
+    /// Returns the expected number of hashes needed to mine the block
+    pub fn work(&self) -> u128 {
+        work_for_difficulty(self.difficulty)
+    }
+
+    /// Checks whether the stored hash satisfies the proof of work condition
+    pub fn meets_difficulty(&self) -> bool {
+        hash_meets_difficulty(&self.hash, self.difficulty)
+    }
+}
+
+impl Hashable for BlockHeader {
+    fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.put_i32(self.index);
+        encoder.put_i64(self.timestamp);
+        encoder.put_bytes(&self.prev_hash);
+        encoder.put_bytes(&self.merkle_root);
+        encoder.put_u64(self.nonce);
+        encoder.put_u128(self.difficulty);
+        encoder.put_bytes(&self.hash);
+        encoder.finish()
+    }
+
+    /// Recomputes the block hash from the header fields
+    fn hash(&self) -> Vec<u8> {
+        crypto_hash::digest(
+            crypto_hash::Algorithm::SHA256,
+            &encode_header(
+                self.index,
+                self.timestamp,
+                &self.prev_hash,
+                &self.merkle_root,
+                self.nonce,
+                self.difficulty,
+            ),
+        )
+    }
+}
+
+impl Decodable for BlockHeader {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        Ok(BlockHeader {
+            index: decoder.get_i32()?,
+            timestamp: decoder.get_i64()?,
+            prev_hash: decoder.get_bytes()?,
+            merkle_root: decoder.get_bytes()?,
+            nonce: decoder.get_u64()?,
+            difficulty: decoder.get_u128()?,
+            hash: decoder.get_bytes()?,
+        })
+    }
+}
+
+/// Returns the encoding of the fields covered by the block hash
+fn encode_header(
+    index: i32,
+    timestamp: i64,
+    prev_hash: &[u8],
+    merkle_root: &[u8],
+    nonce: u64,
+    difficulty: u128,
+) -> Bytes {
+    let mut encoder = Encoder::new();
+    encoder.put_i32(index);
+    encoder.put_i64(timestamp);
+    encoder.put_bytes(prev_hash);
+    encoder.put_bytes(merkle_root);
+    encoder.put_u64(nonce);
+    encoder.put_u128(difficulty);
+    encoder.finish()
+}
+
+fn work_for_difficulty(difficulty: u128) -> u128 {
+    u128::max_value() / difficulty.max(1)
+}
+
+fn hash_meets_difficulty(hash: &[u8], difficulty: u128) -> bool {
+    if hash.len() < 16 {
+        return false;
+    }
+
+    u128::from_be_bytes(
+        hash[0..16]
+            .try_into()
+            .expect("Cannot convert &[u8] to [u8; 16]"),
+    ) < difficulty
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Block {
     pub index: i32,
     pub timestamp: i64, // i32 is sufficient until Jan 19, 2038. But chrono uses i64
+    #[serde(with = "hex_bytes")]
     pub hash: Vec<u8>,
     pub transactions: Vec<Transaction>,
+    #[serde(with = "hex_bytes")]
     pub prev_hash: Vec<u8>,
     pub nonce: u64,
     difficulty: u128,
@@ -21,7 +135,12 @@ pub struct Block {
 
 impl Block {
     /// Returns a new unmined block
-    pub fn new(transactions: Vec<Transaction>, index: i32, prev_hash: Vec<u8>) -> Self {
+    pub fn new(
+        transactions: Vec<Transaction>,
+        index: i32,
+        prev_hash: Vec<u8>,
+        difficulty: u128,
+    ) -> Self {
         Block {
             index,
             timestamp: Utc::now().timestamp(),
@@ -29,38 +148,100 @@ impl Block {
             transactions,
             prev_hash,
             nonce: 0,
-            difficulty: DIFFICULTY,
+            difficulty,
         }
     }
 
     /// Returns a new mined block
     ///
     /// Equivalent to calling b = Block::new() followed by b.mine()
-    pub fn create(transactions: Vec<Transaction>, index: i32, prev_hash: Vec<u8>) -> Self {
-        let mut block = Block::new(transactions, index, prev_hash);
+    pub fn create(
+        transactions: Vec<Transaction>,
+        index: i32,
+        prev_hash: Vec<u8>,
+        difficulty: u128,
+    ) -> Self {
+        let mut block = Block::new(transactions, index, prev_hash, difficulty);
         block.mine();
         block
     }
 
-    /// Creates a genesis block
-    pub fn create_genesis_block(coinbase: Transaction) -> Block {
-        Block::new(vec![coinbase], 0, vec![])
+    /// Creates a mined genesis block
+    pub fn create_genesis_block(coinbase: Transaction, difficulty: u128) -> Block {
+        Block::create(vec![coinbase], 0, vec![], difficulty)
     }
 
     /// Sets the nonce and hash that satisfies the proof of work condition
+    ///
+    /// Blocks the current thread, see `miner::Miner` to mine in the background
     pub fn mine(&mut self) {
+        let merkle_root = self.merkle_root();
+
         for nonce in 0..(u64::max_value()) {
             self.nonce = nonce;
-            self.hash = self.hash();
-            let hash = &self.hash.to_vec()[0..16];
+            self.hash = self.hash_with_merkle_root(&merkle_root);
 
-            if u128::from_be_bytes(hash.try_into().expect("Cannot convert &[u8] to [u8; 16]"))
-                < self.difficulty
-            {
+            if self.meets_difficulty() {
                 return;
             }
         }
     }
+
+    /// Returns the target the block hash has to be below
+    pub fn difficulty(&self) -> u128 {
+        self.difficulty
+    }
+
+    /// Returns the expected number of hashes needed to mine the block
+    pub fn work(&self) -> u128 {
+        work_for_difficulty(self.difficulty)
+    }
+
+    pub fn merkle_root(&self) -> Bytes {
+        let txn_encodings = self
+            .transactions
+            .iter()
+            .map(|txn| txn.encode())
+            .collect::<Vec<Bytes>>();
+
+        blockchain::merkle::MerkleTree::new(txn_encodings).root.data
+    }
+
+    /// Computes the block hash with a precomputed Merkle root, which saves
+    /// rebuilding the Merkle tree for every nonce while mining
+    pub fn hash_with_merkle_root(&self, merkle_root: &[u8]) -> Bytes {
+        crypto_hash::digest(
+            crypto_hash::Algorithm::SHA256,
+            &encode_header(
+                self.index,
+                self.timestamp,
+                &self.prev_hash,
+                merkle_root,
+                self.nonce,
+                self.difficulty,
+            ),
+        )
+    }
+
+    /// Checks whether the stored hash satisfies the proof of work condition
+    ///
+    /// This does not recompute the hash, see `BlockChain::check_block`
+    pub fn meets_difficulty(&self) -> bool {
+        hash_meets_difficulty(&self.hash, self.difficulty)
+    }
+
+    /// Returns the header of the block, with the stored hash
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            timestamp: self.timestamp,
+            prev_hash: self.prev_hash.clone(),
+            merkle_root: self.merkle_root(),
+            nonce: self.nonce,
+            difficulty: self.difficulty,
+            hash: self.hash.clone(),
+        }
+    }
 }
 
 impl std::fmt::Display for Block {
@@ -90,21 +271,125 @@ impl std::fmt::Display for Block {
 
 impl Hashable for Block {
     fn encode(&self) -> Vec<u8> {
-        let mut bytes: Vec<u8> = Vec::new();
+        let mut encoder = Encoder::new();
+
+        encoder.put_i32(self.index);
+        encoder.put_i64(self.timestamp);
+        encoder.put_bytes(&self.prev_hash);
+        encoder.put_u64(self.nonce);
+        encoder.put_u128(self.difficulty);
+        encoder.put_bytes(&self.hash);
 
-        // TODO: Change to functional style (using map and collect into Vec)
-        let mut txn_hashes = Vec::<Bytes>::new();
+        encoder.put_len(self.transactions.len());
         for txn in self.transactions.iter() {
-            txn_hashes.push(txn.encode());
+            encoder.put_encoded(&txn.encode());
         }
 
-        let tree = blockchain::merkle::MerkleTree::new(txn_hashes);
+        encoder.finish()
+    }
 
-        bytes.extend(&self.prev_hash);
-        bytes.extend(tree.root.data);
-        bytes.extend(&self.nonce.to_le_bytes());
-        bytes.extend(&self.difficulty.to_le_bytes());
+    /// Hashes the block header, which commits to the transactions through
+    /// their Merkle root
+    fn hash(&self) -> Vec<u8> {
+        self.hash_with_merkle_root(&self.merkle_root())
+    }
+}
+
+impl Decodable for Block {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let index = decoder.get_i32()?;
+        let timestamp = decoder.get_i64()?;
+        let prev_hash = decoder.get_bytes()?;
+        let nonce = decoder.get_u64()?;
+        let difficulty = decoder.get_u128()?;
+        let hash = decoder.get_bytes()?;
+
+        let mut transactions = Vec::new();
+        for _ in 0..decoder.get_len()? {
+            transactions.push(Transaction::decode_from(decoder)?);
+        }
+
+        Ok(Block {
+            index,
+            timestamp,
+            hash,
+            transactions,
+            prev_hash,
+            nonce,
+            difficulty,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::wallet::Wallet;
+    use crate::util::constants::VERSION;
+
+    /// Returns an unmined block with a coinbase and another transaction
+    fn sample_block() -> Block {
+        let address = Wallet::encode_address(VERSION, &vec![1; 20]);
+        let coinbase = Transaction::create_coinbase_txn(&address, 50);
+        let mut payment = Transaction::create_coinbase_txn(&address, 7);
+        payment.inputs[0].id = coinbase.id.clone();
+        payment.inputs[0].out = 0;
+        payment.update_id();
+
+        let mut block = Block::new(vec![coinbase, payment], 3, vec![2; 32], 1 << 100);
+        block.nonce = 12345;
+        block.hash = block.hash();
+        block
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        let block = sample_block();
+        let decoded = Block::decode(&block.encode()).unwrap();
+
+        assert_eq!(decoded.encode(), block.encode());
+        assert_eq!(decoded.hash(), block.hash);
+        assert_eq!(decoded.difficulty(), block.difficulty());
+        assert_eq!(decoded.header(), block.header());
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let header = sample_block().header();
+        assert_eq!(BlockHeader::decode(&header.encode()), Ok(header));
+    }
+
+    #[test]
+    fn truncated_blocks_and_headers_are_rejected() {
+        let block = sample_block();
+
+        let bytes = block.encode();
+        for len in 0..bytes.len() {
+            match Block::decode(&bytes[..len]) {
+                Err(DecodeError::UnexpectedEnd { .. }) => (),
+                result => panic!("decoded {} of {} bytes: {:?}", len, bytes.len(), result),
+            }
+        }
+
+        let bytes = block.header().encode();
+        for len in 0..bytes.len() {
+            match BlockHeader::decode(&bytes[..len]) {
+                Err(DecodeError::UnexpectedEnd { .. }) => (),
+                result => panic!("decoded {} of {} bytes: {:?}", len, bytes.len(), result),
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        let mut bytes = sample_block().encode();
+        bytes.extend_from_slice(&[0, 0]);
+        match Block::decode(&bytes) {
+            Err(DecodeError::TrailingBytes { remaining: 2 }) => (),
+            result => panic!("expected trailing bytes, got {:?}", result),
+        }
 
-        bytes
+        assert!(Block::decode(&[0xff; 128]).is_err());
+        assert!(BlockHeader::decode(&[0xff; 128]).is_err());
     }
 }
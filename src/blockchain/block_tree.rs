@@ -0,0 +1,77 @@
+use crate::blockchain::block::Block;
+use crate::util::types::Bytes;
+
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub struct BlockTreeEntry {
+    pub block: Block,
+    pub total_work: u128, // work of the block and all of its ancestors
+    pub invalid: bool,    // the block or one of its ancestors failed to connect
+}
+
+/// Every known block that connects to the genesis block, keyed by the hex
+/// encoded block hash
+///
+/// The active chain is one path through the tree. Side branches are kept so
+/// that the node can switch to them once they accumulate more work.
+#[derive(Clone, Debug, Default)]
+pub struct BlockTree {
+    entries: HashMap<String, BlockTreeEntry>,
+    children: HashMap<String, Vec<String>>, // hashes of the known children of each block
+}
+
+impl BlockTree {
+    pub fn contains(&self, hash: &Bytes) -> bool {
+        self.entries.contains_key(&hex::encode(hash))
+    }
+
+    pub fn get(&self, hash: &Bytes) -> Option<&BlockTreeEntry> {
+        self.entries.get(&hex::encode(hash))
+    }
+
+    /// Inserts a block whose parent is already in the tree (or a genesis
+    /// block) and returns its total work
+    pub fn insert(&mut self, block: Block) -> u128 {
+        let key = hex::encode(&block.hash);
+        if let Some(entry) = self.entries.get(&key) {
+            return entry.total_work;
+        }
+
+        let (parent_work, invalid) = self
+            .get(&block.prev_hash)
+            .map_or((0, false), |e| (e.total_work, e.invalid));
+        let total_work = parent_work.saturating_add(block.work());
+
+        self.children
+            .entry(hex::encode(&block.prev_hash))
+            .or_default()
+            .push(key.clone());
+        self.entries.insert(
+            key,
+            BlockTreeEntry {
+                block,
+                total_work,
+                invalid,
+            },
+        );
+
+        total_work
+    }
+
+    /// Marks a block and all of its known descendants as invalid
+    pub fn mark_invalid(&mut self, hash: &Bytes) {
+        let mut invalid_hashes = vec![hex::encode(hash)];
+
+        while let Some(key) = invalid_hashes.pop() {
+            match self.entries.get_mut(&key) {
+                Some(entry) if !entry.invalid => entry.invalid = true,
+                _ => continue,
+            }
+
+            if let Some(children) = self.children.get(&key) {
+                invalid_hashes.extend(children.iter().cloned());
+            }
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use crate::blockchain::block::BlockHeader;
+use crate::util::types::Bytes;
+
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub struct HeaderTreeEntry {
+    pub header: BlockHeader,
+    pub total_work: u128, // work of the block and all of its ancestors
+    pub invalid: bool,    // the block or one of its ancestors failed to connect
+}
+
+/// The headers of every known block that connects to the genesis block, keyed
+/// by the hex encoded block hash
+///
+/// Holds the headers of the blocks in the block tree, and the headers of
+/// blocks that are still being downloaded, so it can run ahead of the block
+/// tree.
+#[derive(Clone, Debug, Default)]
+pub struct HeaderTree {
+    entries: HashMap<String, HeaderTreeEntry>,
+    children: HashMap<String, Vec<String>>, // hashes of the known children of each header
+    best: Option<String>,                   // hash of the valid header with the most work
+}
+
+impl HeaderTree {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, hash: &Bytes) -> bool {
+        self.entries.contains_key(&hex::encode(hash))
+    }
+
+    pub fn get(&self, hash: &Bytes) -> Option<&HeaderTreeEntry> {
+        self.entries.get(&hex::encode(hash))
+    }
+
+    /// Inserts a header whose parent is already in the tree (or the header of
+    /// a genesis block) and returns its total work
+    pub fn insert(&mut self, header: BlockHeader) -> u128 {
+        let key = hex::encode(&header.hash);
+        if let Some(entry) = self.entries.get(&key) {
+            return entry.total_work;
+        }
+
+        let (parent_work, invalid) = self
+            .get(&header.prev_hash)
+            .map_or((0, false), |e| (e.total_work, e.invalid));
+        let total_work = parent_work.saturating_add(header.work());
+
+        self.children
+            .entry(hex::encode(&header.prev_hash))
+            .or_default()
+            .push(key.clone());
+
+        // The first header to reach the most work stays the best
+        if !invalid
+            && self
+                .best()
+                .map_or(true, |best| total_work > best.total_work)
+        {
+            self.best = Some(key.clone());
+        }
+        self.entries.insert(
+            key,
+            HeaderTreeEntry {
+                header,
+                total_work,
+                invalid,
+            },
+        );
+
+        total_work
+    }
+
+    /// Returns the valid header with the most accumulated work
+    pub fn best(&self) -> Option<&HeaderTreeEntry> {
+        self.best.as_ref().and_then(|key| self.entries.get(key))
+    }
+
+    /// Marks a header and all of its known descendants as invalid
+    pub fn mark_invalid(&mut self, hash: &Bytes) {
+        let mut invalid_hashes = vec![hex::encode(hash)];
+
+        while let Some(key) = invalid_hashes.pop() {
+            match self.entries.get_mut(&key) {
+                Some(entry) if !entry.invalid => entry.invalid = true,
+                _ => continue,
+            }
+
+            if let Some(children) = self.children.get(&key) {
+                invalid_hashes.extend(children.iter().cloned());
+            }
+        }
+
+        // Only a scan finds the best header of the branches that are left
+        if self.best().map_or(false, |best| best.invalid) {
+            self.best = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| !entry.invalid)
+                .max_by_key(|(_, entry)| entry.total_work)
+                .map(|(key, _)| key.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::Block;
+    use crate::blockchain::transaction::Transaction;
+    use crate::blockchain::wallet::Wallet;
+    use crate::util::constants::{DIFFICULTY, VERSION};
+
+    fn child(parent: &BlockHeader) -> BlockHeader {
+        let address = Wallet::encode_address(VERSION, &vec![1; 20]);
+        let coinbase = Transaction::create_coinbase_txn(&address, 1);
+        Block::create(
+            vec![coinbase],
+            parent.index + 1,
+            parent.hash.clone(),
+            DIFFICULTY,
+        )
+        .header()
+    }
+
+    #[test]
+    fn invalid_branches_lose_the_best_header() {
+        let address = Wallet::encode_address(VERSION, &vec![1; 20]);
+        let coinbase = Transaction::create_coinbase_txn(&address, 1);
+        let genesis = Block::create_genesis_block(coinbase, DIFFICULTY).header();
+
+        let a1 = child(&genesis);
+        let a2 = child(&a1);
+        let b1 = child(&genesis);
+
+        let mut tree = HeaderTree::default();
+        for header in [&genesis, &a1, &a2, &b1].iter() {
+            tree.insert((*header).clone());
+        }
+        assert_eq!(tree.best().unwrap().header.hash, a2.hash);
+
+        tree.mark_invalid(&a1.hash);
+        assert!(tree.get(&a2.hash).unwrap().invalid);
+        assert_eq!(tree.best().unwrap().header.hash, b1.hash);
+
+        // Headers that build on an invalid branch are invalid as well
+        tree.insert(child(&a2));
+        assert_eq!(tree.best().unwrap().header.hash, b1.hash);
+    }
+}
@@ -0,0 +1,407 @@
+use crate::blockchain::transaction::Transaction;
+use crate::blockchain::txn::OutPoint;
+use crate::blockchain::validation::{LockPoints, TransactionValidationError};
+use crate::blockchain::{BlockChain, ChainUpdate};
+use crate::util::constants::MAX_MEMPOOL_TRANSACTIONS;
+use crate::util::traits::Hashable;
+use crate::util::types::Bytes;
+
+use chrono::Utc;
+use log::info;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+/// The reason a transaction was not added to the mempool
+#[derive(Clone, Debug, PartialEq)]
+pub enum MempoolError {
+    AlreadyKnown, // pending or already mined
+    Full,
+    UnexpectedCoinbase,
+    Conflict { txn_id: String },
+    InsufficientReplacementFee { txn_id: String },
+    Invalid(TransactionValidationError),
+}
+
+impl std::fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::AlreadyKnown => write!(f, "transaction is already known"),
+            Self::Full => write!(f, "mempool is full"),
+            Self::UnexpectedCoinbase => write!(f, "coinbase transactions cannot be relayed"),
+            Self::Conflict { txn_id } => {
+                write!(
+                    f,
+                    "transaction conflicts with pending transaction {}",
+                    txn_id
+                )
+            }
+            Self::InsufficientReplacementFee { txn_id } => {
+                write!(
+                    f,
+                    "transaction does not pay a higher fee and fee rate than the replaced transaction {}",
+                    txn_id
+                )
+            }
+            Self::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MempoolEntry {
+    txn: Transaction,
+    fee: i32,
+    size: usize, // bytes, in the binary encoding
+    lock_points: LockPoints,
+}
+
+impl MempoolEntry {
+    /// Compares the fee per byte of two entries
+    fn cmp_fee_rate(&self, other: &MempoolEntry) -> Ordering {
+        (self.fee as i64 * other.size as i64).cmp(&(other.fee as i64 * self.size as i64))
+    }
+}
+
+/// Transactions waiting to be mined, keyed by the hex encoded transaction id
+///
+/// Transactions may spend the outputs of other pending transactions. `order`
+/// keeps the transactions in the order they were added, which puts parents
+/// before their children.
+#[derive(Debug, Default)]
+pub struct Mempool {
+    txns: HashMap<String, MempoolEntry>,
+    order: Vec<String>,
+}
+
+impl Mempool {
+    pub fn contains(&self, id: &Bytes) -> bool {
+        self.txns.contains_key(&hex::encode(id))
+    }
+
+    pub fn get(&self, id: &Bytes) -> Option<&Transaction> {
+        self.txns.get(&hex::encode(id)).map(|entry| &entry.txn)
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Returns the pending transactions, parents before children
+    pub fn transactions(&self) -> Vec<&Transaction> {
+        self.order.iter().map(|id| &self.txns[id].txn).collect()
+    }
+
+    /// Returns the outputs that pending transactions spend
+    pub fn spent_outpoints(&self) -> HashSet<OutPoint> {
+        self.txns
+            .values()
+            .flat_map(|entry| entry.txn.inputs.iter().map(|input| input.outpoint()))
+            .collect()
+    }
+
+    /// Returns the ids of the pending transactions that spend one of the
+    /// same outputs as `txn`
+    pub fn find_conflicts(&self, txn: &Transaction) -> Vec<String> {
+        self.transactions()
+            .into_iter()
+            .filter(|pending| {
+                pending.inputs.iter().any(|p| {
+                    txn.inputs
+                        .iter()
+                        .any(|input| input.id == p.id && input.out == p.out)
+                })
+            })
+            .map(|pending| hex::encode(&pending.id))
+            .collect()
+    }
+
+    /// Returns the given transactions and the pending transactions that spend
+    /// their outputs, directly or through other pending transactions, in the
+    /// order they were added
+    fn with_descendants(&self, ids: &[String]) -> Vec<String> {
+        let mut found = ids.iter().cloned().collect::<HashSet<String>>();
+
+        for id in self.order.iter() {
+            let is_descendant = self.txns[id]
+                .txn
+                .inputs
+                .iter()
+                .any(|input| found.contains(&hex::encode(&input.id)));
+
+            if is_descendant {
+                found.insert(id.clone());
+            }
+        }
+
+        self.order
+            .iter()
+            .filter(|id| found.contains(*id))
+            .cloned()
+            .collect()
+    }
+
+    /// Validates a transaction against the chain and the pending
+    /// transactions, and adds it to the pool
+    ///
+    /// A transaction that spends the same outputs as pending transactions
+    /// replaces them if they all opted in to replace-by-fee, and it pays a
+    /// higher fee than them and their descendants together, and a higher fee
+    /// per byte than each of them. The replaced transactions and their
+    /// descendants are evicted.
+    pub fn add(&mut self, txn: Transaction, chain: &BlockChain) -> Result<(), MempoolError> {
+        if self.contains(&txn.id) || chain.has_unspent_outputs(&txn) {
+            return Err(MempoolError::AlreadyKnown);
+        }
+
+        if txn.is_coinbase() {
+            return Err(MempoolError::UnexpectedCoinbase);
+        }
+
+        if txn.id != txn.compute_id() {
+            return Err(MempoolError::Invalid(TransactionValidationError::InvalidId));
+        }
+
+        let conflicts = self.find_conflicts(&txn);
+        if let Some(txn_id) = conflicts
+            .iter()
+            .find(|id| !self.txns[*id].txn.is_replaceable())
+        {
+            return Err(MempoolError::Conflict {
+                txn_id: txn_id.clone(),
+            });
+        }
+
+        let replaced = self.with_descendants(&conflicts);
+
+        if self.len() - replaced.len() >= MAX_MEMPOOL_TRANSACTIONS {
+            return Err(MempoolError::Full);
+        }
+
+        // The replacement can't spend the outputs of the transactions it evicts
+        let pending = self
+            .txns
+            .iter()
+            .filter(|(id, _)| !replaced.contains(id))
+            .map(|(id, entry)| (id.clone(), &entry.txn))
+            .collect::<HashMap<String, &Transaction>>();
+
+        let fee = chain
+            .validate_transaction(&txn, &pending)
+            .map_err(MempoolError::Invalid)?;
+
+        // Transactions that are locked are kept until they can be mined
+        let lock_points = chain.lock_points(&txn, &pending, Utc::now().timestamp());
+
+        let id = hex::encode(&txn.id);
+        let entry = MempoolEntry {
+            size: txn.encode().len(),
+            txn,
+            fee,
+            lock_points,
+        };
+
+        let replaced_fee: i64 = replaced.iter().map(|id| self.txns[id].fee as i64).sum();
+        for conflict in conflicts.iter() {
+            if fee as i64 <= replaced_fee
+                || entry.cmp_fee_rate(&self.txns[conflict]) != Ordering::Greater
+            {
+                return Err(MempoolError::InsufficientReplacementFee {
+                    txn_id: conflict.clone(),
+                });
+            }
+        }
+
+        for replaced_id in replaced.iter() {
+            self.txns.remove(replaced_id);
+            info!(
+                "[Mempool] Evicted transaction {}: replaced by {}",
+                replaced_id, id
+            );
+        }
+        self.order.retain(|id| !replaced.contains(id));
+
+        self.order.push(id.clone());
+        self.txns.insert(id, entry);
+
+        Ok(())
+    }
+
+    /// Returns the transactions to include in the block at `height` with the
+    /// timestamp `time`, and their total fee
+    ///
+    /// Transactions with a higher fee per byte are picked first, as long as
+    /// they fit in `max_size` bytes, and their fees added to `subsidy` fit in
+    /// a value. A transaction is only picked after its pending parents, and
+    /// comes after them in the returned list. Transactions whose locks are
+    /// not reached at `height` and `time` are left in the pool.
+    pub fn select(
+        &self,
+        max_size: usize,
+        height: i32,
+        time: i64,
+        subsidy: i32,
+    ) -> (Vec<Transaction>, i32) {
+        let mut candidates = self.order.iter().collect::<Vec<&String>>();
+        // Stable, so transactions with the same fee rate keep their order
+        candidates.sort_by(|a, b| self.txns[*b].cmp_fee_rate(&self.txns[*a]));
+
+        let mut selected = HashSet::<&String>::new();
+        let mut txns = Vec::<Transaction>::new();
+        let mut size: usize = 0;
+        let mut fees: i32 = 0;
+
+        // Children that come before their parents are picked up in a later pass
+        loop {
+            let mut progress = false;
+
+            for id in candidates.iter() {
+                let entry = &self.txns[*id];
+
+                let reward = (subsidy + fees).checked_add(entry.fee);
+                if selected.contains(id)
+                    || size + entry.size > max_size
+                    || reward.is_none()
+                    || !entry.lock_points.is_reached(height, time)
+                {
+                    continue;
+                }
+
+                let has_missing_parent = entry.txn.inputs.iter().any(|input| {
+                    let parent = hex::encode(&input.id);
+                    self.txns.contains_key(&parent) && !selected.contains(&parent)
+                });
+
+                if has_missing_parent {
+                    continue;
+                }
+
+                selected.insert(id);
+                txns.push(entry.txn.clone());
+                size += entry.size;
+                fees += entry.fee;
+                progress = true;
+            }
+
+            if !progress {
+                break;
+            }
+        }
+
+        (txns, fees)
+    }
+
+    /// Brings the pool in line with a chain that has changed
+    ///
+    /// Transactions that were mined are removed, and transactions that
+    /// conflict with mined transactions are evicted with their descendants.
+    /// After a reorganization, the transactions of the disconnected blocks
+    /// are returned to the pool, and every pending transaction is validated
+    /// again, as the outputs it spends may no longer exist.
+    pub fn update(&mut self, chain: &BlockChain, update: &ChainUpdate) {
+        let mined = update
+            .connected
+            .iter()
+            .flat_map(|block| block.transactions.iter())
+            .map(|txn| hex::encode(&txn.id))
+            .collect::<HashSet<String>>();
+
+        let spent = update
+            .connected
+            .iter()
+            .flat_map(|block| block.transactions.iter())
+            .filter(|txn| !txn.is_coinbase())
+            .flat_map(|txn| txn.inputs.iter().map(|input| input.outpoint()))
+            .collect::<HashSet<OutPoint>>();
+
+        let conflicts = self
+            .order
+            .iter()
+            .filter(|id| {
+                !mined.contains(*id)
+                    && self.txns[*id]
+                        .txn
+                        .inputs
+                        .iter()
+                        .any(|input| spent.contains(&input.outpoint()))
+            })
+            .cloned()
+            .collect::<Vec<String>>();
+
+        for id in self.with_descendants(&conflicts).iter() {
+            self.txns.remove(id);
+            info!(
+                "[Mempool] Evicted transaction {}: conflicts with a mined transaction",
+                id
+            );
+        }
+        for id in mined.iter() {
+            self.txns.remove(id);
+        }
+        let txns = &self.txns;
+        self.order.retain(|id| txns.contains_key(id));
+
+        if update.disconnected.is_empty() {
+            self.update_lock_points(chain, &mined);
+            return;
+        }
+
+        let mut candidates = update
+            .disconnected
+            .iter()
+            .rev()
+            .flat_map(|block| block.transactions.iter())
+            .filter(|txn| !txn.is_coinbase())
+            .cloned()
+            .collect::<Vec<Transaction>>();
+
+        for id in self.order.drain(..) {
+            if let Some(entry) = self.txns.remove(&id) {
+                candidates.push(entry.txn);
+            }
+        }
+
+        for txn in candidates.into_iter() {
+            let id = hex::encode(&txn.id);
+
+            match self.add(txn, chain) {
+                Ok(()) | Err(MempoolError::AlreadyKnown) => (),
+                Err(err) => info!("[Mempool] Evicted transaction {}: {}", id, err),
+            }
+        }
+    }
+
+    /// Recomputes the lock points of the transactions that spend outputs of
+    /// `mined` transactions, which were treated as mined in the next block
+    /// when they were added
+    fn update_lock_points(&mut self, chain: &BlockChain, mined: &HashSet<String>) {
+        let time = Utc::now().timestamp();
+        let pending = self
+            .txns
+            .iter()
+            .map(|(id, entry)| (id.clone(), &entry.txn))
+            .collect::<HashMap<String, &Transaction>>();
+
+        let lock_points = self
+            .txns
+            .iter()
+            .filter(|(_, entry)| {
+                entry
+                    .txn
+                    .inputs
+                    .iter()
+                    .any(|input| mined.contains(&hex::encode(&input.id)))
+            })
+            .map(|(id, entry)| (id.clone(), chain.lock_points(&entry.txn, &pending, time)))
+            .collect::<Vec<(String, LockPoints)>>();
+
+        for (id, lock_points) in lock_points.into_iter() {
+            if let Some(entry) = self.txns.get_mut(&id) {
+                entry.lock_points = lock_points;
+            }
+        }
+    }
+}
@@ -42,36 +42,46 @@ impl MerkleNode {
 }
 
 impl MerkleTree {
-    pub fn new(mut data: Vec<Bytes>) -> Self {
-        let mut nodes = Vec::<MerkleNode>::new();
-
-        if data.len() % 2 != 0 {
-            let last_transaction = data[data.len() - 1].clone();
-            data.push(last_transaction);
+    pub fn new(data: Vec<Bytes>) -> Self {
+        // A tree without leaves has the hash of no data as its root
+        if data.is_empty() {
+            return MerkleTree {
+                root: MerkleNode::new(None, None, &vec![]),
+            };
         }
 
+        let mut nodes = Vec::<MerkleNode>::new();
+
         for row in data.iter() {
             let node = MerkleNode::new(None, None, row);
             nodes.push(node);
         }
 
-        for _ in 0..(data.len() / 2) {
+        // Pair up the nodes of each level. The last node of a level with an
+        // odd number of nodes moves up unchanged: repeating it would give a
+        // list that ends in a duplicated pair the same root as the list
+        // without the duplicate.
+        loop {
             let mut level = Vec::<MerkleNode>::new();
 
-            for j in (0..data.len()).step_by(2) {
-                let left = &nodes[j];
-                let right = &nodes[j + 1];
-
-                let node = MerkleNode::new(
-                    Some(Box::new(left.clone())),
-                    Some(Box::new(right.clone())),
-                    &vec![],
-                );
+            for pair in nodes.chunks(2) {
+                let node = match pair {
+                    [left, right] => MerkleNode::new(
+                        Some(Box::new(left.clone())),
+                        Some(Box::new(right.clone())),
+                        &vec![],
+                    ),
+                    _ => pair[0].clone(),
+                };
 
                 level.push(node);
             }
 
             nodes = level;
+
+            if nodes.len() == 1 {
+                break;
+            }
         }
 
         MerkleTree {
@@ -79,3 +89,22 @@ impl MerkleTree {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_tree_has_the_hash_of_no_data_as_its_root() {
+        let tree = MerkleTree::new(vec![]);
+        let expected = crypto_hash::digest(crypto_hash::Algorithm::SHA256, &[]);
+        assert_eq!(tree.root.data, expected);
+    }
+
+    #[test]
+    fn a_duplicated_last_node_changes_the_root() {
+        let three = MerkleTree::new(vec![vec![1], vec![2], vec![3]]);
+        let four = MerkleTree::new(vec![vec![1], vec![2], vec![3], vec![3]]);
+        assert_ne!(three.root.data, four.root.data);
+    }
+}
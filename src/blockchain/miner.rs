@@ -0,0 +1,125 @@
+use crate::blockchain::block::Block;
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The number of hashes a mining run computed and how long it took
+#[derive(Clone, Debug)]
+pub struct MiningStats {
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningStats {
+    /// Returns the hashes computed per second
+    pub fn hash_rate(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.hashes as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+/// A handle to a running mining job
+pub struct MiningJob {
+    stop: Arc<AtomicBool>,
+}
+
+impl MiningJob {
+    /// Stops the worker threads. The job then finishes without a block.
+    pub fn cancel(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Searches for a nonce that satisfies a block's difficulty on several worker
+/// threads
+///
+/// Worker `i` of `n` tries the nonces `i`, `i + n`, `i + 2n`, ... so that the
+/// workers never hash the same header twice.
+#[derive(Clone, Debug)]
+pub struct Miner {
+    threads: usize,
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Self {
+        Miner {
+            threads: threads.max(1),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Starts mining `block` in the background and returns immediately
+    ///
+    /// `on_done` is called from a background thread with the mined block, or
+    /// with `None` if the job was cancelled first.
+    pub fn start<F>(&self, block: Block, on_done: F) -> MiningJob
+    where
+        F: FnOnce(Option<Block>, MiningStats) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let hashes = Arc::new(AtomicU64::new(0));
+        let threads = self.threads;
+        let job_stop = stop.clone();
+
+        thread::spawn(move || {
+            let started = Instant::now();
+            let merkle_root = Arc::new(block.merkle_root());
+
+            let workers = (0..threads)
+                .map(|worker| {
+                    let mut block = block.clone();
+                    let merkle_root = merkle_root.clone();
+                    let stop = stop.clone();
+                    let hashes = hashes.clone();
+
+                    thread::spawn(move || {
+                        let mut nonce = worker as u64;
+
+                        while !stop.load(Ordering::Relaxed) {
+                            block.nonce = nonce;
+                            block.hash = block.hash_with_merkle_root(&merkle_root);
+                            hashes.fetch_add(1, Ordering::Relaxed);
+
+                            if block.meets_difficulty() {
+                                stop.store(true, Ordering::Relaxed);
+                                return Some(block);
+                            }
+
+                            nonce = match nonce.checked_add(threads as u64) {
+                                Some(next) => next,
+                                None => break,
+                            };
+                        }
+
+                        None
+                    })
+                })
+                .collect::<Vec<thread::JoinHandle<Option<Block>>>>();
+
+            let mut mined = None;
+            for worker in workers.into_iter() {
+                if let Ok(Some(block)) = worker.join() {
+                    mined = mined.or(Some(block));
+                }
+            }
+
+            let stats = MiningStats {
+                hashes: hashes.load(Ordering::Relaxed),
+                elapsed: started.elapsed(),
+            };
+
+            on_done(mined, stats);
+        });
+
+        MiningJob { stop: job_stop }
+    }
+}
@@ -1,247 +1,620 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 
 pub mod block;
+pub mod block_tree;
+pub mod header_tree;
+pub mod mempool;
 pub mod merkle;
+pub mod miner;
+pub mod multisig;
+pub mod orphan_pool;
+pub mod params;
+pub mod script;
+pub mod sighash;
+pub mod storage;
 pub mod transaction;
 pub mod txn;
+pub mod utxo;
+pub mod validation;
 pub mod wallet;
 
-use crate::util::constants::BLOCK_MEMORY_POOL_SIZE;
+use crate::util::hex_bytes;
 use crate::util::types::Bytes;
-use block::Block;
+use block::{Block, BlockHeader};
+use block_tree::BlockTree;
+use header_tree::HeaderTree;
 use log::{info, warn};
-use serde::Serialize;
+use multisig::{Multisig, PartialSignature};
+use orphan_pool::OrphanPool;
+use params::ChainParams;
+use serde::{Deserialize, Serialize};
+use sighash::SigHashType;
 use transaction::Transaction;
-use txn::TxnOutput;
+use txn::{OutPoint, TxnOutput, ValueOutOfRange};
+use utxo::{Balance, BlockUndo, Utxo, UtxoSet};
+use validation::{BlockValidationError, ChainValidationError, TransactionValidationError};
+
+/// The blocks that were connected to and disconnected from the active chain
+/// when a block was accepted
+#[derive(Debug, Default)]
+pub struct ChainUpdate {
+    pub accepted: Vec<Block>, // blocks that were newly added to the block tree
+    pub connected: Vec<Block>,
+    pub disconnected: Vec<Block>,
+}
+
+impl ChainUpdate {
+    /// Folds a later update into this one
+    fn merge(&mut self, later: ChainUpdate) {
+        self.accepted.extend(later.accepted);
+
+        for block in later.disconnected.into_iter() {
+            match self.connected.iter().position(|b| b.hash == block.hash) {
+                Some(idx) => {
+                    self.connected.remove(idx);
+                }
+                None => self.disconnected.push(block),
+            }
+        }
 
-#[derive(Clone, Debug, Default, Serialize)]
+        self.connected.extend(later.connected);
+    }
+}
+
+/// A deserialized chain is replayed with `validate_chain`, which also rebuilds
+/// the block tree and the UTXO set
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(try_from = "BlockChainData")]
 pub struct BlockChain {
-    pub blocks: Vec<Block>,
+    pub blocks: Vec<Block>, // the active chain, i.e. the path to the tip with the most work
+    #[serde(with = "hex_bytes")]
     pub last_hash: Vec<u8>,
     pub length: i32,
-    memory_pool: Vec<Vec<Block>>,
+    #[serde(skip)]
+    tree: BlockTree,
+    #[serde(skip)]
+    headers: HeaderTree,
+    #[serde(skip)]
+    orphans: OrphanPool,
+    #[serde(skip)]
+    utxos: UtxoSet,
+    #[serde(skip)]
+    undo: HashMap<String, BlockUndo>, // keyed by the hex encoded hash of each connected block
+    #[serde(skip)]
+    pub params: ChainParams,
 }
 
-impl BlockChain {
-    pub fn new(address: &Bytes) -> Self {
-        let coinbase_txn = Transaction::create_coinbase_txn(address);
-        let genesis_block = Block::create_genesis_block(coinbase_txn);
+/// The serialized fields of a `BlockChain`
+#[derive(Deserialize)]
+struct BlockChainData {
+    blocks: Vec<Block>,
+    #[serde(with = "hex_bytes")]
+    last_hash: Bytes,
+    length: i32,
+}
 
-        BlockChain {
-            blocks: vec![genesis_block.clone()],
-            last_hash: genesis_block.hash,
-            length: 1,
-            memory_pool: vec![],
+impl TryFrom<BlockChainData> for BlockChain {
+    type Error = ChainValidationError;
+
+    fn try_from(data: BlockChainData) -> Result<Self, Self::Error> {
+        if data.length as i64 != data.blocks.len() as i64 {
+            return Err(ChainValidationError::InvalidLength {
+                length: data.length,
+                blocks: data.blocks.len(),
+            });
+        }
+
+        if data.blocks.last().map_or(vec![], |b| b.hash.clone()) != data.last_hash {
+            return Err(ChainValidationError::InvalidLastHash);
         }
+
+        if data.blocks.is_empty() {
+            return Ok(BlockChain::new_placeholder());
+        }
+
+        BlockChain::validate_chain(&data.blocks, ChainParams::default())
+    }
+}
+
+impl BlockChain {
+    pub fn new(address: &Bytes) -> Self {
+        let mut blockchain = BlockChain::new_placeholder();
+        let coinbase_txn =
+            Transaction::create_coinbase_txn(address, blockchain.params.block_subsidy(0));
+        let genesis_block =
+            Block::create_genesis_block(coinbase_txn, blockchain.params.initial_difficulty());
+
+        blockchain.add_block(genesis_block);
+        blockchain
     }
 
     pub fn new_placeholder() -> Self {
+        BlockChain::with_params(ChainParams::default())
+    }
+
+    /// Returns an empty chain with the given consensus rules
+    pub fn with_params(params: ChainParams) -> Self {
         BlockChain {
             blocks: vec![],
             last_hash: vec![],
             length: 0,
-            memory_pool: vec![],
+            tree: BlockTree::default(),
+            headers: HeaderTree::default(),
+            orphans: OrphanPool::default(),
+            utxos: UtxoSet::default(),
+            undo: HashMap::new(),
+            params,
         }
     }
 
+    /// Appends a block to the active chain without validating it
     pub fn add_block(&mut self, block: Block) {
         let last_hash = block.hash.clone();
+        self.headers.insert(block.header());
+        self.tree.insert(block.clone());
+
+        let undo = self.utxos.apply_block(&block);
+        self.undo.insert(hex::encode(&last_hash), undo);
+
         self.blocks.push(block);
         self.last_hash = last_hash;
         self.length += 1;
     }
 
-    pub fn add_block_to_memory_pool(&mut self, block: Block) {
-        if self.memory_pool.is_empty() {
-            info!("[Blockchain] Memory Pool is empty");
+    /// Removes the last block of the active chain. The block stays in the
+    /// block tree.
+    fn disconnect_tip(&mut self) -> Option<Block> {
+        let block = self.blocks.pop()?;
+
+        let undo = self
+            .undo
+            .remove(&hex::encode(&block.hash))
+            .unwrap_or_default();
+        self.utxos.revert_block(&block, undo);
+
+        self.last_hash = self.blocks.last().map_or(vec![], |b| b.hash.clone());
+        self.length -= 1;
+        Some(block)
+    }
+
+    /// Returns the number of coins in circulation once the chain has
+    /// `height` blocks, i.e. the coins created by the first `height` blocks
+    ///
+    /// The supply of the active chain is `total_supply(self.length)`.
+    pub fn total_supply(&self, height: i32) -> i64 {
+        self.params.supply_at(height)
+    }
+
+    /// Returns the accumulated work of the active chain
+    pub fn total_work(&self) -> u128 {
+        self.tree.get(&self.last_hash).map_or(0, |e| e.total_work)
+    }
+
+    /// Returns the difficulty required of a block on top of the given parent
+    ///
+    /// The difficulty of the parent is kept, except at every
+    /// `retarget_interval` blocks, where it is recomputed from the timestamps
+    /// of the preceding window. Only the headers are needed, so this works for
+    /// headers whose blocks weren't downloaded yet.
+    pub fn next_difficulty(&self, parent_hash: &Bytes) -> u128 {
+        let parent = match self.headers.get(parent_hash) {
+            Some(entry) => &entry.header,
+            None => return self.params.initial_difficulty(),
+        };
+
+        if (parent.index + 1) % self.params.retarget_interval() != 0 {
+            return parent.difficulty();
+        }
+
+        let mut first = parent;
+        for _ in 1..self.params.retarget_interval() {
+            first = match self.headers.get(&first.prev_hash) {
+                Some(entry) => &entry.header,
+                None => break,
+            };
+        }
+
+        self.params
+            .retarget(parent.difficulty(), parent.timestamp - first.timestamp)
+    }
+
+    fn is_in_active_chain(&self, hash: &Bytes) -> bool {
+        match self.tree.get(hash) {
+            Some(entry) => {
+                self.blocks.get(entry.block.index as usize).map(|b| &b.hash) == Some(hash)
+            }
+            None => false,
+        }
+    }
+
+    pub fn find_block(&self, hash: &Bytes) -> Option<&Block> {
+        self.tree.get(hash).map(|e| &e.block)
+    }
 
-            if block.index == self.length
-                && block.timestamp >= self.blocks.last().unwrap().timestamp
-                && block.prev_hash == self.last_hash
-            {
+    /// Returns the hash of the block that has to be fetched before the given
+    /// orphan block can be connected
+    pub fn missing_ancestor(&self, hash: &Bytes) -> Option<Bytes> {
+        self.orphans.missing_ancestor(hash)
+    }
+
+    /// Adds a block to the block tree and switches the active chain to the
+    /// tip with the most accumulated work
+    ///
+    /// A block whose parent is unknown is kept in the orphan pool and
+    /// `UnknownParent` is returned, so that the caller can fetch the missing
+    /// ancestors. Orphans are connected once their parent is accepted.
+    pub fn accept_block(&mut self, block: Block) -> Result<ChainUpdate, BlockValidationError> {
+        let hash = block.hash.clone();
+
+        let mut update = match self.connect_to_tree(block.clone()) {
+            Err(BlockValidationError::UnknownParent) => {
                 info!(
-                    "[Blockchain] Added block {} to a new candidate in the memory pool",
+                    "[Blockchain] Storing orphan block {}",
                     hex::encode(&block.hash)
                 );
-                self.memory_pool.push(vec![block]);
-            } else {
-                warn!(
-                    "[Blockchain] Cannot add the block {} (index: {}, timestamp: {}) to blockchain with last hash {} and length {}",
-                    hex::encode(&block.hash),
-                    &block.index,
-                    &block.timestamp,
-                    hex::encode(&self.last_hash),
-                    &self.length
-                );
+                self.orphans.insert(block);
+                return Err(BlockValidationError::UnknownParent);
             }
-        } else {
-            // 1. Push the block to all inner Vectors with matching hash, index
-            //    and timestamp
-            // 2. Keep track of the length
-
-            for candidate in self.memory_pool.iter_mut() {
-                let last_block = candidate.last().unwrap();
-                if block.index == last_block.index + 1
-                    && block.timestamp >= last_block.timestamp
-                    && block.prev_hash == last_block.hash
-                {
-                    info!(
-                        "[Blockchain] Added block {} to an existing candidate in the memory pool",
-                        hex::encode(&block.hash)
-                    );
-                    candidate.push(block.clone());
+            result => result?,
+        };
+
+        let mut parents = vec![hash];
+
+        while let Some(parent) = parents.pop() {
+            for orphan in self.orphans.take_children(&parent) {
+                let orphan_hash = orphan.hash.clone();
+
+                match self.connect_to_tree(orphan) {
+                    Ok(orphan_update) => {
+                        update.merge(orphan_update);
+                        parents.push(orphan_hash);
+                    }
+                    Err(err) => warn!(
+                        "[Blockchain] Discarding orphan block {}: {}",
+                        hex::encode(&orphan_hash),
+                        err
+                    ),
                 }
             }
         }
 
-        self.clear_memory_pool();
+        Ok(update)
     }
 
-    pub fn clear_memory_pool(&mut self) {
-        let mut selected_candidate = Vec::<Block>::new();
-        let mut candidate_found: bool = false;
+    /// Inserts a block into the block tree and reorganizes if it makes a
+    /// heavier chain
+    ///
+    /// Blocks on side branches are only fully validated once their branch
+    /// becomes the heaviest one. If a block fails to connect during a
+    /// reorganization, it is marked invalid and the previous chain is
+    /// restored.
+    fn connect_to_tree(&mut self, block: Block) -> Result<ChainUpdate, BlockValidationError> {
+        if self.tree.contains(&block.hash) {
+            return Ok(ChainUpdate::default());
+        }
 
-        for candidate in self.memory_pool.iter_mut() {
-            if candidate.len() == BLOCK_MEMORY_POOL_SIZE {
-                selected_candidate = candidate.clone();
-                candidate_found = true;
-                info!("[Blockchain] Memory Pool Capacity Reached");
-                break;
-            }
+        BlockChain::check_block(&block)?;
+
+        if self.blocks.is_empty() {
+            self.validate_block(&block)?;
+            self.add_block(block.clone());
+
+            return Ok(ChainUpdate {
+                accepted: vec![block.clone()],
+                connected: vec![block],
+                disconnected: vec![],
+            });
+        }
+
+        if !self.tree.contains(&block.prev_hash) {
+            return Err(BlockValidationError::UnknownParent);
+        }
+
+        let header = block.header();
+        self.check_header_context(&header)?;
+
+        let hash = block.hash.clone();
+        self.headers.insert(header);
+        let total_work = self.tree.insert(block.clone());
+
+        let mut update = if total_work > self.total_work() {
+            self.reorganize(&hash)?
+        } else {
+            info!(
+                "[Blockchain] Stored block {} on a side branch",
+                hex::encode(&hash)
+            );
+            ChainUpdate::default()
+        };
+
+        update.accepted.push(block);
+        Ok(update)
+    }
+
+    /// Makes the block with the given hash the tip of the active chain
+    fn reorganize(&mut self, new_tip: &Bytes) -> Result<ChainUpdate, BlockValidationError> {
+        let mut branch = Vec::<Block>::new();
+        let mut cursor = new_tip.clone();
+
+        while !self.is_in_active_chain(&cursor) {
+            let entry = self.tree.get(&cursor).expect("Branch is not connected");
+            branch.push(entry.block.clone());
+            cursor = entry.block.prev_hash.clone();
+        }
+
+        branch.reverse();
+
+        let fork_point = self.tree.get(&cursor).unwrap().block.index;
+        let mut update = ChainUpdate::default();
+
+        while self.length - 1 > fork_point {
+            update.disconnected.push(self.disconnect_tip().unwrap());
+        }
+
+        if !update.disconnected.is_empty() {
+            info!(
+                "[Blockchain] Reorganizing: disconnected {} blocks after fork point {}",
+                update.disconnected.len(),
+                hex::encode(&cursor)
+            );
         }
 
-        for block in selected_candidate.iter() {
+        for block in branch.into_iter() {
+            if let Err(err) = self.validate_block(&block) {
+                warn!(
+                    "[Blockchain] Block {} failed to connect: {}",
+                    hex::encode(&block.hash),
+                    err
+                );
+                self.tree.mark_invalid(&block.hash);
+                self.headers.mark_invalid(&block.hash);
+
+                // Restore the previous chain
+                for _ in 0..update.connected.len() {
+                    self.disconnect_tip();
+                }
+                for block in update.disconnected.into_iter().rev() {
+                    self.add_block(block);
+                }
+
+                return Err(err);
+            }
+
             info!(
                 "[Blockchain] Adding block {} to blockchain",
                 hex::encode(&block.hash)
             );
             self.add_block(block.clone());
+            update.connected.push(block);
         }
 
-        if candidate_found {
-            self.memory_pool = vec![];
+        Ok(update)
+    }
+
+    /// Switches to a validated chain with more work than the active chain
+    ///
+    /// A chain that starts with the same genesis block is added block by
+    /// block, so the block tree, the headers and the orphans are kept and the
+    /// switch is a reorganization. A chain with another genesis block
+    /// replaces this one, which disconnects all of its blocks.
+    pub fn adopt(&mut self, chain: BlockChain) -> ChainUpdate {
+        let genesis = |chain: &BlockChain| chain.blocks.first().map(|b| b.hash.clone());
+        if !self.blocks.is_empty() && genesis(self) != genesis(&chain) {
+            let update = ChainUpdate {
+                accepted: chain.blocks.clone(),
+                connected: chain.blocks.clone(),
+                disconnected: self.blocks.iter().rev().cloned().collect(),
+            };
+            *self = chain;
+            return update;
+        }
+
+        let mut update = ChainUpdate::default();
+        for block in chain.blocks.into_iter() {
+            let hash = hex::encode(&block.hash);
+            match self.accept_block(block) {
+                Ok(block_update) => update.merge(block_update),
+                Err(err) => {
+                    warn!("[Blockchain] Couldn't adopt block {}: {}", hash, err);
+                    break;
+                }
+            }
         }
+
+        update
     }
 
-    pub fn find_unspent_transactions(&self, public_key_hash: &Bytes) -> Vec<&Transaction> {
-        let mut unspent_transactions: Vec<&Transaction> = Vec::new();
-        let mut spent_txos = HashMap::<String, Vec<i32>>::new();
+    /// Adds a header to the header tree, and returns whether it was new
+    ///
+    /// The header has to extend a known header, or be the header of the
+    /// genesis block of an empty chain, and carry valid proof of work, so a
+    /// peer can't make the node download a chain without the work behind it.
+    pub fn accept_header(&mut self, header: BlockHeader) -> Result<bool, BlockValidationError> {
+        if self.headers.contains(&header.hash) {
+            return Ok(false);
+        }
 
-        for block in self.blocks.iter().rev() {
-            for txn in &block.transactions {
-                let txn_id = hex::encode(&txn.id);
-
-                for (out_idx, out) in txn.outputs.iter().enumerate() {
-                    let mut found: bool = false;
-                    if spent_txos.contains_key(&txn_id) {
-                        for spent_output in &spent_txos[&txn_id] {
-                            if *spent_output == out_idx as i32 {
-                                found = true;
-                                break;
-                            }
-                        }
-                    }
+        BlockChain::check_header(&header)?;
 
-                    if found {
-                        continue;
-                    }
+        if self.headers.is_empty() {
+            if !header.prev_hash.is_empty() {
+                return Err(BlockValidationError::UnknownParent);
+            }
 
-                    if out.is_locked_with_key(&public_key_hash) {
-                        unspent_transactions.push(&txn);
-                    }
-                }
+            if header.index != 0 {
+                return Err(BlockValidationError::InvalidIndex {
+                    expected: 0,
+                    found: header.index,
+                });
+            }
 
-                if !txn.is_coinbase() {
-                    for input in &txn.inputs {
-                        if input.is_uses_key(&public_key_hash) {
-                            let txn_id = hex::encode(&input.id);
-                            spent_txos.entry(txn_id).or_insert(vec![]).push(input.out);
-                        }
-                    }
-                }
+            if header.difficulty() != self.params.initial_difficulty() {
+                return Err(BlockValidationError::InvalidDifficulty {
+                    expected: self.params.initial_difficulty(),
+                    found: header.difficulty(),
+                });
             }
+        } else {
+            self.check_header_context(&header)?;
         }
 
-        unspent_transactions
+        self.headers.insert(header);
+        Ok(true)
     }
 
-    #[allow(dead_code)]
-    pub fn find_unspent_txn_outputs(&self) -> HashMap<String, Vec<&TxnOutput>> {
-        let mut unspent_txn_outputs = HashMap::<String, Vec<&TxnOutput>>::new();
-        let mut spent_txn_outputs = HashMap::<String, Vec<i32>>::new();
+    pub fn has_header(&self, hash: &Bytes) -> bool {
+        self.headers.contains(hash)
+    }
 
-        for block in self.blocks.iter().rev() {
-            for txn in block.transactions.iter() {
-                let txn_id = hex::encode(&txn.id);
-                let is_txn_id_in_spent_txn_outputs = spent_txn_outputs.contains_key(&txn_id);
-                let mut found: bool = false;
-
-                for (output_idx, output) in txn.outputs.iter().enumerate() {
-                    if is_txn_id_in_spent_txn_outputs {
-                        for spent_output in spent_txn_outputs[&txn_id].iter() {
-                            if *spent_output == output_idx as i32 {
-                                found = true;
-                                break;
-                            }
-                        }
-
-                        if found {
-                            continue;
-                        }
-
-                        let mut outputs = unspent_txn_outputs[&txn_id].clone();
-                        outputs.push(output);
-                        unspent_txn_outputs
-                            .entry(txn_id.clone())
-                            .or_insert(vec![])
-                            .append(&mut outputs);
-                    }
+    /// Returns the valid header with the most work, which is the tip of the
+    /// active chain unless headers with more work are known
+    pub fn best_header(&self) -> Option<&BlockHeader> {
+        let best = self.headers.best()?;
 
-                    if !txn.is_coinbase() {
-                        for input in txn.inputs.iter() {
-                            let input_txn_id = hex::encode(&input.id);
-                            spent_txn_outputs
-                                .entry(input_txn_id)
-                                .or_insert(vec![])
-                                .push(input.out);
-                        }
-                    }
+        if best.total_work > self.total_work() {
+            Some(&best.header)
+        } else {
+            self.headers.get(&self.last_hash).map(|e| &e.header)
+        }
+    }
+
+    /// Returns the hashes of headers on the way from the best header back to
+    /// the genesis block, for a peer to find the last header both know
+    ///
+    /// The first ten headers are consecutive, after that the steps between
+    /// them double, so the locator stays short for long chains.
+    pub fn locator(&self) -> Vec<Bytes> {
+        let mut locator = Vec::new();
+        let mut entry = match self.best_header().and_then(|h| self.headers.get(&h.hash)) {
+            Some(entry) => entry,
+            None => return locator,
+        };
+        let mut step = 1;
+
+        loop {
+            locator.push(entry.header.hash.clone());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+
+            let mut next = entry;
+            for _ in 0..step {
+                match self.headers.get(&next.header.prev_hash) {
+                    Some(parent) => next = parent,
+                    None => break,
                 }
             }
+
+            if next.header.hash == entry.header.hash {
+                return locator;
+            }
+            entry = next;
         }
+    }
+
+    /// Returns up to `max` headers of the active chain that follow the first
+    /// hash of `locator` in the active chain, or from the genesis block if
+    /// there is none
+    pub fn headers_after(&self, locator: &[Bytes], max: usize) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find(|hash| self.is_in_active_chain(hash))
+            .and_then(|hash| self.tree.get(hash))
+            .map_or(0, |entry| entry.block.index as usize + 1);
+
+        self.blocks
+            .iter()
+            .skip(start)
+            .take(max)
+            .filter_map(|block| self.headers.get(&block.hash))
+            .map(|entry| entry.header.clone())
+            .collect()
+    }
+
+    /// Returns the hashes of up to `max` blocks on the way to the best header
+    /// that are neither in the block tree nor in the orphan pool, oldest first
+    pub fn missing_blocks(&self, max: usize) -> Vec<Bytes> {
+        let mut missing = Vec::new();
+        let mut cursor = match self.best_header() {
+            Some(header) => header.hash.clone(),
+            None => return missing,
+        };
+
+        while !self.tree.contains(&cursor) {
+            let entry = match self.headers.get(&cursor) {
+                Some(entry) => entry,
+                None => break,
+            };
+
+            if !self.orphans.contains(&cursor) {
+                missing.push(cursor.clone());
+            }
+            cursor = entry.header.prev_hash.clone();
+        }
+
+        missing.reverse();
+        missing.truncate(max);
+        missing
+    }
+
+    pub fn find_unspent_output(&self, outpoint: &OutPoint) -> Option<&Utxo> {
+        self.utxos.get(outpoint)
+    }
 
-        unspent_txn_outputs
+    /// Returns whether `txn` was mined and still has unspent outputs
+    ///
+    /// A mined transaction whose outputs were all spent isn't found, but it
+    /// can't be mined again either, as its inputs are spent.
+    pub fn has_unspent_outputs(&self, txn: &Transaction) -> bool {
+        (0..txn.outputs.len()).any(|out| {
+            self.utxos
+                .get(&OutPoint::new(&txn.id, out as i32))
+                .is_some()
+        })
     }
 
+    /// Returns the balance of a public key hash, split by whether it can be
+    /// spent in the next block
+    pub fn get_balance(&self, public_key_hash: &Bytes) -> Result<Balance, ValueOutOfRange> {
+        self.utxos
+            .balance(public_key_hash, self.length, &self.params)
+    }
+
+    /// Returns unspent outputs locked to a public key hash that add up to at
+    /// least `amount` if it has enough, and their total value
+    ///
+    /// Outputs in `spent`, such as those spent by pending transactions, are
+    /// skipped.
     pub fn find_spendable_outputs(
         &self,
         public_key_hash: &Bytes,
         amount: i32,
-    ) -> (i32, HashMap<String, Vec<i32>>) {
+        spent: &HashSet<OutPoint>,
+    ) -> Result<(i32, HashMap<String, Vec<i32>>), ValueOutOfRange> {
         let mut unspent_outputs = HashMap::<String, Vec<i32>>::new();
-        let unspent_txns = self.find_unspent_transactions(&public_key_hash);
         let mut accumulated: i32 = 0;
 
-        let mut txn_id;
-        for txn in unspent_txns {
-            txn_id = hex::encode(&txn.id).to_owned();
-
-            for (output_idx, output) in txn.outputs.iter().enumerate() {
-                if output.is_locked_with_key(&public_key_hash) && accumulated < amount {
-                    accumulated += output.value;
-                    unspent_outputs
-                        .entry(txn_id.clone())
-                        .or_insert(vec![])
-                        .push(output_idx as i32);
-                }
+        for (outpoint, utxo) in self.utxos.outputs_for(public_key_hash) {
+            if accumulated >= amount {
+                break;
+            }
 
-                if accumulated >= amount {
-                    return (accumulated, unspent_outputs);
-                }
+            // Coinbase outputs that are too recent can't be in the next block
+            if !utxo.is_spendable_at(self.length, &self.params) || spent.contains(outpoint) {
+                continue;
             }
+
+            accumulated = accumulated
+                .checked_add(utxo.output.value)
+                .ok_or(ValueOutOfRange)?;
+            unspent_outputs
+                .entry(hex::encode(&outpoint.txn_id))
+                .or_insert_with(Vec::new)
+                .push(outpoint.out);
         }
 
-        (accumulated, unspent_outputs)
+        Ok((accumulated, unspent_outputs))
     }
 
     pub fn find_transaction(&self, id: &Bytes) -> Result<&Transaction, &str> {
@@ -260,27 +633,61 @@ impl BlockChain {
         Err("Transaction not found")
     }
 
-    pub fn sign_transaction(&self, txn: &mut Transaction, private_key: secp256k1::SecretKey) {
-        let mut prev_txns = HashMap::<String, &Transaction>::new();
-
-        for txn_input in txn.inputs.iter() {
-            let prev_txn = self.find_transaction(&txn_input.id).unwrap();
-            prev_txns.insert(hex::encode(&prev_txn.id), prev_txn);
-        }
+    /// Collects the unspent outputs that a transaction's inputs spend
+    fn find_prev_outputs(&self, txn: &Transaction) -> HashMap<OutPoint, TxnOutput> {
+        txn.inputs
+            .iter()
+            .filter_map(|input| {
+                let outpoint = input.outpoint();
+                self.utxos
+                    .get(&outpoint)
+                    .map(|utxo| (outpoint, utxo.output.clone()))
+            })
+            .collect()
+    }
 
-        txn.sign(private_key, prev_txns).expect("");
+    /// Signs the inputs of `txn` that spend outputs of this chain locked to
+    /// the public key hash of `private_key`
+    pub fn sign_transaction(
+        &self,
+        txn: &mut Transaction,
+        private_key: secp256k1::SecretKey,
+        sighash_type: SigHashType,
+    ) -> Result<(), String> {
+        let prev_outputs = self.find_prev_outputs(txn);
+        txn.sign(private_key, &prev_outputs, sighash_type)
     }
 
-    #[allow(dead_code)]
-    pub fn verify_transaction(&self, txn: &mut Transaction) -> bool {
-        let mut prev_txns = HashMap::<String, &Transaction>::new();
+    /// Checks that every input of `txn` unlocks the output of this chain it
+    /// spends, see `Transaction::verify`
+    pub fn verify_transaction(&self, txn: &Transaction) -> Result<(), TransactionValidationError> {
+        let prev_outputs = self.find_prev_outputs(txn);
+        txn.verify(&prev_outputs)
+    }
 
-        for txn_input in txn.inputs.iter() {
-            let prev_txn = self.find_transaction(&txn_input.id).unwrap();
-            prev_txns.insert(hex::encode(&prev_txn.id), prev_txn);
-        }
+    /// Signs the inputs of `txn` that spend outputs of this chain locked to
+    /// `multisig` with one of its keys, see `Transaction::sign_partial`
+    pub fn sign_partial(
+        &self,
+        txn: &Transaction,
+        private_key: secp256k1::SecretKey,
+        multisig: &Multisig,
+        sighash_type: SigHashType,
+    ) -> Result<Vec<PartialSignature>, String> {
+        let prev_outputs = self.find_prev_outputs(txn);
+        txn.sign_partial(private_key, multisig, &prev_outputs, sighash_type)
+    }
 
-        txn.verify(prev_txns).unwrap()
+    /// Unlocks the inputs of `txn` that spend outputs of this chain locked to
+    /// `multisig`, see `Transaction::combine_signatures`
+    pub fn combine_signatures(
+        &self,
+        txn: &mut Transaction,
+        multisig: &Multisig,
+        signatures: &[PartialSignature],
+    ) -> Result<(), String> {
+        let prev_outputs = self.find_prev_outputs(txn);
+        txn.combine_signatures(multisig, &prev_outputs, signatures)
     }
 }
 
@@ -292,9 +699,11 @@ impl std::fmt::Display for BlockChain {
 Blockchain
 Last Hash: {}
 Length: {}
+Total Supply: {}
 Blocks: {}",
             hex::encode(&self.last_hash),
             self.length,
+            self.total_supply(self.length),
             self.blocks
                 .iter()
                 .map(|b| format!("{}", b))
@@ -302,3 +711,90 @@ Blocks: {}",
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::wallet::Wallet;
+    use crate::util::constants::VERSION;
+
+    /// Mines a block with only a coinbase on top of the active chain
+    fn mine(chain: &mut BlockChain, address: &Bytes) -> Block {
+        let height = chain.length;
+        let coinbase =
+            Transaction::create_coinbase_txn(address, chain.params.block_subsidy(height));
+        let block = Block::create(
+            vec![coinbase],
+            height,
+            chain.last_hash.clone(),
+            chain.next_difficulty(&chain.last_hash),
+        );
+        chain.accept_block(block.clone()).unwrap();
+        block
+    }
+
+    #[test]
+    fn total_supply_adds_up_the_subsidies_up_to_a_height() {
+        let address = Wallet::encode_address(VERSION, &vec![1; 20]);
+        let mut chain = BlockChain::new(&address);
+        mine(&mut chain, &address);
+
+        let coinbase_total: i64 = chain
+            .blocks
+            .iter()
+            .map(|block| block.transactions[0].outputs[0].value as i64)
+            .sum();
+        assert_eq!(chain.total_supply(chain.length), coinbase_total);
+        assert_eq!(chain.total_supply(0), 0);
+
+        for height in [1, 150, 1000].iter() {
+            let expected: i64 = (0..*height)
+                .map(|h| chain.params.block_subsidy(h) as i64)
+                .sum();
+            assert_eq!(chain.total_supply(*height), expected);
+        }
+    }
+
+    #[test]
+    fn adopting_a_chain_with_the_same_genesis_reorganizes() {
+        let address = Wallet::encode_address(VERSION, &vec![1; 20]);
+        let mut chain = BlockChain::new(&address);
+        let mut received = chain.clone();
+
+        let stale = mine(&mut chain, &address);
+        let branch = vec![mine(&mut received, &address), mine(&mut received, &address)];
+
+        let received = BlockChain::validate_chain(&received.blocks, chain.params.clone()).unwrap();
+        let update = chain.adopt(received);
+
+        let hashes = |blocks: &[Block]| blocks.iter().map(|b| b.hash.clone()).collect::<Vec<_>>();
+        assert_eq!(hashes(&update.disconnected), hashes(&[stale.clone()]));
+        assert_eq!(hashes(&update.connected), hashes(&branch));
+        assert_eq!(hashes(&update.accepted), hashes(&branch));
+        assert_eq!(chain.last_hash, branch[1].hash);
+
+        // The stale block stays in the block tree, on a side branch
+        assert!(chain.find_block(&stale.hash).is_some());
+        assert!(chain.has_header(&stale.hash));
+    }
+
+    #[test]
+    fn adopting_a_chain_with_another_genesis_disconnects_every_block() {
+        let address = Wallet::encode_address(VERSION, &vec![1; 20]);
+        let mut chain = BlockChain::new(&address);
+        mine(&mut chain, &address);
+        let old_blocks = chain.blocks.clone();
+
+        let mut received = BlockChain::new(&address);
+        mine(&mut received, &address);
+        mine(&mut received, &address);
+        let received = BlockChain::validate_chain(&received.blocks, chain.params.clone()).unwrap();
+        let new_blocks = received.blocks.clone();
+
+        let update = chain.adopt(received);
+        assert_eq!(update.disconnected.len(), old_blocks.len());
+        assert_eq!(update.disconnected[0].hash, old_blocks[1].hash);
+        assert_eq!(update.connected.len(), new_blocks.len());
+        assert_eq!(chain.last_hash, new_blocks[2].hash);
+    }
+}
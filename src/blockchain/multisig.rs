@@ -0,0 +1,87 @@
+use crate::blockchain::script::{Script, ScriptPubKey};
+use crate::blockchain::wallet::Wallet;
+use crate::util::constants::{MAX_MULTISIG_KEYS, SCRIPT_VERSION};
+use crate::util::hex_bytes;
+use crate::util::types::Bytes;
+
+use serde::{Deserialize, Serialize};
+
+/// A set of public keys of which `threshold` have to sign to spend an output
+///
+/// Outputs are locked to the hash of the multisig script (pay to script
+/// hash), so the address of a multisig is as short as a wallet address. The
+/// order of the keys is part of the script, so every key holder has to use the
+/// same order to arrive at the same address.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Multisig {
+    threshold: usize,
+    public_keys: Vec<Bytes>,
+}
+
+impl Multisig {
+    pub fn new(threshold: usize, public_keys: Vec<Bytes>) -> Result<Self, String> {
+        if public_keys.is_empty() || public_keys.len() > MAX_MULTISIG_KEYS {
+            return Err(format!(
+                "A multisig needs between 1 and {} public keys, not {}",
+                MAX_MULTISIG_KEYS,
+                public_keys.len()
+            ));
+        }
+
+        if threshold == 0 || threshold > public_keys.len() {
+            return Err(format!(
+                "Invalid threshold {} for {} public keys",
+                threshold,
+                public_keys.len()
+            ));
+        }
+
+        for public_key in public_keys.iter() {
+            if secp256k1::PublicKey::from_slice(public_key).is_err() {
+                return Err(format!("Invalid public key {}", hex::encode(public_key)));
+            }
+        }
+
+        Ok(Multisig {
+            threshold,
+            public_keys,
+        })
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn public_keys(&self) -> &[Bytes] {
+        &self.public_keys
+    }
+
+    /// Returns the script that the unlocking scripts of the outputs locked to
+    /// the multisig have to reveal
+    pub fn redeem_script(&self) -> Script {
+        Script::multisig(self.threshold, &self.public_keys)
+    }
+
+    pub fn script_pubkey(&self) -> ScriptPubKey {
+        Script::p2sh(&self.redeem_script().hash160())
+    }
+
+    pub fn address(&self) -> Bytes {
+        Wallet::encode_address(SCRIPT_VERSION, &self.redeem_script().hash160())
+    }
+}
+
+/// The signature of one input of a transaction by one of the keys of a
+/// multisig
+///
+/// Each key holder creates their signatures with `Transaction::sign_partial`,
+/// and once `threshold` key holders have signed, anyone can combine the
+/// signatures with `Transaction::combine_signatures`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PartialSignature {
+    pub input: usize,
+    #[serde(with = "hex_bytes")]
+    pub public_key: Bytes,
+    #[serde(with = "hex_bytes")]
+    pub signature: Bytes,
+}
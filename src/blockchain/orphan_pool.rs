@@ -0,0 +1,84 @@
+use crate::blockchain::block::Block;
+use crate::util::constants::{MAX_ORPHAN_BLOCKS, ORPHAN_BLOCK_EXPIRY};
+use crate::util::types::Bytes;
+
+use chrono::Utc;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+struct Orphan {
+    block: Block,
+    received_at: i64,
+}
+
+/// Blocks whose parent is not known yet, keyed by the hex encoded block hash
+///
+/// The pool holds at most `MAX_ORPHAN_BLOCKS` blocks, and blocks older than
+/// `ORPHAN_BLOCK_EXPIRY` seconds are dropped.
+#[derive(Clone, Debug, Default)]
+pub struct OrphanPool {
+    orphans: HashMap<String, Orphan>,
+}
+
+impl OrphanPool {
+    pub fn contains(&self, hash: &Bytes) -> bool {
+        self.orphans.contains_key(&hex::encode(hash))
+    }
+
+    pub fn insert(&mut self, block: Block) {
+        let now = Utc::now().timestamp();
+        self.orphans
+            .retain(|_, orphan| now - orphan.received_at <= ORPHAN_BLOCK_EXPIRY);
+
+        if self.contains(&block.hash) {
+            return;
+        }
+
+        if self.orphans.len() >= MAX_ORPHAN_BLOCKS {
+            let oldest = self
+                .orphans
+                .iter()
+                .min_by_key(|(_, orphan)| orphan.received_at)
+                .map(|(key, _)| key.clone());
+
+            if let Some(key) = oldest {
+                self.orphans.remove(&key);
+            }
+        }
+
+        self.orphans.insert(
+            hex::encode(&block.hash),
+            Orphan {
+                block,
+                received_at: now,
+            },
+        );
+    }
+
+    /// Removes and returns the orphans whose parent is the given block
+    pub fn take_children(&mut self, parent_hash: &Bytes) -> Vec<Block> {
+        let keys = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| orphan.block.prev_hash == *parent_hash)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<String>>();
+
+        keys.iter()
+            .filter_map(|key| self.orphans.remove(key))
+            .map(|orphan| orphan.block)
+            .collect()
+    }
+
+    /// Returns the hash of the first missing ancestor of an orphan, i.e. the
+    /// parent of the oldest orphan in its branch
+    pub fn missing_ancestor(&self, hash: &Bytes) -> Option<Bytes> {
+        let mut orphan = self.orphans.get(&hex::encode(hash))?;
+
+        while let Some(parent) = self.orphans.get(&hex::encode(&orphan.block.prev_hash)) {
+            orphan = parent;
+        }
+
+        Some(orphan.block.prev_hash.clone())
+    }
+}
@@ -0,0 +1,271 @@
+use crate::util::constants::{
+    COINBASE_MATURITY, DIFFICULTY, HALVING_INTERVAL, INITIAL_SUBSIDY, MAX_RETARGET_FACTOR,
+    RETARGET_INTERVAL, TARGET_BLOCK_TIME, TERMINAL_SUBSIDY,
+};
+
+/// The reason a set of chain parameters was rejected
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChainParamsError {
+    ZeroDifficulty,
+    NonPositiveTargetBlockTime { time: i64 },
+    RetargetIntervalTooShort { interval: i32 },
+    ZeroRetargetFactor,
+    NegativeSubsidy { subsidy: i32 },
+    HalvingIntervalTooShort { interval: i32 },
+    NegativeCoinbaseMaturity { maturity: i32 },
+}
+
+impl std::fmt::Display for ChainParamsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::ZeroDifficulty => write!(f, "initial difficulty must not be 0"),
+            Self::NonPositiveTargetBlockTime { time } => {
+                write!(f, "target block time {} is not positive", time)
+            }
+            Self::RetargetIntervalTooShort { interval } => {
+                write!(f, "retarget interval {} is less than 2 blocks", interval)
+            }
+            Self::ZeroRetargetFactor => write!(f, "max retarget factor must not be 0"),
+            Self::NegativeSubsidy { subsidy } => write!(f, "subsidy {} is negative", subsidy),
+            Self::HalvingIntervalTooShort { interval } => {
+                write!(f, "halving interval {} is less than 1 block", interval)
+            }
+            Self::NegativeCoinbaseMaturity { maturity } => {
+                write!(f, "coinbase maturity {} is negative", maturity)
+            }
+        }
+    }
+}
+
+/// The consensus rules of a chain that can be tuned, e.g. to get faster
+/// blocks in a simulation
+///
+/// Start from the defaults and change them with the `with_` methods, which
+/// reject values the rules can't work with, such as intervals of 0 blocks.
+#[derive(Clone, Debug)]
+pub struct ChainParams {
+    initial_difficulty: u128, // also the easiest difficulty a retarget can reach
+    target_block_time: i64,   // seconds
+    retarget_interval: i32,   // blocks between retargets, at least 2
+    max_retarget_factor: u128,
+    initial_subsidy: i32,  // the newly created coins of the genesis block
+    halving_interval: i32, // blocks between halvings of the subsidy
+    terminal_subsidy: Option<i32>, // the subsidy never falls below this
+    coinbase_maturity: i32, // blocks before a coinbase output can be spent
+}
+
+impl Default for ChainParams {
+    /// Returns the parameters in `util::constants`
+    ///
+    /// Panics if the constants were tuned to values the rules can't work with.
+    fn default() -> Self {
+        let params = ChainParams {
+            initial_difficulty: DIFFICULTY,
+            target_block_time: TARGET_BLOCK_TIME,
+            retarget_interval: RETARGET_INTERVAL,
+            max_retarget_factor: MAX_RETARGET_FACTOR,
+            initial_subsidy: INITIAL_SUBSIDY,
+            halving_interval: HALVING_INTERVAL,
+            terminal_subsidy: TERMINAL_SUBSIDY,
+            coinbase_maturity: COINBASE_MATURITY,
+        };
+
+        params
+            .with_difficulty(
+                DIFFICULTY,
+                TARGET_BLOCK_TIME,
+                RETARGET_INTERVAL,
+                MAX_RETARGET_FACTOR,
+            )
+            .and_then(|params| {
+                params.with_subsidy(INITIAL_SUBSIDY, HALVING_INTERVAL, TERMINAL_SUBSIDY)
+            })
+            .and_then(|params| params.with_coinbase_maturity(COINBASE_MATURITY))
+            .unwrap_or_else(|err| panic!("Invalid chain params in util::constants: {}", err))
+    }
+}
+
+impl ChainParams {
+    /// Sets the difficulty of the genesis block, and how the difficulty is
+    /// retargeted every `retarget_interval` blocks to reach one block every
+    /// `target_block_time` seconds
+    pub fn with_difficulty(
+        mut self,
+        initial_difficulty: u128,
+        target_block_time: i64,
+        retarget_interval: i32,
+        max_retarget_factor: u128,
+    ) -> Result<Self, ChainParamsError> {
+        if initial_difficulty == 0 {
+            return Err(ChainParamsError::ZeroDifficulty);
+        }
+
+        if target_block_time <= 0 {
+            return Err(ChainParamsError::NonPositiveTargetBlockTime {
+                time: target_block_time,
+            });
+        }
+
+        if retarget_interval < 2 {
+            return Err(ChainParamsError::RetargetIntervalTooShort {
+                interval: retarget_interval,
+            });
+        }
+
+        if max_retarget_factor == 0 {
+            return Err(ChainParamsError::ZeroRetargetFactor);
+        }
+
+        self.initial_difficulty = initial_difficulty;
+        self.target_block_time = target_block_time;
+        self.retarget_interval = retarget_interval;
+        self.max_retarget_factor = max_retarget_factor;
+        Ok(self)
+    }
+
+    /// Sets the subsidy of the genesis block, which halves every
+    /// `halving_interval` blocks until it reaches `terminal_subsidy`
+    pub fn with_subsidy(
+        mut self,
+        initial_subsidy: i32,
+        halving_interval: i32,
+        terminal_subsidy: Option<i32>,
+    ) -> Result<Self, ChainParamsError> {
+        for subsidy in Some(initial_subsidy).iter().chain(terminal_subsidy.iter()) {
+            if *subsidy < 0 {
+                return Err(ChainParamsError::NegativeSubsidy { subsidy: *subsidy });
+            }
+        }
+
+        if halving_interval < 1 {
+            return Err(ChainParamsError::HalvingIntervalTooShort {
+                interval: halving_interval,
+            });
+        }
+
+        self.initial_subsidy = initial_subsidy;
+        self.halving_interval = halving_interval;
+        self.terminal_subsidy = terminal_subsidy;
+        Ok(self)
+    }
+
+    /// Sets the number of blocks before a coinbase output can be spent
+    pub fn with_coinbase_maturity(
+        mut self,
+        coinbase_maturity: i32,
+    ) -> Result<Self, ChainParamsError> {
+        if coinbase_maturity < 0 {
+            return Err(ChainParamsError::NegativeCoinbaseMaturity {
+                maturity: coinbase_maturity,
+            });
+        }
+
+        self.coinbase_maturity = coinbase_maturity;
+        Ok(self)
+    }
+
+    pub fn initial_difficulty(&self) -> u128 {
+        self.initial_difficulty
+    }
+
+    pub fn retarget_interval(&self) -> i32 {
+        self.retarget_interval
+    }
+
+    /// Scales the difficulty of the last retarget window by the time it took
+    /// to mine, compared to the target block time
+    ///
+    /// `elapsed` is the time between the first and the last block of the
+    /// window. A higher difficulty is easier to meet, so a slow window raises
+    /// it. The adjustment is clamped to `max_retarget_factor` either way.
+    pub fn retarget(&self, difficulty: u128, elapsed: i64) -> u128 {
+        let expected =
+            (self.target_block_time * (self.retarget_interval as i64 - 1)).max(1) as u128;
+        let elapsed = elapsed.max(0) as u128;
+        let factor = self.max_retarget_factor;
+
+        let adjusted = if elapsed.saturating_mul(factor) <= expected {
+            difficulty / factor
+        } else if elapsed >= expected.saturating_mul(factor) {
+            difficulty.saturating_mul(factor)
+        } else if difficulty > u128::MAX / elapsed {
+            difficulty / expected * elapsed
+        } else {
+            difficulty * elapsed / expected
+        };
+
+        adjusted.max(1).min(self.initial_difficulty)
+    }
+
+    /// Returns the newly created coins the coinbase of the block at `height`
+    /// may claim, on top of the fees
+    pub fn block_subsidy(&self, height: i32) -> i32 {
+        let halvings = height / self.halving_interval;
+        let subsidy = if halvings < 31 {
+            self.initial_subsidy >> halvings
+        } else {
+            0
+        };
+
+        match self.terminal_subsidy {
+            Some(terminal_subsidy) => subsidy.max(terminal_subsidy),
+            None => subsidy,
+        }
+    }
+
+    /// Returns the coins created by the first `height` blocks
+    pub fn supply_at(&self, height: i32) -> i64 {
+        let mut supply: i64 = 0;
+        let mut start = 0;
+
+        // The subsidy is the same for all blocks between two halvings
+        while start < height {
+            let end = height.min(start.saturating_add(self.halving_interval));
+            supply += (end - start) as i64 * self.block_subsidy(start) as i64;
+            start = end;
+        }
+
+        supply
+    }
+
+    /// Returns whether an output created at `created` by a coinbase
+    /// transaction can be spent by a transaction in the block at `height`
+    pub fn is_coinbase_mature(&self, created: i32, height: i32) -> bool {
+        height - created >= self.coinbase_maturity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intervals_the_rules_divide_by_are_rejected() {
+        let params = ChainParams::default();
+
+        assert_eq!(
+            params
+                .clone()
+                .with_subsidy(INITIAL_SUBSIDY, 0, TERMINAL_SUBSIDY)
+                .err(),
+            Some(ChainParamsError::HalvingIntervalTooShort { interval: 0 })
+        );
+        assert_eq!(
+            params
+                .clone()
+                .with_difficulty(DIFFICULTY, TARGET_BLOCK_TIME, 0, MAX_RETARGET_FACTOR)
+                .err(),
+            Some(ChainParamsError::RetargetIntervalTooShort { interval: 0 })
+        );
+        assert_eq!(
+            params
+                .clone()
+                .with_difficulty(DIFFICULTY, TARGET_BLOCK_TIME, RETARGET_INTERVAL, 0)
+                .err(),
+            Some(ChainParamsError::ZeroRetargetFactor)
+        );
+
+        let params = params.with_subsidy(INITIAL_SUBSIDY, 1, None).unwrap();
+        assert_eq!(params.block_subsidy(1), INITIAL_SUBSIDY / 2);
+    }
+}
@@ -0,0 +1,636 @@
+use crate::blockchain::txn::RelativeLock;
+use crate::blockchain::wallet::Wallet;
+use crate::util::constants::MAX_MULTISIG_KEYS;
+use crate::util::encoding::{DecodeError, Decoder, Encoder};
+use crate::util::traits::{Decodable, Hashable};
+use crate::util::types::Bytes;
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use std::str::FromStr;
+
+/// An instruction of a script
+///
+/// `Push` and `Number` push a value onto the stack, the other operations work
+/// on the values at the top of the stack. Numbers are kept on the stack as 8
+/// byte little endian integers, and a value is true unless it is empty or all
+/// of its bytes are zero.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Push(Bytes),
+    Number(i64),
+    Dup,
+    Drop,
+    Swap,
+    If,
+    NotIf,
+    Else,
+    EndIf,
+    Verify,
+    Equal,
+    EqualVerify,
+    Sha256,
+    Hash160,
+    CheckSig,
+    CheckSigVerify,
+    CheckMultiSig,
+    CheckMultiSigVerify,
+    CheckLockTimeVerify,
+    CheckSequenceVerify,
+}
+
+// The names of the operations that take no argument, and their opcodes in the
+// binary encoding
+const OPS: [(Op, &str, u8); 18] = [
+    (Op::Dup, "OP_DUP", 0x10),
+    (Op::Drop, "OP_DROP", 0x11),
+    (Op::Swap, "OP_SWAP", 0x12),
+    (Op::If, "OP_IF", 0x20),
+    (Op::NotIf, "OP_NOTIF", 0x21),
+    (Op::Else, "OP_ELSE", 0x22),
+    (Op::EndIf, "OP_ENDIF", 0x23),
+    (Op::Verify, "OP_VERIFY", 0x24),
+    (Op::Equal, "OP_EQUAL", 0x30),
+    (Op::EqualVerify, "OP_EQUALVERIFY", 0x31),
+    (Op::Sha256, "OP_SHA256", 0x40),
+    (Op::Hash160, "OP_HASH160", 0x41),
+    (Op::CheckSig, "OP_CHECKSIG", 0x50),
+    (Op::CheckSigVerify, "OP_CHECKSIGVERIFY", 0x51),
+    (Op::CheckMultiSig, "OP_CHECKMULTISIG", 0x52),
+    (Op::CheckMultiSigVerify, "OP_CHECKMULTISIGVERIFY", 0x53),
+    (Op::CheckLockTimeVerify, "OP_CHECKLOCKTIMEVERIFY", 0x60),
+    (Op::CheckSequenceVerify, "OP_CHECKSEQUENCEVERIFY", 0x61),
+];
+
+const OP_PUSH: u8 = 0x01;
+const OP_NUMBER: u8 = 0x02;
+
+impl Op {
+    fn is_push(&self) -> bool {
+        matches!(self, Op::Push(_) | Op::Number(_))
+    }
+
+    fn name(&self) -> &'static str {
+        OPS.iter()
+            .find(|(op, _, _)| op == self)
+            .map(|(_, name, _)| *name)
+            .unwrap_or("")
+    }
+}
+
+impl std::fmt::Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Op::Push(bytes) => write!(f, "0x{}", hex::encode(bytes)),
+            Op::Number(n) => write!(f, "{}", n),
+            op => write!(f, "{}", op.name()),
+        }
+    }
+}
+
+impl FromStr for Op {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(digits) = s.strip_prefix("0x") {
+            return hex::decode(digits)
+                .map(Op::Push)
+                .map_err(|err| format!("invalid push {:?}: {}", s, err));
+        }
+
+        if let Ok(n) = s.parse::<i64>() {
+            return Ok(Op::Number(n));
+        }
+
+        OPS.iter()
+            .find(|(_, name, _)| *name == s)
+            .map(|(op, _, _)| op.clone())
+            .ok_or_else(|| format!("unknown script operation {:?}", s))
+    }
+}
+
+/// A program that locks a transaction output (a `ScriptPubKey`), or unlocks
+/// one (a `ScriptSig`)
+///
+/// To spend an output, the `ScriptSig` of the input runs first and leaves its
+/// values on the stack, then the `ScriptPubKey` of the output runs on the same
+/// stack. The output is unlocked if no operation failed and the value left at
+/// the top of the stack is true.
+///
+/// In JSON, scripts are written as their operations separated by spaces, e.g.
+/// `OP_DUP OP_HASH160 0x<public key hash> OP_EQUALVERIFY OP_CHECKSIG`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Script {
+    pub ops: Vec<Op>,
+}
+
+pub type ScriptPubKey = Script;
+pub type ScriptSig = Script;
+
+impl Script {
+    pub fn new(ops: Vec<Op>) -> Self {
+        Script { ops }
+    }
+
+    /// Locks an output to the owner of a public key hash (pay to public key
+    /// hash)
+    pub fn p2pkh(public_key_hash: &[u8]) -> ScriptPubKey {
+        Script::new(vec![
+            Op::Dup,
+            Op::Hash160,
+            Op::Push(public_key_hash.to_vec()),
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    /// Unlocks a `p2pkh` output
+    pub fn p2pkh_sig(signature: &[u8], public_key: &[u8]) -> ScriptSig {
+        Script::new(vec![
+            Op::Push(signature.to_vec()),
+            Op::Push(public_key.to_vec()),
+        ])
+    }
+
+    /// Locks an output to whoever knows a value whose SHA256 hash is `hash`
+    #[allow(dead_code)]
+    pub fn hash_lock(hash: &[u8]) -> ScriptPubKey {
+        Script::new(vec![Op::Sha256, Op::Push(hash.to_vec()), Op::Equal])
+    }
+
+    /// Unlocks a `hash_lock` output
+    #[allow(dead_code)]
+    pub fn hash_lock_sig(preimage: &[u8]) -> ScriptSig {
+        Script::new(vec![Op::Push(preimage.to_vec())])
+    }
+
+    /// Prevents `script` from being unlocked before `lock_time`, a block
+    /// height or a timestamp like `Transaction::lock_time`
+    #[allow(dead_code)]
+    pub fn time_lock(lock_time: i64, script: ScriptPubKey) -> ScriptPubKey {
+        let mut ops = vec![Op::Number(lock_time), Op::CheckLockTimeVerify, Op::Drop];
+        ops.extend(script.ops);
+        Script::new(ops)
+    }
+
+    /// Prevents `script` from being unlocked until `lock` has passed since
+    /// the output was mined
+    #[allow(dead_code)]
+    pub fn relative_time_lock(lock: RelativeLock, script: ScriptPubKey) -> ScriptPubKey {
+        let mut ops = vec![
+            Op::Number(lock.to_sequence() as i64),
+            Op::CheckSequenceVerify,
+            Op::Drop,
+        ];
+        ops.extend(script.ops);
+        Script::new(ops)
+    }
+
+    /// Locks an output to `threshold` signatures of the given public keys
+    ///
+    /// The signatures of the unlocking script have to be in the same order as
+    /// the keys.
+    pub fn multisig(threshold: usize, public_keys: &[Bytes]) -> ScriptPubKey {
+        let mut ops = vec![Op::Number(threshold as i64)];
+        ops.extend(public_keys.iter().map(|key| Op::Push(key.clone())));
+        ops.push(Op::Number(public_keys.len() as i64));
+        ops.push(Op::CheckMultiSig);
+        Script::new(ops)
+    }
+
+    /// Locks an output to a script with the given hash (pay to script hash)
+    ///
+    /// The unlocking script pushes the encoding of that script, the redeem
+    /// script, last. The output is unlocked if the redeem script hashes to
+    /// `script_hash` and the redeem script is then unlocked by the values
+    /// pushed before it.
+    pub fn p2sh(script_hash: &[u8]) -> ScriptPubKey {
+        Script::new(vec![Op::Hash160, Op::Push(script_hash.to_vec()), Op::Equal])
+    }
+
+    /// Returns the hash of the encoding of the script, which is what a `p2sh`
+    /// script locks to
+    pub fn hash160(&self) -> Bytes {
+        Wallet::generate_sha256_ripemd160_hash(&self.encode())
+    }
+
+    /// Returns the script hash of a `p2sh` script
+    pub fn script_hash(&self) -> Option<&Bytes> {
+        match self.ops.as_slice() {
+            [Op::Hash160, Op::Push(script_hash), Op::Equal] => Some(script_hash),
+            _ => None,
+        }
+    }
+
+    /// Returns the public key hash of a `p2pkh` script
+    pub fn public_key_hash(&self) -> Option<&Bytes> {
+        match self.ops.as_slice() {
+            [Op::Dup, Op::Hash160, Op::Push(public_key_hash), Op::EqualVerify, Op::CheckSig] => {
+                Some(public_key_hash)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns whether the script only pushes values, as unlocking scripts
+    /// have to
+    pub fn is_push_only(&self) -> bool {
+        self.ops.iter().all(Op::is_push)
+    }
+}
+
+impl Hashable for Script {
+    fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+
+        encoder.put_len(self.ops.len());
+        for op in self.ops.iter() {
+            match op {
+                Op::Push(bytes) => {
+                    encoder.put_u8(OP_PUSH);
+                    encoder.put_bytes(bytes);
+                }
+                Op::Number(n) => {
+                    encoder.put_u8(OP_NUMBER);
+                    encoder.put_i64(*n);
+                }
+                op => {
+                    let (_, _, opcode) = OPS.iter().find(|(known, _, _)| known == op).unwrap();
+                    encoder.put_u8(*opcode);
+                }
+            }
+        }
+
+        encoder.finish()
+    }
+}
+
+impl Decodable for Script {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let mut ops = Vec::new();
+
+        for _ in 0..decoder.get_len()? {
+            let op = match decoder.get_u8()? {
+                OP_PUSH => Op::Push(decoder.get_bytes()?),
+                OP_NUMBER => Op::Number(decoder.get_i64()?),
+                opcode => OPS
+                    .iter()
+                    .find(|(_, _, known)| *known == opcode)
+                    .map(|(op, _, _)| op.clone())
+                    .ok_or(DecodeError::UnknownOpcode { opcode })?,
+            };
+            ops.push(op);
+        }
+
+        Ok(Script { ops })
+    }
+}
+
+impl std::fmt::Display for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let ops = self
+            .ops
+            .iter()
+            .map(|op| op.to_string())
+            .collect::<Vec<String>>();
+        write!(f, "{}", ops.join(" "))
+    }
+}
+
+impl FromStr for Script {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ops = s
+            .split_whitespace()
+            .map(Op::from_str)
+            .collect::<Result<Vec<Op>, String>>()?;
+        Ok(Script { ops })
+    }
+}
+
+impl Serialize for Script {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Script {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(D::Error::custom)
+    }
+}
+
+/// The reason a script failed
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScriptError {
+    NotPushOnly,
+    StackUnderflow,
+    InvalidNumber,
+    UnbalancedConditional,
+    VerifyFailed,
+    InvalidKeyCount { count: i64 },
+    InvalidSignatureCount { count: i64 },
+    NegativeLockTime,
+    LockTimeNotReached { lock_time: i64 },
+    InvalidSequence { sequence: i64 },
+    SequenceNotReached { sequence: i64 },
+    InvalidRedeemScript,
+    EvalFalse,
+    MissingInput { input: usize, inputs: usize },
+}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::NotPushOnly => write!(f, "unlocking script does not only push values"),
+            Self::StackUnderflow => write!(f, "operation needs more values than on the stack"),
+            Self::InvalidNumber => write!(f, "value is not a number"),
+            Self::UnbalancedConditional => write!(f, "unbalanced conditional"),
+            Self::VerifyFailed => write!(f, "verify operation failed"),
+            Self::InvalidKeyCount { count } => write!(f, "invalid public key count {}", count),
+            Self::InvalidSignatureCount { count } => {
+                write!(f, "invalid signature count {}", count)
+            }
+            Self::NegativeLockTime => write!(f, "lock time is negative"),
+            Self::LockTimeNotReached { lock_time } => {
+                write!(f, "lock time {} is not reached yet", lock_time)
+            }
+            Self::InvalidSequence { sequence } => write!(f, "invalid sequence {}", sequence),
+            Self::SequenceNotReached { sequence } => {
+                write!(
+                    f,
+                    "relative lock of sequence {:#x} is not reached yet",
+                    sequence
+                )
+            }
+            Self::InvalidRedeemScript => write!(f, "redeem script can't be decoded"),
+            Self::EvalFalse => write!(f, "script evaluated to false"),
+            Self::MissingInput { input, inputs } => write!(
+                f,
+                "there is no input {}, the transaction has {} inputs",
+                input, inputs
+            ),
+        }
+    }
+}
+
+/// Checks the parts of a script that depend on the spending transaction
+pub trait SignatureChecker {
+    /// Returns whether `signature` is a valid signature of the spending input
+    /// by `public_key`. Malformed signatures and keys are not valid.
+    fn check_signature(&self, signature: &[u8], public_key: &[u8]) -> bool;
+
+    /// Returns whether the spending transaction can't be in a block before
+    /// `lock_time`
+    fn check_lock_time(&self, lock_time: i64) -> bool;
+
+    /// Returns whether the spending input can't be in a block before the
+    /// relative lock of the sequence `sequence` has passed
+    fn check_sequence(&self, sequence: i64) -> bool;
+}
+
+fn encode_number(n: i64) -> Bytes {
+    n.to_le_bytes().to_vec()
+}
+
+fn decode_number(value: &[u8]) -> Result<i64, ScriptError> {
+    let mut bytes = [0; 8];
+    if value.len() != bytes.len() {
+        return Err(ScriptError::InvalidNumber);
+    }
+
+    bytes.copy_from_slice(value);
+    Ok(i64::from_le_bytes(bytes))
+}
+
+fn is_true(value: &[u8]) -> bool {
+    value.iter().any(|&byte| byte != 0)
+}
+
+fn encode_bool(value: bool) -> Bytes {
+    if value {
+        vec![1]
+    } else {
+        vec![]
+    }
+}
+
+struct Interpreter<'a> {
+    stack: Vec<Bytes>,
+    checker: &'a dyn SignatureChecker,
+}
+
+impl<'a> Interpreter<'a> {
+    fn pop(&mut self) -> Result<Bytes, ScriptError> {
+        self.stack.pop().ok_or(ScriptError::StackUnderflow)
+    }
+
+    fn pop_number(&mut self) -> Result<i64, ScriptError> {
+        decode_number(&self.pop()?)
+    }
+
+    fn top(&self) -> Result<&Bytes, ScriptError> {
+        self.stack.last().ok_or(ScriptError::StackUnderflow)
+    }
+
+    fn run(&mut self, script: &Script) -> Result<(), ScriptError> {
+        // Whether each enclosing `OP_IF` branch is taken
+        let mut branches = Vec::<bool>::new();
+
+        for op in script.ops.iter() {
+            let executing = branches.iter().all(|&taken| taken);
+
+            match op {
+                Op::If | Op::NotIf => {
+                    let taken = if executing {
+                        is_true(&self.pop()?) == (*op == Op::If)
+                    } else {
+                        false
+                    };
+                    branches.push(taken);
+                }
+                Op::Else => match branches.last_mut() {
+                    Some(taken) => *taken = !*taken,
+                    None => return Err(ScriptError::UnbalancedConditional),
+                },
+                Op::EndIf => {
+                    if branches.pop().is_none() {
+                        return Err(ScriptError::UnbalancedConditional);
+                    }
+                }
+                _ if !executing => (),
+                op => self.step(op)?,
+            }
+        }
+
+        if !branches.is_empty() {
+            return Err(ScriptError::UnbalancedConditional);
+        }
+
+        Ok(())
+    }
+
+    fn step(&mut self, op: &Op) -> Result<(), ScriptError> {
+        match op {
+            Op::Push(bytes) => self.stack.push(bytes.clone()),
+            Op::Number(n) => self.stack.push(encode_number(*n)),
+            Op::Dup => {
+                let top = self.top()?.clone();
+                self.stack.push(top);
+            }
+            Op::Drop => {
+                self.pop()?;
+            }
+            Op::Swap => {
+                let first = self.pop()?;
+                let second = self.pop()?;
+                self.stack.push(first);
+                self.stack.push(second);
+            }
+            Op::Verify => {
+                if !is_true(&self.pop()?) {
+                    return Err(ScriptError::VerifyFailed);
+                }
+            }
+            Op::Equal | Op::EqualVerify => {
+                let equal = self.pop()? == self.pop()?;
+                self.push_result(equal, *op == Op::EqualVerify)?;
+            }
+            Op::Sha256 => {
+                let value = self.pop()?;
+                let hash = crypto_hash::digest(crypto_hash::Algorithm::SHA256, &value);
+                self.stack.push(hash);
+            }
+            Op::Hash160 => {
+                let value = self.pop()?;
+                self.stack
+                    .push(Wallet::generate_sha256_ripemd160_hash(&value));
+            }
+            Op::CheckSig | Op::CheckSigVerify => {
+                let public_key = self.pop()?;
+                let signature = self.pop()?;
+                let valid = self.checker.check_signature(&signature, &public_key);
+                self.push_result(valid, *op == Op::CheckSigVerify)?;
+            }
+            Op::CheckMultiSig | Op::CheckMultiSigVerify => {
+                let valid = self.check_multisig()?;
+                self.push_result(valid, *op == Op::CheckMultiSigVerify)?;
+            }
+            Op::CheckLockTimeVerify => {
+                let lock_time = decode_number(self.top()?)?;
+                if lock_time < 0 {
+                    return Err(ScriptError::NegativeLockTime);
+                }
+
+                if !self.checker.check_lock_time(lock_time) {
+                    return Err(ScriptError::LockTimeNotReached { lock_time });
+                }
+            }
+            Op::CheckSequenceVerify => {
+                let sequence = decode_number(self.top()?)?;
+                if sequence < 0 || sequence > u32::MAX as i64 {
+                    return Err(ScriptError::InvalidSequence { sequence });
+                }
+
+                if !self.checker.check_sequence(sequence) {
+                    return Err(ScriptError::SequenceNotReached { sequence });
+                }
+            }
+            Op::If | Op::NotIf | Op::Else | Op::EndIf => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    fn check_result(&self) -> Result<(), ScriptError> {
+        match self.stack.last() {
+            Some(top) if is_true(top) => Ok(()),
+            _ => Err(ScriptError::EvalFalse),
+        }
+    }
+
+    fn push_result(&mut self, result: bool, verify: bool) -> Result<(), ScriptError> {
+        if verify {
+            if !result {
+                return Err(ScriptError::VerifyFailed);
+            }
+        } else {
+            self.stack.push(encode_bool(result));
+        }
+
+        Ok(())
+    }
+
+    /// Pops `<signatures...> <threshold> <public keys...> <key count>` and
+    /// checks that the signatures match the keys in order
+    fn check_multisig(&mut self) -> Result<bool, ScriptError> {
+        let key_count = self.pop_number()?;
+        if key_count < 0 || key_count > MAX_MULTISIG_KEYS as i64 {
+            return Err(ScriptError::InvalidKeyCount { count: key_count });
+        }
+
+        let mut public_keys = Vec::new();
+        for _ in 0..key_count {
+            public_keys.push(self.pop()?);
+        }
+        public_keys.reverse();
+
+        let threshold = self.pop_number()?;
+        if threshold < 0 || threshold > key_count {
+            return Err(ScriptError::InvalidSignatureCount { count: threshold });
+        }
+
+        let mut signatures = Vec::new();
+        for _ in 0..threshold {
+            signatures.push(self.pop()?);
+        }
+        signatures.reverse();
+
+        // Each key can only match one signature, and a key that didn't match
+        // a signature is not tried again for the later ones
+        let mut keys = public_keys.iter();
+        let valid = signatures.iter().all(|signature| {
+            keys.any(|public_key| self.checker.check_signature(signature, public_key))
+        });
+
+        Ok(valid)
+    }
+}
+
+/// Runs `script_sig` and then `script_pubkey`, and checks that they unlock the
+/// output
+pub fn verify_script(
+    script_sig: &ScriptSig,
+    script_pubkey: &ScriptPubKey,
+    checker: &dyn SignatureChecker,
+) -> Result<(), ScriptError> {
+    if !script_sig.is_push_only() {
+        return Err(ScriptError::NotPushOnly);
+    }
+
+    let mut interpreter = Interpreter {
+        stack: Vec::new(),
+        checker,
+    };
+
+    interpreter.run(script_sig)?;
+    let mut redeem_stack = interpreter.stack.clone();
+
+    interpreter.run(script_pubkey)?;
+    interpreter.check_result()?;
+
+    if script_pubkey.script_hash().is_some() {
+        // The hash of the redeem script matched, so it is run on the values
+        // that the unlocking script pushed before it
+        let redeem_script = redeem_stack.pop().ok_or(ScriptError::StackUnderflow)?;
+        let redeem_script =
+            Script::decode(&redeem_script).map_err(|_| ScriptError::InvalidRedeemScript)?;
+
+        interpreter.stack = redeem_stack;
+        interpreter.run(&redeem_script)?;
+        interpreter.check_result()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,83 @@
+/// Which parts of a transaction a signature covers
+///
+/// The type is appended to each signature as a single byte, so every input
+/// can be signed with a different type.
+///
+/// - `All` signs all inputs and outputs.
+/// - `None` signs the inputs but none of the outputs, so anyone can decide
+///   where the coins go.
+/// - `Single` signs the inputs and only the output with the same index as the
+///   signed input.
+///
+/// The `AnyoneCanPay` variants only sign the signed input, so others can add
+/// inputs to the transaction, e.g. to fund an output together.
+///
+/// Apart from `All`, the types also leave the sequences of the other inputs
+/// unsigned, so they can be changed by their owners.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SigHashType {
+    All,
+    None,
+    Single,
+    AllAnyoneCanPay,
+    NoneAnyoneCanPay,
+    SingleAnyoneCanPay,
+}
+
+const SIGHASH_ALL: u8 = 0x01;
+const SIGHASH_NONE: u8 = 0x02;
+const SIGHASH_SINGLE: u8 = 0x03;
+const SIGHASH_ANYONECANPAY: u8 = 0x80;
+
+impl SigHashType {
+    pub fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            SIGHASH_ALL => Some(SigHashType::All),
+            SIGHASH_NONE => Some(SigHashType::None),
+            SIGHASH_SINGLE => Some(SigHashType::Single),
+            b if b == SIGHASH_ALL | SIGHASH_ANYONECANPAY => Some(SigHashType::AllAnyoneCanPay),
+            b if b == SIGHASH_NONE | SIGHASH_ANYONECANPAY => Some(SigHashType::NoneAnyoneCanPay),
+            b if b == SIGHASH_SINGLE | SIGHASH_ANYONECANPAY => {
+                Some(SigHashType::SingleAnyoneCanPay)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            SigHashType::All => SIGHASH_ALL,
+            SigHashType::None => SIGHASH_NONE,
+            SigHashType::Single => SIGHASH_SINGLE,
+            SigHashType::AllAnyoneCanPay => SIGHASH_ALL | SIGHASH_ANYONECANPAY,
+            SigHashType::NoneAnyoneCanPay => SIGHASH_NONE | SIGHASH_ANYONECANPAY,
+            SigHashType::SingleAnyoneCanPay => SIGHASH_SINGLE | SIGHASH_ANYONECANPAY,
+        }
+    }
+
+    pub fn anyone_can_pay(self) -> bool {
+        self.to_u8() & SIGHASH_ANYONECANPAY != 0
+    }
+
+    pub fn signs_no_outputs(self) -> bool {
+        self.to_u8() & !SIGHASH_ANYONECANPAY == SIGHASH_NONE
+    }
+
+    pub fn signs_single_output(self) -> bool {
+        self.to_u8() & !SIGHASH_ANYONECANPAY == SIGHASH_SINGLE
+    }
+}
+
+impl std::fmt::Display for SigHashType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            SigHashType::All => "ALL",
+            SigHashType::None => "NONE",
+            SigHashType::Single => "SINGLE",
+            SigHashType::AllAnyoneCanPay => "ALL|ANYONECANPAY",
+            SigHashType::NoneAnyoneCanPay => "NONE|ANYONECANPAY",
+            SigHashType::SingleAnyoneCanPay => "SINGLE|ANYONECANPAY",
+        };
+        write!(f, "{}", name)
+    }
+}
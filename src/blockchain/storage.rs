@@ -0,0 +1,182 @@
+//# Storage backends for the blocks of a node
+
+use crate::blockchain::block::Block;
+use crate::util::traits::{Decodable, Hashable};
+use crate::util::types::Bytes;
+
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::Path;
+
+const BLOCK_FILE_NAME: &str = "blocks.dat";
+const RECORD_HEADER_LENGTH: usize = 8; // payload length + checksum
+
+/// Where a node keeps the blocks it has accepted
+///
+/// Blocks are stored in the order they were accepted, so parents always come
+/// before their children and the chain can be rebuilt by replaying them.
+pub trait BlockStore {
+    fn put_block(&mut self, block: &Block) -> io::Result<()>;
+
+    fn contains(&self, hash: &Bytes) -> bool;
+
+    /// Returns all stored blocks in the order they were added
+    fn load_blocks(&mut self) -> io::Result<Vec<Block>>;
+}
+
+/// Keeps the blocks in memory only. Used for simulations and tests.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    blocks: Vec<Block>,
+    hashes: HashSet<String>,
+}
+
+impl BlockStore for MemoryStore {
+    fn put_block(&mut self, block: &Block) -> io::Result<()> {
+        if self.hashes.insert(hex::encode(&block.hash)) {
+            self.blocks.push(block.clone());
+        }
+        Ok(())
+    }
+
+    fn contains(&self, hash: &Bytes) -> bool {
+        self.hashes.contains(&hex::encode(hash))
+    }
+
+    fn load_blocks(&mut self) -> io::Result<Vec<Block>> {
+        Ok(self.blocks.clone())
+    }
+}
+
+/// Stores blocks in an append-only file inside a data directory
+///
+/// Each record is the length of the encoded block (4 bytes, little endian),
+/// the first 4 bytes of the SHA256 hash of the encoded block, and the block in
+/// the binary encoding of `Hashable::encode`. The index from block hash to
+/// record offset is rebuilt when the store is opened. A record that was only
+/// partially written, e.g. because the node was killed, fails the length or
+/// checksum check and is truncated.
+pub struct FileStore {
+    file: File,
+    index: HashMap<String, u64>,
+}
+
+impl FileStore {
+    pub fn open(data_dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(data_dir)?;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(data_dir.join(BLOCK_FILE_NAME))?;
+
+        let mut store = FileStore {
+            file,
+            index: HashMap::new(),
+        };
+
+        let (blocks, valid_length) = store.scan()?;
+        let file_length = store.file.metadata()?.len();
+
+        if valid_length < file_length {
+            warn!(
+                "[Storage] Truncating {} bytes of incomplete block data in {}",
+                file_length - valid_length,
+                data_dir.display()
+            );
+            store.file.set_len(valid_length)?;
+            store.file.sync_all()?;
+        }
+
+        for (offset, block) in blocks.iter() {
+            store.index.insert(hex::encode(&block.hash), *offset);
+        }
+
+        Ok(store)
+    }
+
+    /// Reads the records from the start of the file, and returns the blocks
+    /// with their offsets and the length of the valid part of the file
+    fn scan(&mut self) -> io::Result<(Vec<(u64, Block)>, u64)> {
+        let mut data = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut data)?;
+
+        let mut blocks = Vec::new();
+        let mut offset: usize = 0;
+
+        while let Some((block, record_length)) = decode_record(&data[offset..]) {
+            blocks.push((offset as u64, block));
+            offset += record_length;
+        }
+
+        Ok((blocks, offset as u64))
+    }
+}
+
+impl BlockStore for FileStore {
+    fn put_block(&mut self, block: &Block) -> io::Result<()> {
+        if self.contains(&block.hash) {
+            return Ok(());
+        }
+
+        let record = encode_record(block);
+        let offset = self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+
+        self.index.insert(hex::encode(&block.hash), offset);
+        Ok(())
+    }
+
+    fn contains(&self, hash: &Bytes) -> bool {
+        self.index.contains_key(&hex::encode(hash))
+    }
+
+    fn load_blocks(&mut self) -> io::Result<Vec<Block>> {
+        let (blocks, _) = self.scan()?;
+        Ok(blocks.into_iter().map(|(_, block)| block).collect())
+    }
+}
+
+fn checksum(payload: &[u8]) -> Bytes {
+    crypto_hash::digest(crypto_hash::Algorithm::SHA256, payload)[0..4].to_vec()
+}
+
+fn encode_record(block: &Block) -> Bytes {
+    let payload = block.encode();
+
+    let mut record = Vec::with_capacity(RECORD_HEADER_LENGTH + payload.len());
+    record.extend(&(payload.len() as u32).to_le_bytes());
+    record.extend(checksum(&payload));
+    record.extend(payload);
+    record
+}
+
+/// Decodes the record at the start of `data`, and returns the block and the
+/// length of the record
+fn decode_record(data: &[u8]) -> Option<(Block, usize)> {
+    if data.len() < RECORD_HEADER_LENGTH {
+        return None;
+    }
+
+    let mut length = [0u8; 4];
+    length.copy_from_slice(&data[0..4]);
+    let record_length = RECORD_HEADER_LENGTH + u32::from_le_bytes(length) as usize;
+
+    if data.len() < record_length {
+        return None;
+    }
+
+    let payload = &data[RECORD_HEADER_LENGTH..record_length];
+    if checksum(payload) != data[4..RECORD_HEADER_LENGTH] {
+        return None;
+    }
+
+    let block = Block::decode(payload).ok()?;
+    Some((block, record_length))
+}
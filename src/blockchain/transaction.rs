@@ -1,48 +1,144 @@
-use crate::blockchain::txn::{TxnInput, TxnOutput};
+use crate::blockchain::mempool::Mempool;
+use crate::blockchain::multisig::{Multisig, PartialSignature};
+use crate::blockchain::script::{
+    verify_script, Op, Script, ScriptError, ScriptPubKey, SignatureChecker,
+};
+use crate::blockchain::sighash::SigHashType;
+use crate::blockchain::txn::{OutPoint, RelativeLock, TxnInput, TxnOutput, ValueOutOfRange};
+use crate::blockchain::validation::TransactionValidationError;
 use crate::blockchain::wallet::Wallet;
 use crate::blockchain::BlockChain;
-use crate::util::traits::Hashable;
+use crate::util::constants::{LOCKTIME_THRESHOLD, SEQUENCE_FINAL, SEQUENCE_MAX_REPLACEABLE};
+use crate::util::encoding::{DecodeError, Decoder, Encoder};
+use crate::util::hex_bytes;
+use crate::util::traits::{Decodable, Hashable};
 
 use rand::prelude::*;
 use secp256k1::{Message, Secp256k1};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-const COINBASE_REWARD: i32 = 20;
-
 type Bytes = Vec<u8>;
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Transaction {
+    #[serde(with = "hex_bytes")]
     pub id: Vec<u8>,
     pub inputs: Vec<TxnInput>,
     pub outputs: Vec<TxnOutput>,
+    pub lock_time: i64, // the block height, or the timestamp, from which it can be mined
 }
 
 impl Hashable for Transaction {
     fn encode(&self) -> Vec<u8> {
-        let mut bytes: Vec<u8> = Vec::new();
+        let mut encoder = Encoder::new();
 
-        bytes.extend(&self.id);
-        bytes.extend(
-            self.inputs
-                .iter()
-                .flat_map(|input| input.encode())
-                .collect::<Vec<u8>>(),
-        );
-        bytes.extend(
-            self.outputs
-                .iter()
-                .flat_map(|output| output.encode())
-                .collect::<Vec<u8>>(),
-        );
+        encoder.put_bytes(&self.id);
 
-        bytes
+        encoder.put_len(self.inputs.len());
+        for input in self.inputs.iter() {
+            encoder.put_encoded(&input.encode());
+        }
+
+        encoder.put_len(self.outputs.len());
+        for output in self.outputs.iter() {
+            encoder.put_encoded(&output.encode());
+        }
+
+        encoder.put_i64(self.lock_time);
+        encoder.finish()
+    }
+}
+
+impl Decodable for Transaction {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let id = decoder.get_bytes()?;
+
+        let mut inputs = Vec::new();
+        for _ in 0..decoder.get_len()? {
+            inputs.push(TxnInput::decode_from(decoder)?);
+        }
+
+        let mut outputs = Vec::new();
+        for _ in 0..decoder.get_len()? {
+            outputs.push(TxnOutput::decode_from(decoder)?);
+        }
+
+        Ok(Transaction {
+            id,
+            inputs,
+            outputs,
+            lock_time: decoder.get_i64()?,
+        })
+    }
+}
+
+/// How `Transaction::new` locks and signs a transaction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransactionOptions {
+    pub sighash_type: SigHashType,
+    pub lock_time: i64, // 0 if it can be mined right away, see `set_lock_time`
+    pub relative_lock: Option<RelativeLock>, // of every input
+    pub replaceable: bool, // whether it opts in to replace-by-fee
+}
+
+impl Default for TransactionOptions {
+    fn default() -> Self {
+        TransactionOptions {
+            sighash_type: SigHashType::All,
+            lock_time: 0,
+            relative_lock: None,
+            replaceable: false,
+        }
     }
 }
 
 impl Transaction {
-    pub fn new(from: &Bytes, to: &Bytes, amount: i32, chain: &BlockChain) -> Self {
+    /// Creates a transaction that sends `amount` to `to` and leaves `fee` to
+    /// the miner, and sends the rest of the spent outputs back to `from`
+    ///
+    /// Outputs that transactions in `mempool` already spend are not selected.
+    ///
+    /// The transaction is locked as the options ask, and the inputs are signed
+    /// with signatures of their sighash type.
+    pub fn new(
+        from: &Bytes,
+        to: &Bytes,
+        amount: i32,
+        fee: i32,
+        options: TransactionOptions,
+        chain: &BlockChain,
+        mempool: &Mempool,
+    ) -> Result<Self, String> {
+        let mut txn = Self::new_unsigned(from, to, amount, fee, options, chain, mempool)?;
+
+        let wallet_data = Wallet::from_address(from);
+        let private_key = secp256k1::key::SecretKey::from_slice(&wallet_data.private_key)
+            .map_err(|err| format!("Invalid private key: {}", err))?;
+        chain.sign_transaction(&mut txn, private_key, options.sighash_type)?;
+
+        // `from` may not hold the keys of every output it selected, e.g. if
+        // it is the address of a multisig
+        chain
+            .verify_transaction(&txn)
+            .map_err(|err| format!("Couldn't sign the transaction: {}", err))?;
+
+        Ok(txn)
+    }
+
+    /// Creates the same transaction as `new`, but leaves the inputs unsigned
+    ///
+    /// `from` can also be the address of a multisig, whose key holders then
+    /// sign the transaction with `sign_partial`.
+    pub fn new_unsigned(
+        from: &Bytes,
+        to: &Bytes,
+        amount: i32,
+        fee: i32,
+        options: TransactionOptions,
+        chain: &BlockChain,
+        mempool: &Mempool,
+    ) -> Result<Self, String> {
         // Validate the `from` and the `to` addresses
         if !Wallet::is_address_valid(from) {
             eprintln!("Address {} is not a valid address.", hex::encode(from));
@@ -55,15 +151,24 @@ impl Transaction {
         let mut inputs: Vec<TxnInput> = Vec::new();
         let mut outputs: Vec<TxnOutput> = Vec::new();
 
-        let wallet_data = Wallet::from_address(from);
-        let public_key_hash = wallet_data.public_key_hash;
-        let public_key = wallet_data.public_key;
-        let private_key = secp256k1::key::SecretKey::from_slice(&wallet_data.private_key).unwrap();
+        let (_, locking_hash) = Wallet::decode_address(from);
 
-        let (acc, valid_outputs) = chain.find_spendable_outputs(&public_key_hash, amount);
+        if fee < 0 {
+            return Err(format!("Invalid fee: {}", fee));
+        }
+
+        let needed = amount
+            .checked_add(fee)
+            .ok_or_else(|| format!("Invalid amount: {}", ValueOutOfRange))?;
+        let (acc, valid_outputs) = chain
+            .find_spendable_outputs(&locking_hash, needed, &mempool.spent_outpoints())
+            .map_err(|err| format!("Couldn't select outputs: {}", err))?;
 
-        if acc < amount {
-            panic!("Error: Not Enough Funds");
+        if acc < needed {
+            return Err(format!(
+                "Not enough funds: {} available, {} needed",
+                acc, needed
+            ));
         }
 
         let mut txn_id;
@@ -75,8 +180,8 @@ impl Transaction {
                 let input = TxnInput {
                     id: txn_id.clone(),
                     out: out.clone(),
-                    signature: vec![],
-                    public_key: public_key.clone(),
+                    script_sig: Script::default(),
+                    sequence: SEQUENCE_FINAL,
                 };
                 inputs.push(input);
             }
@@ -84,24 +189,103 @@ impl Transaction {
 
         outputs.push(TxnOutput::new(amount, &to));
 
-        if acc > amount {
-            outputs.push(TxnOutput::new(acc - amount, from));
+        if acc > needed {
+            outputs.push(TxnOutput::new(acc - needed, from));
         }
 
         let mut txn = Transaction {
-            id: vec![0; 32],
+            id: vec![],
             inputs,
             outputs,
+            lock_time: 0,
         };
-        txn.id = txn.hash();
-        chain.sign_transaction(&mut txn, private_key);
 
-        txn
+        txn.set_lock_time(options.lock_time);
+        if options.relative_lock.is_some() {
+            for input_idx in 0..txn.inputs.len() {
+                txn.set_relative_lock(input_idx, options.relative_lock);
+            }
+        }
+        if options.replaceable {
+            txn.set_replaceable();
+        }
+
+        Ok(txn)
+    }
+
+    /// Returns the id the transaction should have, i.e. the hash of the
+    /// transaction with an empty id and, unless it is a coinbase transaction,
+    /// empty unlocking scripts
+    ///
+    /// Signing doesn't change the id, and the same transaction can't get
+    /// another id by being signed differently. The unlocking script of a
+    /// coinbase transaction holds random data instead, which makes its id
+    /// unique.
+    pub fn compute_id(&self) -> Bytes {
+        let mut txn_copy = self.clone();
+        txn_copy.id = vec![];
+
+        if !self.is_coinbase() {
+            for input in txn_copy.inputs.iter_mut() {
+                input.script_sig = Script::default();
+            }
+        }
+
+        txn_copy.hash()
     }
 
-    /// Create a coinbase transaction, i.e. the first transaction for the
-    /// genesis block
-    pub fn create_coinbase_txn(to: &Bytes) -> Self {
+    /// Recomputes the id after the inputs or outputs were changed
+    ///
+    /// Signatures don't cover the id, so inputs can be added to a transaction
+    /// whose inputs are signed with an `AnyoneCanPay` type.
+    pub fn update_id(&mut self) {
+        self.id = self.compute_id();
+    }
+
+    /// Sets the block height (below `LOCKTIME_THRESHOLD`) or the timestamp
+    /// from which the transaction can be mined
+    ///
+    /// This changes the id of the transaction and is covered by the
+    /// signatures, so it has to be set before the transaction is signed.
+    pub fn set_lock_time(&mut self, lock_time: i64) {
+        self.lock_time = lock_time;
+        self.update_id();
+    }
+
+    /// Sets how long after the output an input spends was mined the
+    /// transaction can be mined, see `set_lock_time`
+    pub fn set_relative_lock(&mut self, input_idx: usize, lock: Option<RelativeLock>) {
+        self.inputs[input_idx].sequence = match lock {
+            Some(lock) => lock.to_sequence(),
+            None => SEQUENCE_FINAL,
+        };
+        self.update_id();
+    }
+
+    /// Signals that the transaction can be replaced in the mempool by one that
+    /// pays a higher fee, see `set_lock_time`
+    ///
+    /// Inputs with a relative lock already signal it.
+    pub fn set_replaceable(&mut self) {
+        for input in self.inputs.iter_mut() {
+            if input.sequence > SEQUENCE_MAX_REPLACEABLE {
+                input.sequence = SEQUENCE_MAX_REPLACEABLE;
+            }
+        }
+        self.update_id();
+    }
+
+    /// Returns whether the transaction opted in to replace-by-fee, i.e.
+    /// whether an input has a sequence up to `SEQUENCE_MAX_REPLACEABLE`
+    pub fn is_replaceable(&self) -> bool {
+        self.inputs
+            .iter()
+            .any(|input| input.sequence <= SEQUENCE_MAX_REPLACEABLE)
+    }
+
+    /// Create a coinbase transaction, i.e. the transaction that pays the
+    /// block reward and the fees of a block's transactions to its miner
+    pub fn create_coinbase_txn(to: &Bytes, value: i32) -> Self {
         let mut rng = rand::thread_rng();
 
         // Coinbase transaction have random data
@@ -114,19 +298,20 @@ impl Transaction {
         let txin = TxnInput {
             id: vec![],
             out: -1,
-            signature: vec![],
-            public_key: data,
+            script_sig: Script::new(vec![Op::Push(data)]),
+            sequence: SEQUENCE_FINAL,
         };
 
-        let txout = TxnOutput::new(COINBASE_REWARD, &to);
+        let txout = TxnOutput::new(value, &to);
 
         let mut transaction = Transaction {
             id: vec![],
             inputs: vec![txin],
             outputs: vec![txout],
+            lock_time: 0,
         };
 
-        transaction.id = transaction.hash();
+        transaction.update_id();
         transaction
     }
 
@@ -138,78 +323,418 @@ impl Transaction {
         self.inputs.len() == 1 && self.inputs[0].id.len() == 0 && self.inputs[0].out == -1
     }
 
+    /// Returns the digest that the signatures of an input with the given
+    /// sighash type sign
+    ///
+    /// It is the hash of a copy of the transaction without its id, in which
+    /// the unlocking script of the input is replaced by the locking script of
+    /// the output it spends, and the unlocking scripts of the other inputs are
+    /// empty. The parts that the sighash type doesn't cover are removed from
+    /// the copy, and the type itself is appended.
+    ///
+    /// Every input's digest only depends on the transaction and the arguments,
+    /// so the same digest is computed when signing and when verifying. Returns
+    /// `None` if there is no input `input_idx`, or for `Single` types if there
+    /// is no output with the index of the input.
+    pub fn signature_hash(
+        &self,
+        input_idx: usize,
+        script_pubkey: &ScriptPubKey,
+        sighash_type: SigHashType,
+    ) -> Option<Bytes> {
+        if input_idx >= self.inputs.len() {
+            return None;
+        }
+
+        let mut txn_copy = self.clone();
+        txn_copy.id = vec![];
+
+        for (idx, input) in txn_copy.inputs.iter_mut().enumerate() {
+            if idx == input_idx {
+                input.script_sig = script_pubkey.clone();
+                continue;
+            }
+
+            input.script_sig = Script::default();
+            if sighash_type != SigHashType::All {
+                input.sequence = 0;
+            }
+        }
+
+        if sighash_type.signs_no_outputs() {
+            txn_copy.outputs.clear();
+        }
+
+        if sighash_type.signs_single_output() {
+            if input_idx >= txn_copy.outputs.len() {
+                return None;
+            }
+
+            // The earlier outputs keep their place, but not their content
+            txn_copy.outputs.truncate(input_idx + 1);
+            for output in txn_copy.outputs.iter_mut().take(input_idx) {
+                output.value = -1;
+                output.script_pubkey = Script::default();
+            }
+        }
+
+        if sighash_type.anyone_can_pay() {
+            txn_copy.inputs = vec![txn_copy.inputs.swap_remove(input_idx)];
+        }
+
+        let mut preimage = txn_copy.encode();
+        preimage.push(sighash_type.to_u8());
+        Some(crypto_hash::digest(
+            crypto_hash::Algorithm::SHA256,
+            &preimage,
+        ))
+    }
+
+    /// Signs an input with the given sighash type, and returns the compact
+    /// signature followed by the sighash type
+    fn sign_input(
+        &self,
+        input_idx: usize,
+        script_pubkey: &ScriptPubKey,
+        private_key: &secp256k1::SecretKey,
+        sighash_type: SigHashType,
+    ) -> Result<Bytes, String> {
+        let digest = match self.signature_hash(input_idx, script_pubkey, sighash_type) {
+            Some(digest) => digest,
+            None => {
+                return Err(format!(
+                    "Input {} can't be signed with {}: there is no output {}",
+                    input_idx, sighash_type, input_idx
+                ))
+            }
+        };
+
+        let message = Message::from_slice(&digest).map_err(|err| err.to_string())?;
+        let mut signature = Secp256k1::signing_only()
+            .sign(&message, private_key)
+            .serialize_compact()
+            .to_vec();
+        signature.push(sighash_type.to_u8());
+
+        Ok(signature)
+    }
+
+    /// Checks a signature made by `sign_input`
+    fn check_input_signature(
+        &self,
+        input_idx: usize,
+        script_pubkey: &ScriptPubKey,
+        signature: &[u8],
+        public_key: &[u8],
+    ) -> bool {
+        let (sighash_type, signature) = match signature.split_last() {
+            Some((sighash_type, signature)) => (*sighash_type, signature),
+            None => return false,
+        };
+
+        let sighash_type = match SigHashType::from_u8(sighash_type) {
+            Some(sighash_type) => sighash_type,
+            None => return false,
+        };
+
+        let signature = match secp256k1::Signature::from_compact(signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+
+        let public_key = match secp256k1::PublicKey::from_slice(public_key) {
+            Ok(public_key) => public_key,
+            Err(_) => return false,
+        };
+
+        let digest = match self.signature_hash(input_idx, script_pubkey, sighash_type) {
+            Some(digest) => digest,
+            None => return false,
+        };
+        let message = match Message::from_slice(&digest) {
+            Ok(message) => message,
+            Err(_) => return false,
+        };
+
+        Secp256k1::verification_only()
+            .verify(&message, &signature, &public_key)
+            .is_ok()
+    }
+
+    /// Unlocks the inputs that spend outputs locked to the public key hash
+    /// of `private_key`, with signatures of the given sighash type
     pub fn sign(
         &mut self,
         private_key: secp256k1::SecretKey,
-        prev_txns: HashMap<String, &Transaction>,
+        prev_outputs: &HashMap<OutPoint, TxnOutput>,
+        sighash_type: SigHashType,
     ) -> Result<(), String> {
         if self.is_coinbase() {
             return Ok(());
         }
 
         for input in &self.inputs {
-            if !prev_txns.contains_key(&hex::encode(&input.id)) {
+            if !prev_outputs.contains_key(&input.outpoint()) {
                 return Err("The transaction is not present in the history".to_string());
             }
         }
 
-        let mut txn_copy = self.clone();
         let secp = Secp256k1::new();
+        let public_key = secp256k1::PublicKey::from_secret_key(&secp, &private_key)
+            .serialize()
+            .to_vec();
+        let public_key_hash = Wallet::generate_sha256_ripemd160_hash(&public_key);
 
-        for (input_idx, input_data) in self.inputs.iter_mut().enumerate() {
-            let prev_txn = &prev_txns[&hex::encode(&input_data.id)];
-            txn_copy.inputs[input_idx].signature = vec![];
-            txn_copy.inputs[input_idx].public_key = prev_txn.outputs[input_data.out as usize]
-                .public_key_hash
-                .clone();
-            txn_copy.id = txn_copy.hash();
-            txn_copy.inputs[input_idx].public_key = vec![];
+        for input_idx in 0..self.inputs.len() {
+            let prev_output = &prev_outputs[&self.inputs[input_idx].outpoint()];
+            if prev_output.public_key_hash() != Some(&public_key_hash) {
+                continue;
+            }
 
-            let message = Message::from_slice(&txn_copy.id).unwrap();
-            let signature = secp.sign(&message, &private_key);
+            let signature = self.sign_input(
+                input_idx,
+                &prev_output.script_pubkey,
+                &private_key,
+                sighash_type,
+            )?;
 
-            // It was self.inputs[input_idx]
-            input_data.signature = signature.serialize_compact().to_vec();
+            self.inputs[input_idx].script_sig = Script::p2pkh_sig(&signature, &public_key);
         }
 
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn verify(&mut self, prev_txns: HashMap<String, &Transaction>) -> Result<bool, &str> {
-        if self.is_coinbase() {
-            return Ok(true);
+    /// Unlocks the inputs that spend outputs of `chain` locked to `multisig`
+    /// with signatures by the wallets at the `signers` addresses
+    ///
+    /// Each signer signs with `sign_partial`, and the signatures are combined
+    /// once all of them have signed.
+    pub fn sign_multisig(
+        &mut self,
+        multisig: &Multisig,
+        signers: &[Bytes],
+        sighash_type: SigHashType,
+        chain: &BlockChain,
+    ) -> Result<(), String> {
+        let mut signatures = Vec::new();
+        for signer in signers {
+            let wallet_data = Wallet::from_address(signer);
+            let private_key = secp256k1::key::SecretKey::from_slice(&wallet_data.private_key)
+                .map_err(|err| format!("Invalid private key: {}", err))?;
+            signatures.extend(chain.sign_partial(self, private_key, multisig, sighash_type)?);
         }
 
-        for input in &self.inputs {
-            if !prev_txns.contains_key(&hex::encode(&input.id)) {
-                return Err("The transaction is not present in the history");
+        chain.combine_signatures(self, multisig, &signatures)
+    }
+
+    /// Signs the inputs that spend outputs locked to `multisig` with one of
+    /// its keys, with signatures of the given sighash type
+    pub fn sign_partial(
+        &self,
+        private_key: secp256k1::SecretKey,
+        multisig: &Multisig,
+        prev_outputs: &HashMap<OutPoint, TxnOutput>,
+        sighash_type: SigHashType,
+    ) -> Result<Vec<PartialSignature>, String> {
+        let secp = Secp256k1::new();
+        let public_key = secp256k1::PublicKey::from_secret_key(&secp, &private_key)
+            .serialize()
+            .to_vec();
+
+        if !multisig.public_keys().contains(&public_key) {
+            return Err("The key is not one of the keys of the multisig".to_string());
+        }
+
+        let script_pubkey = multisig.script_pubkey();
+        let mut signatures = Vec::new();
+
+        for (input_idx, input) in self.inputs.iter().enumerate() {
+            let prev_output = match prev_outputs.get(&input.outpoint()) {
+                Some(prev_output) => prev_output,
+                None => return Err("The transaction is not present in the history".to_string()),
+            };
+
+            if prev_output.script_pubkey != script_pubkey {
+                continue;
             }
+
+            signatures.push(PartialSignature {
+                input: input_idx,
+                public_key: public_key.clone(),
+                signature: self.sign_input(
+                    input_idx,
+                    &script_pubkey,
+                    &private_key,
+                    sighash_type,
+                )?,
+            });
         }
 
-        let mut txn_copy = self.clone();
-        let secp = Secp256k1::new();
+        Ok(signatures)
+    }
 
-        for (input_idx, input_data) in self.inputs.iter().enumerate() {
-            let prev_txn = &prev_txns[&hex::encode(&input_data.id)];
-            txn_copy.inputs[input_idx].signature = vec![];
-            txn_copy.inputs[input_idx].public_key = prev_txn.outputs[input_data.out as usize]
-                .public_key_hash
-                .clone();
-            txn_copy.id = txn_copy.hash();
-            txn_copy.inputs[input_idx].public_key = vec![];
-
-            let message = Message::from_slice(&txn_copy.id).unwrap();
-            let signature = secp256k1::Signature::from_compact(&input_data.signature).unwrap();
-            let public_key = secp256k1::PublicKey::from_slice(&input_data.public_key).unwrap();
-
-            match secp.verify(&message, &signature, &public_key) {
-                Ok(_) => (),
-                Err(_) => return Err("secp256k1 error"),
+    /// Unlocks the inputs that spend outputs locked to `multisig` with the
+    /// partial signatures of its key holders
+    ///
+    /// Signatures that are invalid or by keys that are not part of the
+    /// multisig are ignored. Every input needs valid signatures by at least
+    /// `threshold` keys.
+    pub fn combine_signatures(
+        &mut self,
+        multisig: &Multisig,
+        prev_outputs: &HashMap<OutPoint, TxnOutput>,
+        signatures: &[PartialSignature],
+    ) -> Result<(), String> {
+        let script_pubkey = multisig.script_pubkey();
+        let redeem_script = multisig.redeem_script().encode();
+
+        for input_idx in 0..self.inputs.len() {
+            match prev_outputs.get(&self.inputs[input_idx].outpoint()) {
+                Some(prev_output) if prev_output.script_pubkey == script_pubkey => (),
+                Some(_) => continue,
+                None => return Err("The transaction is not present in the history".to_string()),
+            }
+
+            let is_valid = |partial: &&PartialSignature, public_key: &Bytes| {
+                partial.input == input_idx
+                    && partial.public_key == *public_key
+                    && self.check_input_signature(
+                        input_idx,
+                        &script_pubkey,
+                        &partial.signature,
+                        public_key,
+                    )
+            };
+
+            // The signatures have to be in the order of the keys
+            let mut ops = multisig
+                .public_keys()
+                .iter()
+                .filter_map(|public_key| {
+                    signatures
+                        .iter()
+                        .find(|partial| is_valid(partial, public_key))
+                        .map(|partial| Op::Push(partial.signature.clone()))
+                })
+                .take(multisig.threshold())
+                .collect::<Vec<Op>>();
+
+            if ops.len() < multisig.threshold() {
+                return Err(format!(
+                    "Input {} has {} of the {} signatures it needs",
+                    input_idx,
+                    ops.len(),
+                    multisig.threshold()
+                ));
             }
+
+            ops.push(Op::Push(redeem_script.clone()));
+            self.inputs[input_idx].script_sig = Script::new(ops);
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every input unlocks the output it spends
+    ///
+    /// Unlike `BlockChain::validate_transaction`, this doesn't check the
+    /// values, or whether the outputs are still unspent.
+    pub fn verify(
+        &self,
+        prev_outputs: &HashMap<OutPoint, TxnOutput>,
+    ) -> Result<(), TransactionValidationError> {
+        if self.is_coinbase() {
+            return Ok(());
         }
 
-        Ok(true)
+        for (input_idx, input) in self.inputs.iter().enumerate() {
+            let prev_output = match prev_outputs.get(&input.outpoint()) {
+                Some(prev_output) => prev_output,
+                None => {
+                    return Err(TransactionValidationError::MissingPreviousOutput {
+                        txn_id: hex::encode(&input.id),
+                        out: input.out,
+                    })
+                }
+            };
+
+            self.verify_input(input_idx, &prev_output.script_pubkey)
+                .map_err(|reason| TransactionValidationError::ScriptFailed {
+                    input: input_idx,
+                    reason,
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the unlocking script of an input against the locking script of
+    /// the output it spends
+    pub fn verify_input(
+        &self,
+        input_idx: usize,
+        script_pubkey: &ScriptPubKey,
+    ) -> Result<(), ScriptError> {
+        let input = match self.inputs.get(input_idx) {
+            Some(input) => input,
+            None => {
+                return Err(ScriptError::MissingInput {
+                    input: input_idx,
+                    inputs: self.inputs.len(),
+                })
+            }
+        };
+
+        let checker = InputChecker {
+            txn: self,
+            input_idx,
+            script_pubkey,
+        };
+
+        verify_script(&input.script_sig, script_pubkey, &checker)
+    }
+}
+
+/// Checks the signatures and time locks in the scripts of one input
+struct InputChecker<'a> {
+    txn: &'a Transaction,
+    input_idx: usize,
+    script_pubkey: &'a ScriptPubKey,
+}
+
+impl<'a> SignatureChecker for InputChecker<'a> {
+    fn check_signature(&self, signature: &[u8], public_key: &[u8]) -> bool {
+        self.txn
+            .check_input_signature(self.input_idx, self.script_pubkey, signature, public_key)
+    }
+
+    /// The transaction is only mined once its lock time is reached, so a
+    /// lock time up to the transaction's is reached too
+    fn check_lock_time(&self, lock_time: i64) -> bool {
+        let txn_lock_time = self.txn.lock_time;
+
+        // Heights and timestamps can't be compared
+        if (lock_time < LOCKTIME_THRESHOLD) != (txn_lock_time < LOCKTIME_THRESHOLD) {
+            return false;
+        }
+
+        lock_time <= txn_lock_time
+    }
+
+    /// The same as `check_lock_time`, for the relative lock of the input
+    fn check_sequence(&self, sequence: i64) -> bool {
+        let required = match RelativeLock::from_sequence(sequence as u32) {
+            Some(required) => required,
+            None => return true,
+        };
+
+        match (required, self.txn.inputs[self.input_idx].relative_lock()) {
+            (RelativeLock::Blocks(required), Some(RelativeLock::Blocks(lock))) => required <= lock,
+            (RelativeLock::Seconds(required), Some(RelativeLock::Seconds(lock))) => {
+                required <= lock
+            }
+            _ => false,
+        }
     }
 }
 
@@ -220,13 +745,13 @@ impl std::fmt::Display for TxnInput {
             "
                 ID: {},
                 Out: {},
-                Signature: {},
-                Public Key: {}
+                Script Sig: {},
+                Sequence: {:#x}
             ",
             hex::encode(&self.id),
             self.out,
-            hex::encode(&self.signature),
-            hex::encode(&self.public_key)
+            self.script_sig,
+            self.sequence
         )
     }
 }
@@ -237,10 +762,9 @@ impl std::fmt::Display for TxnOutput {
             f,
             "
                 Val: {},
-                Public Key Hash: {}
+                Script Pub Key: {}
             ",
-            self.value,
-            hex::encode(&self.public_key_hash)
+            self.value, self.script_pubkey
         )
     }
 }
@@ -253,6 +777,7 @@ impl std::fmt::Display for Transaction {
         Transaction ID: {}
             Inputs: {}
             Outputs: {}
+            Lock Time: {}
             ",
             hex::encode(&self.id),
             self.inputs
@@ -262,7 +787,268 @@ impl std::fmt::Display for Transaction {
             self.outputs
                 .iter()
                 .map(|op| format!("{}", op))
-                .collect::<String>()
+                .collect::<String>(),
+            self.lock_time
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::constants::VERSION;
+
+    /// Returns a transaction with two inputs and two outputs
+    fn sample_transaction() -> Transaction {
+        let mut txn = Transaction {
+            id: vec![],
+            inputs: vec![
+                TxnInput {
+                    id: vec![1; 32],
+                    out: 0,
+                    script_sig: Script::p2pkh_sig(&[2; 71], &[3; 33]),
+                    sequence: SEQUENCE_FINAL,
+                },
+                TxnInput {
+                    id: vec![4; 32],
+                    out: 3,
+                    script_sig: Script::new(vec![Op::Number(-7), Op::Push(vec![])]),
+                    sequence: 10,
+                },
+            ],
+            outputs: vec![
+                TxnOutput {
+                    value: 5,
+                    script_pubkey: Script::p2pkh(&[5; 20]),
+                },
+                TxnOutput {
+                    value: 0,
+                    script_pubkey: Script::default(),
+                },
+            ],
+            lock_time: 42,
+        };
+        txn.update_id();
+        txn
+    }
+
+    /// Returns a private key, and two outputs locked to it with the
+    /// transaction with two inputs and two outputs that spends them
+    fn spending_transaction() -> (
+        secp256k1::SecretKey,
+        HashMap<OutPoint, TxnOutput>,
+        Transaction,
+    ) {
+        let mut rng = rand::rngs::OsRng::new().unwrap();
+        let (private_key, public_key) = Secp256k1::new().generate_keypair(&mut rng);
+        let public_key_hash =
+            Wallet::generate_sha256_ripemd160_hash(&public_key.serialize().to_vec());
+
+        let mut txn = sample_transaction();
+        let mut prev_outputs = HashMap::new();
+        for input in txn.inputs.iter_mut() {
+            input.script_sig = Script::default();
+            prev_outputs.insert(
+                input.outpoint(),
+                TxnOutput {
+                    value: 10,
+                    script_pubkey: Script::p2pkh(&public_key_hash),
+                },
+            );
+        }
+        txn.update_id();
+
+        (private_key, prev_outputs, txn)
+    }
+
+    #[test]
+    fn sighash_types_sign_the_parts_they_cover() {
+        let modifications: [(&str, fn(&mut Transaction)); 6] = [
+            ("change the signed input", |txn| txn.inputs[0].sequence = 1),
+            ("change another input", |txn| txn.inputs[1].sequence = 1),
+            ("add an input", |txn| {
+                let mut input = txn.inputs[1].clone();
+                input.id = vec![9; 32];
+                txn.inputs.push(input);
+            }),
+            ("change the output of the input", |txn| {
+                txn.outputs[0].value += 1
+            }),
+            ("change another output", |txn| txn.outputs[1].value += 1),
+            ("add an output", |txn| {
+                let output = txn.outputs[0].clone();
+                txn.outputs.push(output);
+            }),
+        ];
+
+        // Whether the signature of the first input tolerates each modification
+        let cases = [
+            (SigHashType::All, [false, false, false, false, false, false]),
+            (SigHashType::None, [false, true, false, true, true, true]),
+            (SigHashType::Single, [false, true, false, false, true, true]),
+            (
+                SigHashType::AllAnyoneCanPay,
+                [false, true, true, false, false, false],
+            ),
+            (
+                SigHashType::NoneAnyoneCanPay,
+                [false, true, true, true, true, true],
+            ),
+            (
+                SigHashType::SingleAnyoneCanPay,
+                [false, true, true, false, true, true],
+            ),
+        ];
+
+        for (sighash_type, tolerated) in cases.iter() {
+            let (private_key, prev_outputs, mut txn) = spending_transaction();
+            txn.sign(private_key, &prev_outputs, *sighash_type).unwrap();
+            assert_eq!(txn.verify(&prev_outputs), Ok(()));
+
+            let script_pubkey = &prev_outputs[&txn.inputs[0].outpoint()].script_pubkey;
+            for ((name, modify), tolerated) in modifications.iter().zip(tolerated.iter()) {
+                let mut modified = txn.clone();
+                modify(&mut modified);
+                assert_eq!(
+                    modified.verify_input(0, script_pubkey).is_ok(),
+                    *tolerated,
+                    "{} signature, {}",
+                    sighash_type,
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_input_is_verified_against_its_own_output() {
+        let (first_key, mut prev_outputs, mut txn) = spending_transaction();
+        let (second_key, second_outputs, _) = spending_transaction();
+
+        // Lock the second output to another key
+        let second = txn.inputs[1].outpoint();
+        let other_output = second_outputs.values().next().unwrap().clone();
+        prev_outputs.insert(second.clone(), other_output);
+
+        txn.sign(first_key, &prev_outputs, SigHashType::All)
+            .unwrap();
+        match txn.verify(&prev_outputs) {
+            Err(TransactionValidationError::ScriptFailed { input: 1, .. }) => (),
+            result => panic!("expected input 1 to fail, got {:?}", result),
+        }
+
+        txn.sign(second_key, &prev_outputs, SigHashType::All)
+            .unwrap();
+        assert_eq!(txn.verify(&prev_outputs), Ok(()));
+        for (input_idx, input) in txn.inputs.iter().enumerate() {
+            let script_pubkey = &prev_outputs[&input.outpoint()].script_pubkey;
+            assert_eq!(txn.verify_input(input_idx, script_pubkey), Ok(()));
+        }
+
+        // A signature only unlocks the input it was made for
+        let mut swapped = txn.clone();
+        let first_sig = swapped.inputs[0].script_sig.clone();
+        swapped.inputs[0].script_sig = swapped.inputs[1].script_sig.clone();
+        swapped.inputs[1].script_sig = first_sig;
+        match swapped.verify(&prev_outputs) {
+            Err(TransactionValidationError::ScriptFailed { input: 0, .. }) => (),
+            result => panic!("expected input 0 to fail, got {:?}", result),
+        }
+
+        let first = txn.inputs[0].outpoint();
+        let mut same_key = txn.clone();
+        same_key.inputs[1].script_sig = same_key.inputs[0].script_sig.clone();
+        assert!(same_key
+            .verify_input(1, &prev_outputs[&first].script_pubkey)
+            .is_err());
+
+        match txn.verify(&HashMap::new()) {
+            Err(TransactionValidationError::MissingPreviousOutput { out: 0, .. }) => (),
+            result => panic!("expected a missing output, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn inputs_that_do_not_exist_fail_to_verify() {
+        let (private_key, prev_outputs, mut txn) = spending_transaction();
+        txn.sign(private_key, &prev_outputs, SigHashType::All)
+            .unwrap();
+
+        let script_pubkey = &prev_outputs[&txn.inputs[0].outpoint()].script_pubkey;
+        assert_eq!(
+            txn.verify_input(2, script_pubkey),
+            Err(ScriptError::MissingInput {
+                input: 2,
+                inputs: 2
+            })
+        );
+    }
+
+    #[test]
+    fn single_signatures_need_an_output_with_the_index_of_the_input() {
+        let (private_key, prev_outputs, mut txn) = spending_transaction();
+        txn.outputs.truncate(1);
+        assert!(txn
+            .sign(private_key, &prev_outputs, SigHashType::Single)
+            .is_err());
+    }
+
+    #[test]
+    fn transactions_round_trip() {
+        let txn = sample_transaction();
+        let decoded = Transaction::decode(&txn.encode()).unwrap();
+
+        assert_eq!(decoded.encode(), txn.encode());
+        assert_eq!(decoded.id, txn.id);
+        assert_eq!(decoded.compute_id(), txn.id);
+        assert_eq!(decoded.lock_time, 42);
+        assert_eq!(decoded.inputs[1].sequence, 10);
+    }
+
+    #[test]
+    fn coinbase_transactions_round_trip() {
+        let address = Wallet::encode_address(VERSION, &vec![6; 20]);
+        let coinbase = Transaction::create_coinbase_txn(&address, 50);
+        let decoded = Transaction::decode(&coinbase.encode()).unwrap();
+
+        assert!(decoded.is_coinbase());
+        assert_eq!(decoded.encode(), coinbase.encode());
+        assert_eq!(decoded.compute_id(), coinbase.id);
+    }
+
+    #[test]
+    fn truncated_transactions_are_rejected() {
+        let bytes = sample_transaction().encode();
+        for len in 0..bytes.len() {
+            match Transaction::decode(&bytes[..len]) {
+                Err(DecodeError::UnexpectedEnd { .. }) => (),
+                result => panic!("decoded {} of {} bytes: {:?}", len, bytes.len(), result),
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        let mut bytes = sample_transaction().encode();
+        bytes.push(0);
+        match Transaction::decode(&bytes) {
+            Err(DecodeError::TrailingBytes { remaining: 1 }) => (),
+            result => panic!("expected trailing bytes, got {:?}", result),
+        }
+
+        assert!(Transaction::decode(&[0xff; 64]).is_err());
+
+        // An input whose script has an opcode that doesn't exist
+        let mut encoder = Encoder::new();
+        encoder.put_bytes(&[]);
+        encoder.put_len(1);
+        encoder.put_bytes(&[1; 32]);
+        encoder.put_i32(0);
+        encoder.put_len(1);
+        encoder.put_u8(0xff);
+        match Transaction::decode(&encoder.finish()) {
+            Err(DecodeError::UnknownOpcode { opcode: 0xff }) => (),
+            result => panic!("expected an unknown opcode, got {:?}", result),
+        }
+    }
+}
@@ -1,52 +1,151 @@
+use crate::blockchain::script::{Script, ScriptPubKey, ScriptSig};
 use crate::blockchain::wallet::Wallet;
-use crate::util::traits::Hashable;
+use crate::util::constants::{
+    SCRIPT_VERSION, SEQUENCE_LOCKTIME_DISABLE_FLAG, SEQUENCE_LOCKTIME_MASK,
+    SEQUENCE_LOCKTIME_TYPE_FLAG,
+};
+use crate::util::encoding::{DecodeError, Decoder, Encoder};
+use crate::util::hex_bytes;
+use crate::util::traits::{Decodable, Hashable};
 use crate::util::types::Bytes;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-// TODO: Move constants to a module
-const CHECKSUM_LENGTH: usize = 4;
+/// A sum of values that doesn't fit in the `i32` of a value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ValueOutOfRange;
 
-#[derive(Clone, Debug, Serialize)]
+impl std::fmt::Display for ValueOutOfRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "sum of values is out of range")
+    }
+}
+
+/// Adds up values, failing instead of overflowing
+pub fn sum_values<I: IntoIterator<Item = i32>>(values: I) -> Result<i32, ValueOutOfRange> {
+    values
+        .into_iter()
+        .try_fold(0i32, |sum, value| sum.checked_add(value))
+        .ok_or(ValueOutOfRange)
+}
+
+/// Identifies a transaction output by the transaction id and its index
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub txn_id: Bytes,
+    pub out: i32,
+}
+
+impl OutPoint {
+    pub fn new(txn_id: &Bytes, out: i32) -> Self {
+        OutPoint {
+            txn_id: txn_id.clone(),
+            out,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TxnInput {
-    pub id: Bytes,         // the hash of the transaction
-    pub out: i32,          // index where the output appears
-    pub signature: Bytes,  // similar to pub_key
-    pub public_key: Bytes, // public key that hasn't been hashed
+    #[serde(with = "hex_bytes")]
+    pub id: Bytes, // the hash of the transaction
+    pub out: i32,              // index where the output appears
+    pub script_sig: ScriptSig, // unlocks the output
+    pub sequence: u32,         // the relative lock, see `relative_lock`
 }
 
 impl Hashable for TxnInput {
     fn encode(&self) -> Vec<u8> {
-        let mut bytes: Vec<u8> = Vec::new();
+        let mut encoder = Encoder::new();
 
-        bytes.extend(&self.id);
-        bytes.extend(&self.out.to_le_bytes());
-        bytes.extend(&self.signature);
-        bytes.extend(&self.public_key);
-        bytes
+        encoder.put_bytes(&self.id);
+        encoder.put_i32(self.out);
+        encoder.put_encoded(&self.script_sig.encode());
+        encoder.put_u32(self.sequence);
+        encoder.finish()
+    }
+}
+
+impl Decodable for TxnInput {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        Ok(TxnInput {
+            id: decoder.get_bytes()?,
+            out: decoder.get_i32()?,
+            script_sig: Script::decode_from(decoder)?,
+            sequence: decoder.get_u32()?,
+        })
     }
 }
 
 impl TxnInput {
-    pub fn is_uses_key(&self, public_key_hash: &Bytes) -> bool {
-        let locking_hash = Wallet::generate_sha256_ripemd160_hash(&self.public_key);
-        locking_hash == *public_key_hash
+    pub fn outpoint(&self) -> OutPoint {
+        OutPoint::new(&self.id, self.out)
+    }
+
+    /// Returns how long after the output it spends was mined the input can
+    /// be mined
+    ///
+    /// Unless `SEQUENCE_LOCKTIME_DISABLE_FLAG` is set, the low 16 bits of the
+    /// sequence are a number of blocks, or of seconds if
+    /// `SEQUENCE_LOCKTIME_TYPE_FLAG` is set.
+    pub fn relative_lock(&self) -> Option<RelativeLock> {
+        RelativeLock::from_sequence(self.sequence)
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
+/// The time an input has to wait after the output it spends was mined
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RelativeLock {
+    Blocks(i32),
+    Seconds(i64),
+}
+
+impl RelativeLock {
+    pub fn from_sequence(sequence: u32) -> Option<Self> {
+        if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return None;
+        }
+
+        let value = sequence & SEQUENCE_LOCKTIME_MASK;
+        if sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            Some(RelativeLock::Seconds(value as i64))
+        } else {
+            Some(RelativeLock::Blocks(value as i32))
+        }
+    }
+
+    pub fn to_sequence(self) -> u32 {
+        match self {
+            RelativeLock::Blocks(blocks) => blocks as u32 & SEQUENCE_LOCKTIME_MASK,
+            RelativeLock::Seconds(seconds) => {
+                SEQUENCE_LOCKTIME_TYPE_FLAG | (seconds as u32 & SEQUENCE_LOCKTIME_MASK)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TxnOutput {
     pub value: i32,
-    pub public_key_hash: Bytes, // needed to unlock the tokens in the `value` field
+    pub script_pubkey: ScriptPubKey, // needed to unlock the tokens in the `value` field
 }
 
 impl Hashable for TxnOutput {
     fn encode(&self) -> Bytes {
-        let mut bytes: Bytes = Vec::new();
+        let mut encoder = Encoder::new();
+
+        encoder.put_i32(self.value);
+        encoder.put_encoded(&self.script_pubkey.encode());
+        encoder.finish()
+    }
+}
 
-        bytes.extend(&self.value.to_le_bytes());
-        bytes.extend(&self.public_key_hash);
-        bytes
+impl Decodable for TxnOutput {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        Ok(TxnOutput {
+            value: decoder.get_i32()?,
+            script_pubkey: Script::decode_from(decoder)?,
+        })
     }
 }
 
@@ -54,20 +153,33 @@ impl TxnOutput {
     pub fn new(value: i32, address: &Bytes) -> Self {
         let mut txn_output = Self {
             value,
-            public_key_hash: vec![],
+            script_pubkey: Script::default(),
         };
 
         txn_output.lock(address);
         txn_output
     }
 
+    /// Locks the output to an address, i.e. to the public key hash of a
+    /// wallet or to the hash of a script such as a multisig script
     pub fn lock(&mut self, address: &Bytes) {
-        let decoded_address = bs58::decode(address).into_vec().unwrap();
-        let public_key_hash = &decoded_address[1..(decoded_address.len() - CHECKSUM_LENGTH)];
-        self.public_key_hash = public_key_hash.to_owned();
+        let (version, hash) = Wallet::decode_address(address);
+        self.script_pubkey = match version {
+            SCRIPT_VERSION => Script::p2sh(&hash),
+            _ => Script::p2pkh(&hash),
+        };
+    }
+
+    /// Returns the public key hash the output is locked to, if it is locked
+    /// to a single key
+    pub fn public_key_hash(&self) -> Option<&Bytes> {
+        self.script_pubkey.public_key_hash()
     }
 
-    pub fn is_locked_with_key(&self, public_key_hash: &Bytes) -> bool {
-        self.public_key_hash == *public_key_hash
+    /// Returns the hash in the address the output is locked to
+    pub fn locking_hash(&self) -> Option<&Bytes> {
+        self.script_pubkey
+            .public_key_hash()
+            .or_else(|| self.script_pubkey.script_hash())
     }
 }
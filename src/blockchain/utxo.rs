@@ -0,0 +1,176 @@
+use crate::blockchain::block::Block;
+use crate::blockchain::params::ChainParams;
+use crate::blockchain::txn::{OutPoint, TxnOutput, ValueOutOfRange};
+use crate::util::types::Bytes;
+
+use std::collections::{HashMap, HashSet};
+
+/// The outputs spent by a connected block, needed to disconnect it again
+pub type BlockUndo = Vec<(OutPoint, Utxo)>;
+
+/// An unspent output, and the block and kind of transaction that created it
+#[derive(Clone, Debug)]
+pub struct Utxo {
+    pub output: TxnOutput,
+    pub height: i32,
+    pub time: i64, // the timestamp of the block
+    pub is_coinbase: bool,
+}
+
+impl Utxo {
+    /// Returns whether the output can be spent by a transaction in the block
+    /// at `height`
+    pub fn is_spendable_at(&self, height: i32, params: &ChainParams) -> bool {
+        !self.is_coinbase || params.is_coinbase_mature(self.height, height)
+    }
+}
+
+/// The value of the unspent outputs locked to a public key hash
+///
+/// Coinbase outputs that can't be spent in the next block yet are counted as
+/// immature.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Balance {
+    pub mature: i32,
+    pub immature: i32,
+}
+
+impl std::fmt::Display for Balance {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.immature {
+            0 => write!(f, "{}", self.mature),
+            immature => write!(f, "{} (+{} immature)", self.mature, immature),
+        }
+    }
+}
+
+/// The unspent transaction outputs of the active chain
+///
+/// The set is updated when a block is connected or disconnected, so balance
+/// queries and coin selection don't need to scan the chain. Outputs are also
+/// indexed by the hash in the address they are locked to, i.e. the public key
+/// hash of a wallet or the hash of a multisig script.
+#[derive(Clone, Debug, Default)]
+pub struct UtxoSet {
+    outputs: HashMap<OutPoint, Utxo>,
+    by_owner: HashMap<Bytes, HashSet<OutPoint>>,
+}
+
+impl UtxoSet {
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&Utxo> {
+        self.outputs.get(outpoint)
+    }
+
+    /// Returns the unspent outputs locked to a public key hash, or to a script
+    /// hash
+    pub fn outputs_for(&self, public_key_hash: &Bytes) -> Vec<(&OutPoint, &Utxo)> {
+        match self.by_owner.get(public_key_hash) {
+            Some(outpoints) => outpoints
+                .iter()
+                .filter_map(|outpoint| self.outputs.get_key_value(outpoint))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Returns the balance of a public key hash for a transaction in the
+    /// block at `height`
+    pub fn balance(
+        &self,
+        public_key_hash: &Bytes,
+        height: i32,
+        params: &ChainParams,
+    ) -> Result<Balance, ValueOutOfRange> {
+        let mut balance = Balance::default();
+
+        for (_, utxo) in self.outputs_for(public_key_hash) {
+            let total = if utxo.is_spendable_at(height, params) {
+                &mut balance.mature
+            } else {
+                &mut balance.immature
+            };
+            *total = total
+                .checked_add(utxo.output.value)
+                .ok_or(ValueOutOfRange)?;
+        }
+
+        Ok(balance)
+    }
+
+    fn insert(&mut self, outpoint: OutPoint, utxo: Utxo) {
+        if let Some(owner) = utxo.output.locking_hash() {
+            self.by_owner
+                .entry(owner.clone())
+                .or_insert_with(HashSet::new)
+                .insert(outpoint.clone());
+        }
+
+        self.outputs.insert(outpoint, utxo);
+    }
+
+    fn remove(&mut self, outpoint: &OutPoint) -> Option<Utxo> {
+        let utxo = self.outputs.remove(outpoint)?;
+
+        if let Some(owner) = utxo.output.locking_hash() {
+            if let Some(outpoints) = self.by_owner.get_mut(owner) {
+                outpoints.remove(outpoint);
+                if outpoints.is_empty() {
+                    self.by_owner.remove(owner);
+                }
+            }
+        }
+
+        Some(utxo)
+    }
+
+    /// Spends the inputs and adds the outputs of a block's transactions, and
+    /// returns the spent outputs
+    pub fn apply_block(&mut self, block: &Block) -> BlockUndo {
+        let mut undo = BlockUndo::new();
+        let mut created = HashSet::<OutPoint>::new();
+
+        for txn in block.transactions.iter() {
+            if !txn.is_coinbase() {
+                for input in txn.inputs.iter() {
+                    let outpoint = input.outpoint();
+                    if let Some(utxo) = self.remove(&outpoint) {
+                        // Outputs created earlier in the same block are gone
+                        // again after a revert, so they need no undo data
+                        if !created.contains(&outpoint) {
+                            undo.push((outpoint, utxo));
+                        }
+                    }
+                }
+            }
+
+            for (out, output) in txn.outputs.iter().enumerate() {
+                let outpoint = OutPoint::new(&txn.id, out as i32);
+                created.insert(outpoint.clone());
+                self.insert(
+                    outpoint,
+                    Utxo {
+                        output: output.clone(),
+                        height: block.index,
+                        time: block.timestamp,
+                        is_coinbase: txn.is_coinbase(),
+                    },
+                );
+            }
+        }
+
+        undo
+    }
+
+    /// Reverts `apply_block`
+    pub fn revert_block(&mut self, block: &Block, undo: BlockUndo) {
+        for txn in block.transactions.iter().rev() {
+            for out in 0..txn.outputs.len() {
+                self.remove(&OutPoint::new(&txn.id, out as i32));
+            }
+        }
+
+        for (outpoint, utxo) in undo.into_iter() {
+            self.insert(outpoint, utxo);
+        }
+    }
+}
@@ -0,0 +1,1073 @@
+use crate::blockchain::block::{Block, BlockHeader};
+use crate::blockchain::params::ChainParams;
+use crate::blockchain::script::ScriptError;
+use crate::blockchain::transaction::Transaction;
+use crate::blockchain::txn::{sum_values, OutPoint, RelativeLock};
+use crate::blockchain::BlockChain;
+use crate::util::constants::{LOCKTIME_THRESHOLD, MAX_BLOCK_SIZE, MAX_FUTURE_BLOCK_TIME};
+use crate::util::traits::Hashable;
+use crate::util::types::Bytes;
+
+use chrono::Utc;
+use std::collections::{HashMap, HashSet};
+
+/// The reason a transaction was rejected
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransactionValidationError {
+    InvalidId,
+    NoInputs,
+    NoOutputs,
+    InvalidOutputValue {
+        out: usize,
+        value: i32,
+    },
+    DuplicateInput {
+        txn_id: String,
+        out: i32,
+    },
+    MissingPreviousOutput {
+        txn_id: String,
+        out: i32,
+    },
+    AlreadySpent {
+        txn_id: String,
+        out: i32,
+    },
+    ImmatureCoinbase {
+        txn_id: String,
+        out: i32,
+        height: i32,
+    },
+    ScriptFailed {
+        input: usize,
+        reason: ScriptError,
+    },
+    OutputsExceedInputs {
+        input_total: i32,
+        output_total: i32,
+    },
+    ValueOutOfRange,
+    Premature {
+        lock_points: LockPoints,
+    },
+}
+
+/// The lowest block height and timestamp of a block that a transaction can be
+/// in, due to its lock time and the relative locks of its inputs
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LockPoints {
+    pub height: i32,
+    pub time: i64,
+}
+
+impl LockPoints {
+    /// Returns whether the transaction can be in the block at `height` with
+    /// the timestamp `time`
+    pub fn is_reached(&self, height: i32, time: i64) -> bool {
+        height >= self.height && time >= self.time
+    }
+}
+
+/// The reason a block was rejected
+///
+/// Nodes use this to log the rejection and to decide whether the peer that
+/// sent the block misbehaved.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlockValidationError {
+    UnknownParent,
+    InvalidParent,
+    InvalidIndex {
+        expected: i32,
+        found: i32,
+    },
+    InvalidTimestamp {
+        timestamp: i64,
+    },
+    InvalidHash,
+    InsufficientProofOfWork,
+    BlockTooLarge {
+        size: usize,
+    },
+    NoTransactions,
+    InvalidDifficulty {
+        expected: u128,
+        found: u128,
+    },
+    MissingCoinbase,
+    MultipleCoinbase,
+    DuplicateTransaction {
+        txn_id: String,
+    },
+    DuplicateOutput {
+        txn_id: String,
+        out: i32,
+    },
+    InvalidCoinbaseReward {
+        expected: i32,
+        found: i32,
+    },
+    ValueOutOfRange,
+    DoubleSpend {
+        txn_id: String,
+        out: i32,
+    },
+    InvalidTransaction {
+        txn_id: String,
+        reason: TransactionValidationError,
+    },
+}
+
+/// The reason a chain received from a peer was rejected
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChainValidationError {
+    EmptyChain,
+    InvalidLength {
+        length: i32,
+        blocks: usize,
+    },
+    InvalidLastHash,
+    InvalidBlock {
+        index: usize,
+        reason: BlockValidationError,
+    },
+}
+
+impl std::fmt::Display for TransactionValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::InvalidId => write!(f, "transaction id does not match its contents"),
+            Self::NoInputs => write!(f, "transaction has no inputs"),
+            Self::NoOutputs => write!(f, "transaction has no outputs"),
+            Self::InvalidOutputValue { out, value } => {
+                write!(f, "output {} has invalid value {}", out, value)
+            }
+            Self::DuplicateInput { txn_id, out } => {
+                write!(
+                    f,
+                    "output {}:{} is spent by more than one input",
+                    txn_id, out
+                )
+            }
+            Self::MissingPreviousOutput { txn_id, out } => {
+                write!(f, "output {}:{} does not exist", txn_id, out)
+            }
+            Self::AlreadySpent { txn_id, out } => {
+                write!(f, "output {}:{} is already spent", txn_id, out)
+            }
+            Self::ImmatureCoinbase {
+                txn_id,
+                out,
+                height,
+            } => write!(
+                f,
+                "output {}:{} is a coinbase output of block {} that is not mature yet",
+                txn_id, out, height
+            ),
+            Self::ScriptFailed { input, reason } => {
+                write!(f, "input {} does not unlock its output: {}", input, reason)
+            }
+            Self::OutputsExceedInputs {
+                input_total,
+                output_total,
+            } => write!(
+                f,
+                "outputs ({}) exceed inputs ({})",
+                output_total, input_total
+            ),
+            Self::ValueOutOfRange => write!(f, "sum of the input or output values is out of range"),
+            Self::Premature { lock_points } => write!(
+                f,
+                "transaction is locked until block {} and time {}",
+                lock_points.height, lock_points.time
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for BlockValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::UnknownParent => write!(f, "previous block is unknown"),
+            Self::InvalidParent => write!(f, "previous block is invalid"),
+            Self::InvalidIndex { expected, found } => {
+                write!(f, "expected index {}, found {}", expected, found)
+            }
+            Self::InvalidTimestamp { timestamp } => write!(f, "invalid timestamp {}", timestamp),
+            Self::InvalidHash => write!(f, "block hash does not match its contents"),
+            Self::InsufficientProofOfWork => write!(f, "block hash does not meet the difficulty"),
+            Self::BlockTooLarge { size } => write!(
+                f,
+                "block is {} bytes, more than the limit of {} bytes",
+                size, MAX_BLOCK_SIZE
+            ),
+            Self::NoTransactions => write!(f, "block has no transactions"),
+            Self::InvalidDifficulty { expected, found } => write!(
+                f,
+                "block difficulty is {:#x} but {:#x} is required",
+                found, expected
+            ),
+            Self::MissingCoinbase => write!(f, "block has no coinbase transaction"),
+            Self::MultipleCoinbase => write!(f, "block has more than one coinbase transaction"),
+            Self::DuplicateTransaction { txn_id } => {
+                write!(f, "transaction {} is in the block more than once", txn_id)
+            }
+            Self::DuplicateOutput { txn_id, out } => {
+                write!(f, "output {}:{} already exists", txn_id, out)
+            }
+            Self::InvalidCoinbaseReward { expected, found } => {
+                write!(f, "coinbase pays {} instead of {}", found, expected)
+            }
+            Self::ValueOutOfRange => {
+                write!(f, "sum of the coinbase value or the fees is out of range")
+            }
+            Self::DoubleSpend { txn_id, out } => {
+                write!(f, "output {}:{} is spent twice in the block", txn_id, out)
+            }
+            Self::InvalidTransaction { txn_id, reason } => {
+                write!(f, "transaction {} is invalid: {}", txn_id, reason)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ChainValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::EmptyChain => write!(f, "chain has no blocks"),
+            Self::InvalidLength { length, blocks } => {
+                write!(f, "chain length is {} but it has {} blocks", length, blocks)
+            }
+            Self::InvalidLastHash => write!(f, "last hash is not the hash of the last block"),
+            Self::InvalidBlock { index, reason } => {
+                write!(f, "block {} is invalid: {}", index, reason)
+            }
+        }
+    }
+}
+
+impl BlockChain {
+    /// Replays `blocks` from the genesis block, validating each one against
+    /// the state built by the blocks before it
+    ///
+    /// The blocks are checked against the consensus rules in `params`, i.e.
+    /// those of the node validating them, never the ones the sender claims.
+    /// On success the rebuilt chain is returned, on failure the index of the
+    /// first invalid block.
+    pub fn validate_chain(
+        blocks: &[Block],
+        params: ChainParams,
+    ) -> Result<BlockChain, ChainValidationError> {
+        if blocks.is_empty() {
+            return Err(ChainValidationError::EmptyChain);
+        }
+
+        let mut chain = BlockChain::with_params(params);
+
+        for (index, block) in blocks.iter().enumerate() {
+            chain
+                .validate_block(block)
+                .map_err(|reason| ChainValidationError::InvalidBlock { index, reason })?;
+            chain.add_block(block.clone());
+        }
+
+        Ok(chain)
+    }
+
+    /// Performs the checks of a header that do not depend on the state of the
+    /// chain, i.e. that its hash matches its fields and meets its difficulty
+    pub fn check_header(header: &BlockHeader) -> Result<(), BlockValidationError> {
+        if header.timestamp > Utc::now().timestamp() + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockValidationError::InvalidTimestamp {
+                timestamp: header.timestamp,
+            });
+        }
+
+        if header.hash != header.hash() {
+            return Err(BlockValidationError::InvalidHash);
+        }
+
+        if !header.meets_difficulty() {
+            return Err(BlockValidationError::InsufficientProofOfWork);
+        }
+
+        Ok(())
+    }
+
+    /// Performs the checks of a header against its parent, which has to be in
+    /// the header tree
+    pub(super) fn check_header_context(
+        &self,
+        header: &BlockHeader,
+    ) -> Result<(), BlockValidationError> {
+        let parent = match self.headers.get(&header.prev_hash) {
+            Some(entry) => entry,
+            None => return Err(BlockValidationError::UnknownParent),
+        };
+
+        if parent.invalid {
+            return Err(BlockValidationError::InvalidParent);
+        }
+
+        if header.index != parent.header.index + 1 {
+            return Err(BlockValidationError::InvalidIndex {
+                expected: parent.header.index + 1,
+                found: header.index,
+            });
+        }
+
+        if header.timestamp < parent.header.timestamp {
+            return Err(BlockValidationError::InvalidTimestamp {
+                timestamp: header.timestamp,
+            });
+        }
+
+        let expected_difficulty = self.next_difficulty(&header.prev_hash);
+        if header.difficulty() != expected_difficulty {
+            return Err(BlockValidationError::InvalidDifficulty {
+                expected: expected_difficulty,
+                found: header.difficulty(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Performs the checks that do not depend on the state of the chain
+    ///
+    /// The block hash commits to the Merkle root of the transactions, so
+    /// recomputing it also rebuilds and checks the Merkle root.
+    pub fn check_block(block: &Block) -> Result<(), BlockValidationError> {
+        if block.transactions.is_empty() {
+            return Err(BlockValidationError::NoTransactions);
+        }
+
+        if block.timestamp > Utc::now().timestamp() + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockValidationError::InvalidTimestamp {
+                timestamp: block.timestamp,
+            });
+        }
+
+        if block.hash != block.hash() {
+            return Err(BlockValidationError::InvalidHash);
+        }
+
+        if !block.meets_difficulty() {
+            return Err(BlockValidationError::InsufficientProofOfWork);
+        }
+
+        let size = block.encode().len();
+        if size > MAX_BLOCK_SIZE {
+            return Err(BlockValidationError::BlockTooLarge { size });
+        }
+
+        // The outputs of a transaction are stored under its id, so the id
+        // can't be chosen freely
+        let mut txn_ids = HashSet::<&Bytes>::new();
+        for txn in block.transactions.iter() {
+            if txn.id != txn.compute_id() {
+                return Err(BlockValidationError::InvalidTransaction {
+                    txn_id: hex::encode(&txn.id),
+                    reason: TransactionValidationError::InvalidId,
+                });
+            }
+
+            if !txn_ids.insert(&txn.id) {
+                return Err(BlockValidationError::DuplicateTransaction {
+                    txn_id: hex::encode(&txn.id),
+                });
+            }
+        }
+
+        let coinbase_txns = block
+            .transactions
+            .iter()
+            .filter(|txn| txn.is_coinbase())
+            .collect::<Vec<&Transaction>>();
+
+        match coinbase_txns.len() {
+            0 => Err(BlockValidationError::MissingCoinbase),
+            1 => Ok(()),
+            _ => Err(BlockValidationError::MultipleCoinbase),
+        }
+    }
+
+    /// Validates a block as the next block on top of the current tip
+    pub fn validate_block(&self, block: &Block) -> Result<(), BlockValidationError> {
+        match self.blocks.last() {
+            Some(last_block) => {
+                if block.prev_hash != self.last_hash {
+                    return Err(BlockValidationError::UnknownParent);
+                }
+
+                if block.timestamp < last_block.timestamp {
+                    return Err(BlockValidationError::InvalidTimestamp {
+                        timestamp: block.timestamp,
+                    });
+                }
+            }
+            None => {
+                if !block.prev_hash.is_empty() {
+                    return Err(BlockValidationError::UnknownParent);
+                }
+            }
+        }
+
+        if block.index != self.length {
+            return Err(BlockValidationError::InvalidIndex {
+                expected: self.length,
+                found: block.index,
+            });
+        }
+
+        let expected_difficulty = self.next_difficulty(&self.last_hash);
+        if block.difficulty() != expected_difficulty {
+            return Err(BlockValidationError::InvalidDifficulty {
+                expected: expected_difficulty,
+                found: block.difficulty(),
+            });
+        }
+
+        Self::check_block(block)?;
+
+        let mut spent_in_block = HashSet::<(Bytes, i32)>::new();
+        let mut earlier_txns = HashMap::<String, &Transaction>::new();
+        let mut fees: i32 = 0;
+        let mut reward: i32 = 0;
+
+        for txn in block.transactions.iter() {
+            // Unspent outputs can't be overwritten by a transaction with the
+            // same id
+            for out in 0..txn.outputs.len() {
+                let outpoint = OutPoint::new(&txn.id, out as i32);
+                if self.find_unspent_output(&outpoint).is_some() {
+                    return Err(BlockValidationError::DuplicateOutput {
+                        txn_id: hex::encode(&txn.id),
+                        out: outpoint.out,
+                    });
+                }
+            }
+
+            if txn.is_coinbase() {
+                // A negative output would let the others claim more than the
+                // reward while the sum still matches it
+                for (out, output) in txn.outputs.iter().enumerate() {
+                    if output.value <= 0 {
+                        return Err(BlockValidationError::InvalidTransaction {
+                            txn_id: hex::encode(&txn.id),
+                            reason: TransactionValidationError::InvalidOutputValue {
+                                out,
+                                value: output.value,
+                            },
+                        });
+                    }
+                }
+                reward = sum_values(txn.outputs.iter().map(|output| output.value))
+                    .map_err(|_| BlockValidationError::ValueOutOfRange)?;
+                earlier_txns.insert(hex::encode(&txn.id), txn);
+                continue;
+            }
+
+            for input in txn.inputs.iter() {
+                if !spent_in_block.insert((input.id.clone(), input.out)) {
+                    return Err(BlockValidationError::DoubleSpend {
+                        txn_id: hex::encode(&input.id),
+                        out: input.out,
+                    });
+                }
+            }
+
+            let fee = self
+                .validate_transaction(txn, &earlier_txns)
+                .map_err(|reason| BlockValidationError::InvalidTransaction {
+                    txn_id: hex::encode(&txn.id),
+                    reason,
+                })?;
+            fees = fees
+                .checked_add(fee)
+                .ok_or(BlockValidationError::ValueOutOfRange)?;
+
+            let lock_points = self.lock_points(txn, &earlier_txns, block.timestamp);
+            if !lock_points.is_reached(block.index, block.timestamp) {
+                return Err(BlockValidationError::InvalidTransaction {
+                    txn_id: hex::encode(&txn.id),
+                    reason: TransactionValidationError::Premature { lock_points },
+                });
+            }
+
+            earlier_txns.insert(hex::encode(&txn.id), txn);
+        }
+
+        // The coinbase claims the block subsidy and the fees of the block's
+        // transactions
+        let expected_reward = self
+            .params
+            .block_subsidy(block.index)
+            .checked_add(fees)
+            .ok_or(BlockValidationError::ValueOutOfRange)?;
+        if reward != expected_reward {
+            return Err(BlockValidationError::InvalidCoinbaseReward {
+                expected: expected_reward,
+                found: reward,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Validates a non-coinbase transaction against the outputs that are
+    /// currently unspent on the chain, and returns its fee, i.e. the value of
+    /// its inputs that is not spent by its outputs
+    ///
+    /// The transaction may also spend the outputs of `pending` transactions,
+    /// i.e. transactions that are not on the chain yet, keyed by their hex
+    /// encoded id. Checking that those outputs are not spent twice is left to
+    /// the caller.
+    ///
+    /// The transaction is checked as part of the next block, so coinbase
+    /// outputs have to be `coinbase_maturity` blocks below it.
+    pub fn validate_transaction(
+        &self,
+        txn: &Transaction,
+        pending: &HashMap<String, &Transaction>,
+    ) -> Result<i32, TransactionValidationError> {
+        if txn.inputs.is_empty() {
+            return Err(TransactionValidationError::NoInputs);
+        }
+
+        if txn.outputs.is_empty() {
+            return Err(TransactionValidationError::NoOutputs);
+        }
+
+        for (out, output) in txn.outputs.iter().enumerate() {
+            if output.value <= 0 {
+                return Err(TransactionValidationError::InvalidOutputValue {
+                    out,
+                    value: output.value,
+                });
+            }
+        }
+
+        let mut outpoints = HashSet::<OutPoint>::new();
+        for input in txn.inputs.iter() {
+            if !outpoints.insert(input.outpoint()) {
+                return Err(TransactionValidationError::DuplicateInput {
+                    txn_id: hex::encode(&input.id),
+                    out: input.out,
+                });
+            }
+        }
+
+        let mut input_total: i32 = 0;
+
+        for (input_idx, input) in txn.inputs.iter().enumerate() {
+            let outpoint = input.outpoint();
+
+            // Pending transactions end up in the next block at the earliest
+            let prev_output = match pending.get(&hex::encode(&input.id)) {
+                Some(prev_txn) if input.out >= 0 => prev_txn
+                    .outputs
+                    .get(input.out as usize)
+                    .map(|output| (output, self.length, prev_txn.is_coinbase())),
+                Some(_) => None,
+                None => self
+                    .find_unspent_output(&outpoint)
+                    .map(|utxo| (&utxo.output, utxo.height, utxo.is_coinbase)),
+            };
+
+            let prev_output = match prev_output {
+                Some((_, height, true)) if !self.params.is_coinbase_mature(height, self.length) => {
+                    return Err(TransactionValidationError::ImmatureCoinbase {
+                        txn_id: hex::encode(&input.id),
+                        out: input.out,
+                        height,
+                    })
+                }
+                Some((prev_output, _, _)) => prev_output,
+                // Only look through the chain to report the right reason
+                None if self.find_transaction(&input.id).is_ok() => {
+                    return Err(TransactionValidationError::AlreadySpent {
+                        txn_id: hex::encode(&input.id),
+                        out: input.out,
+                    })
+                }
+                None => {
+                    return Err(TransactionValidationError::MissingPreviousOutput {
+                        txn_id: hex::encode(&input.id),
+                        out: input.out,
+                    })
+                }
+            };
+
+            txn.verify_input(input_idx, &prev_output.script_pubkey)
+                .map_err(|reason| TransactionValidationError::ScriptFailed {
+                    input: input_idx,
+                    reason,
+                })?;
+
+            input_total = input_total
+                .checked_add(prev_output.value)
+                .ok_or(TransactionValidationError::ValueOutOfRange)?;
+        }
+
+        let output_total = sum_values(txn.outputs.iter().map(|output| output.value))
+            .map_err(|_| TransactionValidationError::ValueOutOfRange)?;
+        if output_total > input_total {
+            return Err(TransactionValidationError::OutputsExceedInputs {
+                input_total,
+                output_total,
+            });
+        }
+
+        Ok(input_total - output_total)
+    }
+
+    /// Returns the lowest block height and timestamp of a block that `txn`
+    /// can be in
+    ///
+    /// The outputs of `pending` transactions are treated as if they were
+    /// mined in the next block at the timestamp `time`. The inputs of `txn`
+    /// have to exist, see `validate_transaction`.
+    pub fn lock_points(
+        &self,
+        txn: &Transaction,
+        pending: &HashMap<String, &Transaction>,
+        time: i64,
+    ) -> LockPoints {
+        let mut lock_points = LockPoints::default();
+
+        if txn.lock_time < LOCKTIME_THRESHOLD {
+            lock_points.height = txn.lock_time.min(i32::MAX as i64) as i32;
+        } else {
+            lock_points.time = txn.lock_time;
+        }
+
+        if txn.is_coinbase() {
+            return lock_points;
+        }
+
+        for input in txn.inputs.iter() {
+            let relative_lock = match input.relative_lock() {
+                Some(relative_lock) => relative_lock,
+                None => continue,
+            };
+
+            let (height, created) = match pending.get(&hex::encode(&input.id)) {
+                Some(_) => (self.length, time),
+                None => match self.find_unspent_output(&input.outpoint()) {
+                    Some(utxo) => (utxo.height, utxo.time),
+                    None => continue,
+                },
+            };
+
+            match relative_lock {
+                RelativeLock::Blocks(blocks) => {
+                    lock_points.height = lock_points.height.max(height + blocks)
+                }
+                RelativeLock::Seconds(seconds) => {
+                    lock_points.time = lock_points.time.max(created + seconds)
+                }
+            }
+        }
+
+        lock_points
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::mempool::{Mempool, MempoolError};
+    use crate::blockchain::multisig::Multisig;
+    use crate::blockchain::sighash::SigHashType;
+    use crate::blockchain::transaction::TransactionOptions;
+    use crate::blockchain::wallet::Wallet;
+    use crate::util::constants::{HALVING_INTERVAL, INITIAL_SUBSIDY, TERMINAL_SUBSIDY, VERSION};
+
+    use secp256k1::{Secp256k1, SecretKey};
+
+    /// Returns a private key and its address, without saving a wallet file
+    fn key_pair() -> (SecretKey, Bytes) {
+        let mut rng = rand::rngs::OsRng::new().unwrap();
+        let (private_key, public_key) = Secp256k1::new().generate_keypair(&mut rng);
+        let public_key_hash =
+            Wallet::generate_sha256_ripemd160_hash(&public_key.serialize().to_vec());
+        (
+            private_key,
+            Wallet::encode_address(VERSION, &public_key_hash),
+        )
+    }
+
+    #[test]
+    fn transactions_that_spend_an_output_twice_are_rejected() {
+        let (private_key, from) = key_pair();
+        let (_, to) = key_pair();
+        let chain = BlockChain::new(&from);
+
+        let mut txn = Transaction::new_unsigned(
+            &from,
+            &to,
+            5,
+            1,
+            TransactionOptions::default(),
+            &chain,
+            &Mempool::default(),
+        )
+        .unwrap();
+        txn.inputs.push(txn.inputs[0].clone());
+        txn.update_id();
+        chain
+            .sign_transaction(&mut txn, private_key, SigHashType::All)
+            .unwrap();
+
+        match chain.validate_transaction(&txn, &HashMap::new()) {
+            Err(TransactionValidationError::DuplicateInput { .. }) => (),
+            result => panic!("expected a duplicate input, got {:?}", result),
+        }
+
+        let mut mempool = Mempool::default();
+        match mempool.add(txn, &chain) {
+            Err(MempoolError::Invalid(TransactionValidationError::DuplicateInput { .. })) => (),
+            result => panic!("expected a duplicate input, got {:?}", result),
+        }
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn transactions_with_forged_ids_are_rejected() {
+        let (private_key, from) = key_pair();
+        let (_, to) = key_pair();
+        let chain = BlockChain::new(&from);
+
+        let mut coinbase = Transaction::create_coinbase_txn(&to, chain.params.block_subsidy(1));
+        coinbase.id = chain.blocks[0].transactions[0].id.clone();
+        let block = Block::create(
+            vec![coinbase],
+            1,
+            chain.last_hash.clone(),
+            chain.next_difficulty(&chain.last_hash),
+        );
+        match BlockChain::check_block(&block) {
+            Err(BlockValidationError::InvalidTransaction {
+                reason: TransactionValidationError::InvalidId,
+                ..
+            }) => (),
+            result => panic!("expected an invalid id, got {:?}", result),
+        }
+
+        let mut txn = Transaction::new_unsigned(
+            &from,
+            &to,
+            5,
+            1,
+            TransactionOptions::default(),
+            &chain,
+            &Mempool::default(),
+        )
+        .unwrap();
+        chain
+            .sign_transaction(&mut txn, private_key, SigHashType::All)
+            .unwrap();
+        txn.id = vec![0; 32];
+        match Mempool::default().add(txn, &chain) {
+            Err(MempoolError::Invalid(TransactionValidationError::InvalidId)) => (),
+            result => panic!("expected an invalid id, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn transactions_are_locked_as_the_options_ask() {
+        let (private_key, from) = key_pair();
+        let (_, to) = key_pair();
+        let chain = BlockChain::new(&from);
+
+        let options = TransactionOptions {
+            lock_time: 5,
+            relative_lock: Some(RelativeLock::Blocks(2)),
+            ..TransactionOptions::default()
+        };
+        let mut txn =
+            Transaction::new_unsigned(&from, &to, 5, 1, options, &chain, &Mempool::default())
+                .unwrap();
+        chain
+            .sign_transaction(&mut txn, private_key, SigHashType::All)
+            .unwrap();
+
+        assert_eq!(txn.lock_time, 5);
+        for input in txn.inputs.iter() {
+            assert_eq!(input.relative_lock(), Some(RelativeLock::Blocks(2)));
+        }
+        assert_eq!(txn.id, txn.compute_id());
+        assert_eq!(chain.validate_transaction(&txn, &HashMap::new()), Ok(1));
+    }
+
+    #[test]
+    fn transactions_opt_in_to_replace_by_fee_with_the_options() {
+        let (_, from) = key_pair();
+        let (_, to) = key_pair();
+        let chain = BlockChain::new(&from);
+
+        let txn = Transaction::new_unsigned(
+            &from,
+            &to,
+            5,
+            1,
+            TransactionOptions::default(),
+            &chain,
+            &Mempool::default(),
+        )
+        .unwrap();
+        assert!(!txn.is_replaceable());
+
+        let options = TransactionOptions {
+            replaceable: true,
+            ..TransactionOptions::default()
+        };
+        let txn = Transaction::new_unsigned(&from, &to, 5, 1, options, &chain, &Mempool::default())
+            .unwrap();
+        assert!(txn.is_replaceable());
+        assert_eq!(txn.id, txn.compute_id());
+    }
+
+    #[test]
+    fn multisig_outputs_are_spent_with_enough_partial_signatures() {
+        let secp = Secp256k1::new();
+        let keys = (0..3).map(|_| key_pair().0).collect::<Vec<SecretKey>>();
+        let public_keys = keys
+            .iter()
+            .map(|key| {
+                secp256k1::PublicKey::from_secret_key(&secp, key)
+                    .serialize()
+                    .to_vec()
+            })
+            .collect();
+        let multisig = Multisig::new(2, public_keys).unwrap();
+        let (_, to) = key_pair();
+        let chain = BlockChain::new(&multisig.address());
+
+        let mut txn = Transaction::new_unsigned(
+            &multisig.address(),
+            &to,
+            5,
+            1,
+            TransactionOptions::default(),
+            &chain,
+            &Mempool::default(),
+        )
+        .unwrap();
+        let signatures = chain
+            .sign_partial(&txn, keys[2], &multisig, SigHashType::All)
+            .unwrap();
+        assert!(chain
+            .combine_signatures(&mut txn, &multisig, &signatures)
+            .is_err());
+
+        let mut signatures = signatures;
+        signatures.extend(
+            chain
+                .sign_partial(&txn, keys[0], &multisig, SigHashType::All)
+                .unwrap(),
+        );
+        chain
+            .combine_signatures(&mut txn, &multisig, &signatures)
+            .unwrap();
+        assert_eq!(chain.verify_transaction(&txn), Ok(()));
+    }
+
+    #[test]
+    fn mempool_updates_remove_mined_and_conflicting_transactions() {
+        let (private_key, from) = key_pair();
+        let (_, to) = key_pair();
+        let mut chain = BlockChain::new(&from);
+
+        let signed = |amount: i32, chain: &BlockChain| {
+            let mut txn = Transaction::new_unsigned(
+                &from,
+                &to,
+                amount,
+                1,
+                TransactionOptions::default(),
+                chain,
+                &Mempool::default(),
+            )
+            .unwrap();
+            chain
+                .sign_transaction(&mut txn, private_key, SigHashType::All)
+                .unwrap();
+            txn
+        };
+        let mine = |txns: Vec<Transaction>, chain: &mut BlockChain| {
+            let height = chain.length;
+            // Each transaction pays a fee of 1
+            let reward = chain.params.block_subsidy(height) + txns.len() as i32;
+            let mut block_txns = vec![Transaction::create_coinbase_txn(&to, reward)];
+            block_txns.extend(txns);
+            let block = Block::create(
+                block_txns,
+                height,
+                chain.last_hash.clone(),
+                chain.next_difficulty(&chain.last_hash),
+            );
+            chain.accept_block(block).unwrap()
+        };
+
+        // A transaction that was mined is removed, and can't be added again
+        let mut mempool = Mempool::default();
+        let txn = signed(5, &chain);
+        mempool.add(txn.clone(), &chain).unwrap();
+        let update = mine(vec![txn.clone()], &mut chain);
+        mempool.update(&chain, &update);
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.add(txn, &chain), Err(MempoolError::AlreadyKnown));
+
+        // A transaction that spends the same outputs as a mined one is evicted
+        let txn = signed(4, &chain);
+        let conflict = signed(3, &chain);
+        mempool.add(txn, &chain).unwrap();
+        let update = mine(vec![conflict], &mut chain);
+        mempool.update(&chain, &update);
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn outputs_spent_by_pending_transactions_are_not_selected() {
+        let (private_key, from) = key_pair();
+        let (_, to) = key_pair();
+        let chain = BlockChain::new(&from);
+        let mut mempool = Mempool::default();
+
+        let mut txn = Transaction::new_unsigned(
+            &from,
+            &to,
+            5,
+            1,
+            TransactionOptions::default(),
+            &chain,
+            &mempool,
+        )
+        .unwrap();
+        chain
+            .sign_transaction(&mut txn, private_key, SigHashType::All)
+            .unwrap();
+        mempool.add(txn, &chain).unwrap();
+
+        match Transaction::new_unsigned(
+            &from,
+            &to,
+            5,
+            1,
+            TransactionOptions::default(),
+            &chain,
+            &mempool,
+        ) {
+            Err(err) => assert!(err.starts_with("Not enough funds"), "{}", err),
+            Ok(_) => panic!("expected the pending output to be skipped"),
+        }
+    }
+
+    #[test]
+    fn blocks_cannot_overwrite_unspent_outputs() {
+        let (_, address) = key_pair();
+        let chain = BlockChain::new(&address);
+
+        // The genesis coinbase again, with the same id and unspent outputs
+        let block = Block::create(
+            chain.blocks[0].transactions.clone(),
+            1,
+            chain.last_hash.clone(),
+            chain.next_difficulty(&chain.last_hash),
+        );
+        match chain.validate_block(&block) {
+            Err(BlockValidationError::DuplicateOutput { out: 0, .. }) => (),
+            result => panic!("expected a duplicate output, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn chains_are_validated_with_the_rules_of_the_receiver() {
+        let (_, address) = key_pair();
+        let params = ChainParams::default()
+            .with_subsidy(INITIAL_SUBSIDY * 2, HALVING_INTERVAL, TERMINAL_SUBSIDY)
+            .unwrap();
+
+        let mut sender = BlockChain::with_params(params.clone());
+        let coinbase = Transaction::create_coinbase_txn(&address, params.block_subsidy(0));
+        sender.add_block(Block::create_genesis_block(
+            coinbase,
+            params.initial_difficulty(),
+        ));
+
+        assert!(BlockChain::validate_chain(&sender.blocks, params).is_ok());
+        match BlockChain::validate_chain(&sender.blocks, ChainParams::default()) {
+            Err(ChainValidationError::InvalidBlock { index: 0, .. }) => (),
+            result => panic!("expected an invalid genesis block, got {:?}", result.err()),
+        }
+    }
+
+    #[test]
+    fn values_that_add_up_past_the_range_are_rejected() {
+        let (private_key, from) = key_pair();
+        let (_, to) = key_pair();
+        let chain = BlockChain::new(&from);
+
+        let mut txn = Transaction::new_unsigned(
+            &from,
+            &to,
+            5,
+            1,
+            TransactionOptions::default(),
+            &chain,
+            &Mempool::default(),
+        )
+        .unwrap();
+        txn.outputs[0].value = i32::max_value();
+        txn.outputs[1].value = i32::max_value();
+        txn.update_id();
+        chain
+            .sign_transaction(&mut txn, private_key, SigHashType::All)
+            .unwrap();
+        assert_eq!(
+            chain.validate_transaction(&txn, &HashMap::new()),
+            Err(TransactionValidationError::ValueOutOfRange)
+        );
+
+        let mut coinbase = Transaction::create_coinbase_txn(&to, i32::max_value());
+        coinbase.outputs.push(coinbase.outputs[0].clone());
+        coinbase.update_id();
+        let block = Block::create(
+            vec![coinbase],
+            1,
+            chain.last_hash.clone(),
+            chain.next_difficulty(&chain.last_hash),
+        );
+        assert_eq!(
+            chain.validate_block(&block),
+            Err(BlockValidationError::ValueOutOfRange)
+        );
+    }
+
+    #[test]
+    fn coinbases_with_negative_outputs_are_rejected() {
+        let (_, address) = key_pair();
+        let chain = BlockChain::new(&address);
+
+        // Adds up to the reward, but mints 5 spendable coins
+        let subsidy = chain.params.block_subsidy(1);
+        let mut coinbase = Transaction::create_coinbase_txn(&address, subsidy + 5);
+        coinbase.outputs.push(coinbase.outputs[0].clone());
+        coinbase.outputs[1].value = -5;
+        coinbase.update_id();
+        let block = Block::create(
+            vec![coinbase],
+            1,
+            chain.last_hash.clone(),
+            chain.next_difficulty(&chain.last_hash),
+        );
+        match chain.validate_block(&block) {
+            Err(BlockValidationError::InvalidTransaction {
+                reason: TransactionValidationError::InvalidOutputValue { out: 1, value: -5 },
+                ..
+            }) => (),
+            result => panic!("expected an invalid output value, got {:?}", result),
+        }
+    }
+}
@@ -106,6 +106,24 @@ impl Wallet {
         hashed_result.to_vec()
     }
 
+    /// Returns the address of a hash, i.e. the Base58 encoding of the
+    /// version, the hash and its checksum
+    pub fn encode_address(version: u8, hash: &[u8]) -> Bytes {
+        let mut full_hash: Bytes = vec![version];
+        full_hash.extend(hash);
+        let checksum = Self::generate_checksum(&full_hash);
+        full_hash.extend(&checksum);
+
+        bs58::encode(&full_hash).into_vec()
+    }
+
+    /// Returns the version and the hash of an address
+    pub fn decode_address(address: &Bytes) -> (u8, Bytes) {
+        let full_hash = bs58::decode(address).into_vec().unwrap();
+        let hash = &full_hash[1..(full_hash.len() - CHECKSUM_LENGTH)];
+        (full_hash[0], hash.to_vec())
+    }
+
     pub fn is_address_valid(address: &Bytes) -> bool {
         let hash = bs58::decode(address).into_vec().unwrap();
 
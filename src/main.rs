@@ -7,8 +7,12 @@ use log::*;
 use simplelog::*;
 use std::env;
 use std::fs::File;
+use std::path::Path;
 use std::{thread, time};
 
+use blockchain::multisig::Multisig;
+use blockchain::transaction::TransactionOptions;
+use blockchain::txn::RelativeLock;
 use blockchain::wallet::Wallet;
 use network::node::*;
 use network::server::{Server, ServerCommand, ServerMessage};
@@ -28,29 +32,53 @@ macro_rules! broadcast {
     };
 }
 
-#[actix_rt::main]
-async fn main() {
-    // Initialize the logger
+fn init_logger(log_file: &str) {
     CombinedLogger::init(vec![
         TermLogger::new(LevelFilter::Info, Config::default(), TerminalMode::Mixed),
         WriteLogger::new(
             LevelFilter::Info,
             Config::default(),
-            File::create("log/application.log").unwrap(),
+            File::create(log_file).unwrap(),
         ),
     ])
     .unwrap();
+}
 
+#[actix_rt::main]
+async fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 3 {
-        println!("\nUsage: {} number-of-nodes number-of-wallets", args[0]);
+    if args.len() >= 4 && args[1] == "node" {
+        let port: u16 = args[3].parse::<u16>().expect("Couldn't parse port");
+
+        init_logger(&format!("log/{}.log", args[2]));
+        run_node(args[2].clone(), port, args[4..].to_vec()).await;
+        return;
+    }
+
+    if args.len() != 3 && args.len() != 4 {
+        println!(
+            "\nUsage: {0} number-of-nodes number-of-wallets [data-dir]\n       {0} node name port [seed-address...]",
+            args[0]
+        );
         std::process::exit(0);
     }
 
+    // Initialize the logger
+    init_logger("log/application.log");
+
     let n_nodes: u32 = args[1].parse::<u32>().expect("Couldn't parse n_nodes");
     let n_wallets: u32 = args[2].parse::<u32>().expect("Couldn't parse n_wallets");
 
+    // Without a data directory the blocks are only kept in memory
+    let data_dir = args.get(3).map(Path::new);
+
+    let mining_threads = env::var("MINING_THREADS").ok().map(|threads| {
+        threads
+            .parse::<usize>()
+            .expect("Couldn't parse MINING_THREADS")
+    });
+
     println!("Running the simulation with:");
     println!("Nodes: {}", n_nodes);
     println!("Wallets: {}\n", n_wallets);
@@ -68,7 +96,20 @@ async fn main() {
 
     for i in 0..n_nodes {
         let node_name = format!("Node-{}", i);
-        let addr = Node::default(node_name.clone(), server_addr.clone()).start();
+        let mut node = match data_dir {
+            Some(dir) => Node::with_data_dir(
+                node_name.clone(),
+                server_addr.clone(),
+                &dir.join(&node_name),
+            )
+            .expect("Couldn't open the node's data directory"),
+            None => Node::default(node_name.clone(), server_addr.clone()),
+        };
+
+        if let Some(threads) = mining_threads {
+            node.set_mining_threads(threads);
+        }
+        let addr = node.start();
         nodes.push(addr);
 
         let payload = format!(
@@ -126,15 +167,76 @@ async fn main() {
     handle_result(result, "CreateBlockchain");
 
     // Simulation Seed Money
+    // Each wallet passes on what it received, so the transactions pay no fee
     for i in 0..(n_wallets - 1) {
         let result = nodes[0].try_send(GenericMessage(Payload::AddTransactionAndMine {
             from: wallets[i as usize].address.clone(),
             to: wallets[(i + 1) as usize].address.clone(),
             amt: 10,
+            fee: 0,
+            options: TransactionOptions::default(),
+        }));
+        handle_result(result, "AddTransactionAndMine");
+    }
+
+    // The last wallet passes its coins through a multisig of the first two
+    // wallets, which both sign to send them back
+    if n_wallets >= 2 {
+        let last_wallet = &wallets[(n_wallets - 1) as usize];
+        let public_keys = wallets[..2]
+            .iter()
+            .map(|wallet| wallet.public_key.serialize().to_vec())
+            .collect();
+        let multisig = Multisig::new(2, public_keys).expect("Couldn't create the multisig");
+
+        let result = nodes[0].try_send(GenericMessage(Payload::AddTransactionAndMine {
+            from: last_wallet.address.clone(),
+            to: multisig.address(),
+            amt: 10,
+            fee: 0,
+            options: TransactionOptions {
+                replaceable: true,
+                ..TransactionOptions::default()
+            },
         }));
         handle_result(result, "AddTransactionAndMine");
+
+        let result = nodes[0].try_send(GenericMessage(Payload::AddMultisigTransactionAndMine {
+            signers: wallets[..2].iter().map(|w| w.address.clone()).collect(),
+            multisig,
+            to: last_wallet.address.clone(),
+            amt: 10,
+            fee: 0,
+            options: TransactionOptions {
+                relative_lock: Some(RelativeLock::Blocks(1)),
+                ..TransactionOptions::default()
+            },
+        }));
+        handle_result(result, "AddMultisigTransactionAndMine");
     }
 
+    // Wait until the seed transactions are mined
+    loop {
+        let result = nodes[0]
+            .send(GenericMessage(Payload::GetMiningStatus))
+            .await;
+
+        match result {
+            Ok(Ok(GenericResponse::MiningStatus {
+                mining: false,
+                queued: 0,
+            })) => break,
+            Ok(Ok(_)) => actix_rt::time::delay_for(time::Duration::from_millis(100)).await,
+            _ => {
+                warn!("Couldn't get the mining status of Node-0");
+                break;
+            }
+        }
+    }
+
+    // Give Node-1 time to request the last block after it's announced
+    actix_rt::time::delay_for(time::Duration::from_millis(500)).await;
+
     // Get Wallet Balances
     println!("\nWallet Balances");
     println!("===============");
@@ -150,3 +252,66 @@ async fn main() {
 
     System::current().stop();
 }
+
+/// Runs a single node that talks to the nodes in other processes over TCP
+///
+/// The node listens on `port` and connects to the seed nodes in `peers`,
+/// which tell it about the other nodes. A node without seeds creates a new
+/// blockchain and keeps mining blocks on it, the others download the
+/// blockchain from their peers and follow it.
+async fn run_node(name: String, port: u16, peers: Vec<String>) {
+    let node = Node::default(name.clone(), Server::offline().start()).start();
+
+    match node.send(GenericMessage(Payload::Listen { port })).await {
+        Ok(Ok(_)) => (),
+        Ok(Err(err)) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            error!("Couldn't start {}: {}", name, err);
+            std::process::exit(1);
+        }
+    }
+
+    for address in peers.iter() {
+        let result = node
+            .send(GenericMessage(Payload::Connect {
+                address: address.clone(),
+            }))
+            .await;
+
+        match result {
+            Ok(Ok(_)) => (),
+            Ok(Err(err)) => error!("{}", err),
+            Err(err) => error!("Couldn't connect {} to {}: {}", name, address, err),
+        }
+    }
+
+    let one_second = time::Duration::from_secs(1);
+
+    if !peers.is_empty() {
+        loop {
+            actix_rt::time::delay_for(one_second).await;
+        }
+    }
+
+    let wallet = Wallet::new();
+    let result = node.try_send(GenericMessage(Payload::CreateBlockchain {
+        address: wallet.address.clone(),
+    }));
+    handle_result(result, "CreateBlockchain");
+
+    loop {
+        let result = node.send(GenericMessage(Payload::GetMiningStatus)).await;
+
+        if let Ok(Ok(GenericResponse::MiningStatus { mining: false, .. })) = result {
+            let result = node.try_send(GenericMessage(Payload::MineBlock {
+                address: wallet.address.clone(),
+            }));
+            handle_result(result, "MineBlock");
+        }
+
+        actix_rt::time::delay_for(one_second).await;
+    }
+}
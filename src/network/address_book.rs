@@ -0,0 +1,200 @@
+//# The addresses of the nodes a node knows about
+
+use crate::util::constants::{ADDRESS_EXPIRY, MAX_ADDRESSES_PER_SOURCE, MAX_KNOWN_ADDRESSES};
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Debug)]
+struct AddressEntry {
+    last_seen: i64,              // timestamp
+    source: Option<SourceGroup>, // none if the node saw the address itself
+    connected: bool,
+}
+
+/// The network a peer that sent addresses is in, i.e. the first 16 bits of
+/// an IPv4 address or the first 32 bits of an IPv6 address
+///
+/// A single operator usually controls whole networks rather than single IP
+/// addresses, so limits apply to groups.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SourceGroup([u8; 4]);
+
+impl From<IpAddr> for SourceGroup {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => {
+                let octets = ip.octets();
+                SourceGroup([4, 0, octets[0], octets[1]])
+            }
+            IpAddr::V6(ip) => {
+                let octets = ip.octets();
+                SourceGroup([octets[0], octets[1], octets[2], octets[3]])
+            }
+        }
+    }
+}
+
+/// The listening addresses of other nodes, and when they were last seen
+///
+/// Nodes learn addresses from the peers they connect to and from the address
+/// lists their peers send them, and connect to the most recently seen ones.
+/// The book keeps at most `MAX_KNOWN_ADDRESSES` addresses, and forgets
+/// addresses that weren't seen for `ADDRESS_EXPIRY` seconds. Addresses sent by
+/// peers of the same `SourceGroup` take up at most `MAX_ADDRESSES_PER_SOURCE`
+/// entries, so a single network can't fill the book with its own nodes.
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    entries: HashMap<SocketAddr, AddressEntry>,
+    excluded: HashSet<SocketAddr>, // e.g. the node's own address
+}
+
+impl AddressBook {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Adds an address that was sent by a peer in the `source` group, or
+    /// seen by the node itself, or updates when it was last seen
+    ///
+    /// If the source's share of the book, or the book, is full, the address
+    /// of the source, or of any source, that was seen the longest time ago
+    /// and isn't connected makes room.
+    pub fn add(&mut self, address: SocketAddr, last_seen: i64, source: Option<SourceGroup>) {
+        if self.excluded.contains(&address) {
+            return;
+        }
+
+        if let Some(entry) = self.entries.get_mut(&address) {
+            entry.last_seen = entry.last_seen.max(last_seen);
+            return;
+        }
+
+        let from_source = |entry: &AddressEntry| source.is_some() && entry.source == source;
+        let source_full = source.is_some()
+            && self.entries.values().filter(|e| from_source(e)).count() >= MAX_ADDRESSES_PER_SOURCE;
+
+        if source_full || self.entries.len() >= MAX_KNOWN_ADDRESSES {
+            let oldest = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| !entry.connected && (!source_full || from_source(entry)))
+                .min_by_key(|(_, entry)| entry.last_seen)
+                .map(|(address, _)| *address);
+
+            match oldest {
+                Some(oldest) if self.entries[&oldest].last_seen < last_seen => {
+                    self.entries.remove(&oldest);
+                }
+                _ => return,
+            }
+        }
+
+        self.entries.insert(
+            address,
+            AddressEntry {
+                last_seen,
+                source,
+                connected: false,
+            },
+        );
+    }
+
+    /// Records that the node connected to or disconnected from an address,
+    /// which counts as seeing it at `now`
+    pub fn set_connected(&mut self, address: SocketAddr, connected: bool, now: i64) {
+        self.add(address, now, None);
+
+        if let Some(entry) = self.entries.get_mut(&address) {
+            entry.connected = connected;
+            entry.last_seen = now;
+            entry.source = None;
+        }
+    }
+
+    pub fn remove(&mut self, address: &SocketAddr) {
+        self.entries.remove(address);
+    }
+
+    /// Removes an address and ignores it from now on
+    pub fn exclude(&mut self, address: SocketAddr) {
+        self.entries.remove(&address);
+        self.excluded.insert(address);
+    }
+
+    /// Returns up to `n` addresses that aren't connected or in `skip`, most
+    /// recently seen first
+    pub fn candidates(&self, n: usize, skip: &HashSet<SocketAddr>) -> Vec<SocketAddr> {
+        let mut candidates = self
+            .entries
+            .iter()
+            .filter(|(address, entry)| !entry.connected && !skip.contains(*address))
+            .map(|(address, entry)| (*address, entry.last_seen))
+            .collect::<Vec<(SocketAddr, i64)>>();
+
+        candidates.sort_by_key(|(_, last_seen)| -last_seen);
+        candidates
+            .into_iter()
+            .take(n)
+            .map(|(address, _)| address)
+            .collect()
+    }
+
+    /// Returns up to `n` addresses with when they were last seen, most
+    /// recently seen first. Connected addresses are seen at `now`.
+    pub fn recent(&self, n: usize, now: i64) -> Vec<(SocketAddr, i64)> {
+        let mut addresses = self
+            .entries
+            .iter()
+            .map(|(address, entry)| {
+                let last_seen = if entry.connected {
+                    now
+                } else {
+                    entry.last_seen
+                };
+                (*address, last_seen)
+            })
+            .collect::<Vec<(SocketAddr, i64)>>();
+
+        addresses.sort_by_key(|(_, last_seen)| -last_seen);
+        addresses.truncate(n);
+        addresses
+    }
+
+    /// Forgets the addresses that aren't connected and weren't seen for
+    /// `ADDRESS_EXPIRY` seconds
+    pub fn expire(&mut self, now: i64) {
+        self.entries
+            .retain(|_, entry| entry.connected || now - entry.last_seen < ADDRESS_EXPIRY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(a: u8, b: u8, c: u8) -> SocketAddr {
+        SocketAddr::from(([a, b, c, 1], 8333))
+    }
+
+    #[test]
+    fn one_source_fills_only_its_share_of_the_book() {
+        let mut book = AddressBook::default();
+        let source = Some(SourceGroup::from(address(10, 0, 0).ip()));
+
+        for i in 0..(MAX_ADDRESSES_PER_SOURCE * 2) {
+            book.add(address(1, (i / 256) as u8, i as u8), i as i64, source);
+        }
+        assert_eq!(book.len(), MAX_ADDRESSES_PER_SOURCE);
+
+        // The newest addresses of the source replaced its oldest
+        let recent = book.recent(1, 0);
+        assert_eq!(recent[0].1, (MAX_ADDRESSES_PER_SOURCE * 2 - 1) as i64);
+
+        // Another network, and the node itself, still have room
+        let other = Some(SourceGroup::from(address(10, 1, 0).ip()));
+        book.add(address(2, 0, 0), 0, other);
+        book.add(address(3, 0, 0), 0, None);
+        assert_eq!(book.len(), MAX_ADDRESSES_PER_SOURCE + 2);
+    }
+}
@@ -0,0 +1,97 @@
+//# Downloading the blocks of the best known header chain
+
+use crate::network::node::GenericMessage;
+use crate::util::constants::{BLOCK_DOWNLOAD_TIMEOUT, MAX_BLOCKS_IN_FLIGHT_PER_PEER};
+use crate::util::types::Bytes;
+
+use actix::prelude::*;
+use std::collections::HashMap;
+
+struct InFlight {
+    peer: Recipient<GenericMessage>,
+    requested_at: i64,
+}
+
+/// The peers a node downloads blocks from, and the blocks it's waiting for
+///
+/// Blocks are spread over the peers that sent headers, with at most
+/// `MAX_BLOCKS_IN_FLIGHT_PER_PEER` requested from each peer at a time. A
+/// block that doesn't arrive within `BLOCK_DOWNLOAD_TIMEOUT` seconds, or
+/// whose peer disconnects, is requested again, possibly from another peer.
+#[derive(Default)]
+pub struct BlockSync {
+    peers: Vec<Recipient<GenericMessage>>,
+    in_flight: HashMap<String, InFlight>, // keyed by the hex encoded block hash
+    next_peer: usize,
+}
+
+impl BlockSync {
+    pub fn add_peer(&mut self, peer: Recipient<GenericMessage>) {
+        if !self.peers.contains(&peer) {
+            self.peers.push(peer);
+        }
+    }
+
+    /// Stops downloading from a peer, so its blocks are requested elsewhere
+    pub fn remove_peer(&mut self, peer: &Recipient<GenericMessage>) {
+        self.peers.retain(|p| p != peer);
+        self.in_flight.retain(|_, request| &request.peer != peer);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Records that a block arrived, and returns whether it was requested
+    pub fn received(&mut self, hash: &Bytes) -> bool {
+        self.in_flight.remove(&hex::encode(hash)).is_some()
+    }
+
+    /// Assigns the `missing` blocks that aren't in flight to peers with room
+    /// for more requests, and returns the blocks to request from each peer
+    pub fn schedule(
+        &mut self,
+        missing: Vec<Bytes>,
+        now: i64,
+    ) -> Vec<(Recipient<GenericMessage>, Vec<Bytes>)> {
+        self.in_flight
+            .retain(|_, request| now - request.requested_at < BLOCK_DOWNLOAD_TIMEOUT);
+
+        let mut requests: Vec<(Recipient<GenericMessage>, Vec<Bytes>)> = Vec::new();
+        let missing = missing
+            .into_iter()
+            .filter(|hash| !self.in_flight.contains_key(&hex::encode(hash)))
+            .collect::<Vec<Bytes>>();
+        let mut missing = missing.into_iter().peekable();
+
+        // Take turns between the peers, so each one serves part of the chain
+        let mut idle_turns = 0;
+        while missing.peek().is_some() && idle_turns < self.peers.len() {
+            self.next_peer = (self.next_peer + 1) % self.peers.len();
+            let peer = &self.peers[self.next_peer];
+
+            let load = self.in_flight.values().filter(|r| &r.peer == peer).count();
+            if load >= MAX_BLOCKS_IN_FLIGHT_PER_PEER {
+                idle_turns += 1;
+                continue;
+            }
+            idle_turns = 0;
+
+            let hash = missing.next().unwrap();
+            self.in_flight.insert(
+                hex::encode(&hash),
+                InFlight {
+                    peer: peer.clone(),
+                    requested_at: now,
+                },
+            );
+
+            match requests.iter_mut().find(|(p, _)| p == peer) {
+                Some((_, hashes)) => hashes.push(hash),
+                None => requests.push((peer.clone(), vec![hash])),
+            }
+        }
+
+        requests
+    }
+}
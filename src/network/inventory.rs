@@ -0,0 +1,117 @@
+//# Announcing blocks and transactions by their hashes
+
+use crate::util::constants::{INVENTORY_REQUEST_TIMEOUT, MAX_SEEN_INVENTORY};
+use crate::util::encoding::{DecodeError, Decoder, Encoder};
+use crate::util::traits::{Decodable, Hashable};
+use crate::util::types::Bytes;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// A block or transaction, identified by its hash
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum InventoryItem {
+    Transaction(Bytes),
+    Block(Bytes),
+}
+
+impl std::fmt::Display for InventoryItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InventoryItem::Transaction(id) => write!(f, "transaction {}", hex::encode(id)),
+            InventoryItem::Block(hash) => write!(f, "block {}", hex::encode(hash)),
+        }
+    }
+}
+
+const TRANSACTION: u8 = 0x01;
+const BLOCK: u8 = 0x02;
+
+impl Hashable for InventoryItem {
+    fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+
+        match self {
+            InventoryItem::Transaction(id) => {
+                encoder.put_u8(TRANSACTION);
+                encoder.put_bytes(id);
+            }
+            InventoryItem::Block(hash) => {
+                encoder.put_u8(BLOCK);
+                encoder.put_bytes(hash);
+            }
+        }
+
+        encoder.finish()
+    }
+}
+
+impl Decodable for InventoryItem {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        match decoder.get_u8()? {
+            TRANSACTION => Ok(InventoryItem::Transaction(decoder.get_bytes()?)),
+            BLOCK => Ok(InventoryItem::Block(decoder.get_bytes()?)),
+            kind => Err(DecodeError::UnknownInventoryType { kind }),
+        }
+    }
+}
+
+/// The items a node has recently seen or requested
+///
+/// Nodes announce every item they accept to their neighbors, so most items
+/// are announced to a node several times. It only requests an item it hasn't
+/// seen, and only from one neighbor at a time. If the item doesn't arrive
+/// within `INVENTORY_REQUEST_TIMEOUT` seconds, the next announcement requests
+/// it again. The last `MAX_SEEN_INVENTORY` items are remembered.
+#[derive(Debug, Default)]
+pub struct SeenInventory {
+    seen: HashSet<InventoryItem>,
+    order: VecDeque<InventoryItem>, // oldest first
+    requested: HashMap<InventoryItem, i64>,
+}
+
+impl SeenInventory {
+    /// Records that an item was received, and returns whether it's new
+    pub fn mark_seen(&mut self, item: InventoryItem) -> bool {
+        self.requested.remove(&item);
+
+        if !self.seen.insert(item.clone()) {
+            return false;
+        }
+
+        self.order.push_back(item);
+        if self.order.len() > MAX_SEEN_INVENTORY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        true
+    }
+
+    /// Records that a received item was rejected, so that the next
+    /// announcement requests it again
+    ///
+    /// Neither the hash of a block nor the id of a transaction covers the
+    /// signatures, so a valid copy of an item can follow an invalid one.
+    pub fn mark_rejected(&mut self, item: &InventoryItem) {
+        self.requested.remove(item);
+    }
+
+    /// Returns whether an announced item should be requested, and if so
+    /// records the request
+    pub fn should_request(&mut self, item: &InventoryItem, now: i64) -> bool {
+        if self.seen.contains(item) {
+            return false;
+        }
+
+        self.requested
+            .retain(|_, requested_at| now - *requested_at < INVENTORY_REQUEST_TIMEOUT);
+
+        if self.requested.contains_key(item) {
+            return false;
+        }
+
+        self.requested.insert(item.clone(), now);
+        true
+    }
+}
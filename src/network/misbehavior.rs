@@ -0,0 +1,362 @@
+//# Scoring peers that break the rules, and banning them
+
+use crate::blockchain::mempool::MempoolError;
+use crate::blockchain::validation::{
+    BlockValidationError, ChainValidationError, TransactionValidationError,
+};
+use crate::network::node::GenericMessage;
+use crate::util::constants::{
+    ADDRESS_RATE_LIMIT, BAN_DURATION, BAN_SCORE, MAX_ADDRESSES_PER_MESSAGE,
+    REQUEST_BLOCKCHAIN_INTERVAL,
+};
+
+use actix::prelude::*;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// Something a peer did that an honest node doesn't do
+#[derive(Clone, Debug, PartialEq)]
+pub enum Misbehavior {
+    InvalidBlock(BlockValidationError),
+    InvalidHeader(BlockValidationError),
+    UnconnectedHeaders,
+    InvalidChain(ChainValidationError),
+    InvalidTransaction(MempoolError),
+    OversizedMessage { items: usize, max: usize },
+    MalformedMessage(String),
+    UnexpectedMessage,
+    TooManyRequests,
+    TooManyChains,
+}
+
+impl Misbehavior {
+    /// Returns the misbehavior of a peer that sent a block rejected with
+    /// `err`, if sending it is misbehavior
+    ///
+    /// Orphans are expected, and a block from the future may be valid on a
+    /// peer whose clock is ahead. A block whose parent is invalid may have
+    /// been sent by a peer that hasn't validated the parent yet.
+    pub fn from_block_error(err: BlockValidationError) -> Option<Self> {
+        match err {
+            BlockValidationError::UnknownParent
+            | BlockValidationError::InvalidParent
+            | BlockValidationError::InvalidTimestamp { .. } => None,
+            err => Some(Self::InvalidBlock(err)),
+        }
+    }
+
+    /// Returns the misbehavior of a peer that sent a header rejected with
+    /// `err`, if sending it is misbehavior
+    pub fn from_header_error(err: BlockValidationError) -> Option<Self> {
+        match err {
+            BlockValidationError::UnknownParent => Some(Self::UnconnectedHeaders),
+            BlockValidationError::InvalidParent | BlockValidationError::InvalidTimestamp { .. } => {
+                None
+            }
+            err => Some(Self::InvalidHeader(err)),
+        }
+    }
+
+    /// Returns the misbehavior of a peer that sent a transaction rejected with
+    /// `err`, if sending it is misbehavior
+    ///
+    /// Only transactions that are invalid on every chain count. Peers relay
+    /// transactions whose inputs this node hasn't seen yet, or that were
+    /// spent or locked on its chain, without doing anything wrong.
+    pub fn from_transaction_error(err: MempoolError) -> Option<Self> {
+        match &err {
+            MempoolError::UnexpectedCoinbase => Some(Self::InvalidTransaction(err)),
+            MempoolError::Invalid(reason) if is_invalid_on_every_chain(reason) => {
+                Some(Self::InvalidTransaction(err))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns how much the misbehavior adds to the peer's score. Peers are
+    /// banned at `BAN_SCORE`.
+    ///
+    /// Breaking the wire protocol, or consensus rules that a block can be
+    /// checked against on its own, is never an accident, so those get the
+    /// peer banned right away. Rules that depend on the chain a block builds
+    /// on, and the rest, can be broken by a peer with a different view of the
+    /// chain, or a buggy one, and only add up if repeated.
+    pub fn score(&self) -> u32 {
+        match self {
+            Self::InvalidBlock(reason) | Self::InvalidHeader(reason) => block_error_score(reason),
+            Self::InvalidChain(ChainValidationError::InvalidBlock { reason, .. }) => {
+                chain_block_error_score(reason)
+            }
+            Self::InvalidChain(_) | Self::MalformedMessage(_) => BAN_SCORE,
+            Self::UnconnectedHeaders | Self::OversizedMessage { .. } => 20,
+            Self::InvalidTransaction(_)
+            | Self::UnexpectedMessage
+            | Self::TooManyRequests
+            | Self::TooManyChains => 10,
+        }
+    }
+}
+
+/// Returns whether a transaction rejected with `reason` is invalid on every
+/// chain, rather than only on the chain it was checked against
+fn is_invalid_on_every_chain(reason: &TransactionValidationError) -> bool {
+    match reason {
+        TransactionValidationError::InvalidId
+        | TransactionValidationError::NoInputs
+        | TransactionValidationError::NoOutputs
+        | TransactionValidationError::DuplicateInput { .. }
+        | TransactionValidationError::InvalidOutputValue { .. }
+        | TransactionValidationError::ScriptFailed { .. }
+        | TransactionValidationError::OutputsExceedInputs { .. }
+        | TransactionValidationError::ValueOutOfRange => true,
+        TransactionValidationError::MissingPreviousOutput { .. }
+        | TransactionValidationError::AlreadySpent { .. }
+        | TransactionValidationError::ImmatureCoinbase { .. }
+        | TransactionValidationError::Premature { .. } => false,
+    }
+}
+
+/// Returns the score of a block or header rejected with `reason`
+fn block_error_score(reason: &BlockValidationError) -> u32 {
+    match reason {
+        // Sent before the peer knew better, see `from_block_error`
+        BlockValidationError::UnknownParent
+        | BlockValidationError::InvalidParent
+        | BlockValidationError::InvalidTimestamp { .. } => 0,
+        // Checked against the parent and the outputs of the chain
+        BlockValidationError::InvalidIndex { .. }
+        | BlockValidationError::InvalidDifficulty { .. }
+        | BlockValidationError::DuplicateOutput { .. }
+        | BlockValidationError::InvalidCoinbaseReward { .. }
+        | BlockValidationError::DoubleSpend { .. } => 50,
+        BlockValidationError::InvalidTransaction { reason, .. }
+            if !is_invalid_on_every_chain(reason) =>
+        {
+            50
+        }
+        _ => BAN_SCORE,
+    }
+}
+
+/// Returns the score of a chain with a block rejected with `reason`
+///
+/// The blocks of a chain arrive together, so unlike a block sent on its own,
+/// one that doesn't connect to the block before it, or builds on an invalid
+/// one, is never an accident. A timestamp may still be from the future of this
+/// node's clock.
+fn chain_block_error_score(reason: &BlockValidationError) -> u32 {
+    match reason {
+        BlockValidationError::UnknownParent | BlockValidationError::InvalidParent => BAN_SCORE,
+        BlockValidationError::InvalidTimestamp { .. } => 50,
+        reason => block_error_score(reason),
+    }
+}
+
+impl std::fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::InvalidBlock(reason) => write!(f, "sent an invalid block: {}", reason),
+            Self::InvalidHeader(reason) => write!(f, "sent an invalid header: {}", reason),
+            Self::UnconnectedHeaders => write!(f, "sent headers that don't connect"),
+            Self::InvalidChain(reason) => write!(f, "sent an invalid chain: {}", reason),
+            Self::InvalidTransaction(reason) => {
+                write!(f, "sent an invalid transaction: {}", reason)
+            }
+            Self::OversizedMessage { items, max } => {
+                write!(f, "sent {} items in a message, the limit is {}", items, max)
+            }
+            Self::MalformedMessage(reason) => write!(f, "sent a malformed message: {}", reason),
+            Self::UnexpectedMessage => write!(f, "sent a handshake message after the handshake"),
+            Self::TooManyRequests => write!(f, "requested the blockchain too often"),
+            Self::TooManyChains => write!(f, "sent the blockchain too often"),
+        }
+    }
+}
+
+/// The misbehavior scores of the peers, and the banned IP addresses
+///
+/// Scores are kept until the peer disconnects. Bans expire after
+/// `BAN_DURATION` seconds.
+#[derive(Default)]
+pub struct PeerScores {
+    scores: Vec<(Recipient<GenericMessage>, u32)>,
+    banned: HashMap<IpAddr, i64>,                           // until when
+    chain_requests: Vec<(Recipient<GenericMessage>, i64)>, // when each peer last requested the blockchain
+    chains_received: Vec<(Recipient<GenericMessage>, i64)>, // when each peer last sent the blockchain
+    address_allowances: Vec<(Recipient<GenericMessage>, f64, i64)>, // addresses each peer may send, as of when
+}
+
+impl PeerScores {
+    /// Adds to the score of a peer, and returns whether it reached `BAN_SCORE`
+    pub fn add(&mut self, peer: &Recipient<GenericMessage>, score: u32) -> bool {
+        let total = match self.scores.iter_mut().find(|(p, _)| p == peer) {
+            Some((_, total)) => {
+                *total = total.saturating_add(score);
+                *total
+            }
+            None => {
+                self.scores.push((peer.clone(), score));
+                score
+            }
+        };
+
+        total >= BAN_SCORE
+    }
+
+    /// Forgets the score of a peer that disconnected
+    pub fn remove(&mut self, peer: &Recipient<GenericMessage>) {
+        self.scores.retain(|(p, _)| p != peer);
+        self.chain_requests.retain(|(p, _)| p != peer);
+        self.chains_received.retain(|(p, _)| p != peer);
+        self.address_allowances.retain(|(p, ..)| p != peer);
+    }
+
+    /// Bans an IP address for `BAN_DURATION` seconds, and returns when the
+    /// ban expires
+    pub fn ban(&mut self, ip: IpAddr, now: i64) -> i64 {
+        let until = now + BAN_DURATION;
+        self.banned.insert(ip, until);
+        until
+    }
+
+    pub fn is_banned(&mut self, ip: &IpAddr, now: i64) -> bool {
+        self.banned.retain(|_, until| *until > now);
+        self.banned.contains_key(ip)
+    }
+
+    /// Returns whether a peer may request the blockchain, which it may do
+    /// once every `REQUEST_BLOCKCHAIN_INTERVAL` seconds, and if so records
+    /// the request
+    pub fn allow_chain_request(&mut self, peer: &Recipient<GenericMessage>, now: i64) -> bool {
+        allow_once_per_interval(&mut self.chain_requests, peer, now)
+    }
+
+    /// Returns whether a peer may send the blockchain, which it may do once
+    /// every `REQUEST_BLOCKCHAIN_INTERVAL` seconds, and if so records it
+    ///
+    /// Nodes don't request the blockchain from each other, so every chain is
+    /// unsolicited, and validating one is as expensive as sending it.
+    pub fn allow_chain(&mut self, peer: &Recipient<GenericMessage>, now: i64) -> bool {
+        allow_once_per_interval(&mut self.chains_received, peer, now)
+    }
+
+    /// Refills the allowance of a peer that was asked for addresses, so it
+    /// can answer with a full message
+    pub fn expect_addresses(&mut self, peer: &Recipient<GenericMessage>, now: i64) {
+        let max = MAX_ADDRESSES_PER_MESSAGE as f64;
+        match self.address_allowances.iter_mut().find(|(p, ..)| p == peer) {
+            Some((_, allowance, since)) => {
+                *allowance = max;
+                *since = now;
+            }
+            None => self.address_allowances.push((peer.clone(), max, now)),
+        }
+    }
+
+    /// Returns how many of `count` addresses a peer sent may be processed,
+    /// and takes them from its allowance
+    ///
+    /// A peer starts with an allowance of `MAX_ADDRESSES_PER_MESSAGE`, which
+    /// refills at `ADDRESS_RATE_LIMIT` addresses per second, and whenever the
+    /// node sends it `RequestAddresses` (see `expect_addresses`).
+    pub fn allow_addresses(
+        &mut self,
+        peer: &Recipient<GenericMessage>,
+        count: usize,
+        now: i64,
+    ) -> usize {
+        let max = MAX_ADDRESSES_PER_MESSAGE as f64;
+        let (allowance, since) = match self.address_allowances.iter_mut().find(|(p, ..)| p == peer)
+        {
+            Some((_, allowance, since)) => (allowance, since),
+            None => {
+                self.address_allowances.push((peer.clone(), max, now));
+                let (_, allowance, since) = self.address_allowances.last_mut().unwrap();
+                (allowance, since)
+            }
+        };
+
+        let elapsed = (now - *since).max(0) as f64;
+        *allowance = (*allowance + elapsed * ADDRESS_RATE_LIMIT).min(max);
+        *since = now;
+
+        let allowed = count.min(*allowance as usize);
+        *allowance -= allowed as f64;
+        allowed
+    }
+}
+
+/// Returns whether `peer` last did something, as recorded in `last_times`,
+/// at least `REQUEST_BLOCKCHAIN_INTERVAL` seconds ago, and if so records that
+/// it did it `now`
+fn allow_once_per_interval(
+    last_times: &mut Vec<(Recipient<GenericMessage>, i64)>,
+    peer: &Recipient<GenericMessage>,
+    now: i64,
+) -> bool {
+    match last_times.iter_mut().find(|(p, _)| p == peer) {
+        Some((_, last)) if now - *last < REQUEST_BLOCKCHAIN_INTERVAL => false,
+        Some((_, last)) => {
+            *last = now;
+            true
+        }
+        None => {
+            last_times.push((peer.clone(), now));
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_are_scored_by_what_they_break() {
+        assert_eq!(
+            Misbehavior::from_block_error(BlockValidationError::InvalidParent),
+            None
+        );
+        assert_eq!(
+            Misbehavior::from_header_error(BlockValidationError::InvalidParent),
+            None
+        );
+
+        let score = |err| Misbehavior::from_block_error(err).unwrap().score();
+        assert_eq!(
+            score(BlockValidationError::InsufficientProofOfWork),
+            BAN_SCORE
+        );
+        assert!(
+            score(BlockValidationError::DoubleSpend {
+                txn_id: String::new(),
+                out: 0,
+            }) < BAN_SCORE
+        );
+        assert!(
+            score(BlockValidationError::InvalidTransaction {
+                txn_id: String::new(),
+                reason: TransactionValidationError::ImmatureCoinbase {
+                    txn_id: String::new(),
+                    out: 0,
+                    height: 0,
+                },
+            }) < BAN_SCORE
+        );
+        assert_eq!(
+            score(BlockValidationError::InvalidTransaction {
+                txn_id: String::new(),
+                reason: TransactionValidationError::NoInputs,
+            }),
+            BAN_SCORE
+        );
+
+        let chain = |reason| {
+            Misbehavior::InvalidChain(ChainValidationError::InvalidBlock { index: 1, reason })
+                .score()
+        };
+        assert_eq!(chain(BlockValidationError::InvalidParent), BAN_SCORE);
+        assert_eq!(chain(BlockValidationError::UnknownParent), BAN_SCORE);
+        assert!(chain(BlockValidationError::InvalidTimestamp { timestamp: 0 }) > 0);
+    }
+}
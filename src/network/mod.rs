@@ -1,2 +1,8 @@
+pub mod address_book;
+pub mod block_sync;
+pub mod inventory;
+pub mod misbehavior;
 pub mod node;
+pub mod peer;
 pub mod server;
+pub mod wire;
@@ -1,13 +1,38 @@
-use crate::blockchain::block::Block;
-use crate::blockchain::transaction::Transaction;
+use crate::blockchain::block::{Block, BlockHeader};
+use crate::blockchain::mempool::{Mempool, MempoolError};
+use crate::blockchain::miner::{Miner, MiningJob, MiningStats};
+use crate::blockchain::multisig::Multisig;
+use crate::blockchain::storage::{BlockStore, FileStore, MemoryStore};
+use crate::blockchain::transaction::{Transaction, TransactionOptions};
+use crate::blockchain::validation::BlockValidationError;
 use crate::blockchain::BlockChain;
 use crate::broadcast;
+use crate::network::address_book::{AddressBook, SourceGroup};
+use crate::network::block_sync::BlockSync;
+use crate::network::inventory::{InventoryItem, SeenInventory};
+use crate::network::misbehavior::{Misbehavior, PeerScores};
+use crate::network::peer::{
+    self, Disconnect, HandshakeFailed, IncomingConnection, Peer, PeerConnected, PeerDisconnected,
+    PeerMisbehaved, PeerUnreachable,
+};
 use crate::network::server::{Server, ServerMessage};
+use crate::util::constants::{
+    BLOCK_DOWNLOAD_TIMEOUT, BLOCK_DOWNLOAD_WINDOW, MAX_ADDRESSES_PER_MESSAGE, MAX_BLOCK_SIZE,
+    MAX_HEADERS_PER_MESSAGE, MAX_INBOUND_PEERS, MAX_INVENTORY_PER_MESSAGE, MINING_THREADS,
+    PEER_MAINTENANCE_INTERVAL, TARGET_PEERS,
+};
+use crate::util::helper_functions::handle_result;
+use crate::util::traits::Hashable;
 use crate::util::types::Bytes;
 
 use actix::prelude::*;
-use log::{info, trace};
-use std::time::Instant;
+use chrono::Utc;
+use log::{info, trace, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum Events {
@@ -17,6 +42,13 @@ pub enum Events {
     DownloadedBlockchain = 3,
     ReceivedFresherBlockchain = 4,
     ReceivedNewBlock = 5,
+    RejectedBlock = 6,
+    RejectedBlockchain = 7,
+    ReceivedOrphanBlock = 8,
+    AcceptedTransaction = 9,
+    RejectedTransaction = 10,
+    DisconnectedPeer = 11,
+    BannedPeer = 12,
 }
 
 // Refactor: semantically order message types in enums
@@ -30,12 +62,42 @@ pub enum Payload {
         addresses: Vec<Recipient<GenericMessage>>,
     },
 
+    Listen {
+        port: u16,
+    },
+
+    Connect {
+        address: String,
+    },
+
     AddTransactionAndMine {
         from: Bytes,
         to: Bytes,
         amt: i32,
+        fee: i32,
+        options: TransactionOptions,
+    },
+
+    AddMultisigTransactionAndMine {
+        multisig: Multisig,
+        signers: Vec<Bytes>, // the addresses of the wallets that sign
+        to: Bytes,
+        amt: i32,
+        fee: i32,
+        options: TransactionOptions,
+    },
+
+    SubmitTransaction {
+        transaction: Transaction,
+        sender_addr: Option<Recipient<GenericMessage>>, // none if submitted to this node
     },
 
+    MineBlock {
+        address: Bytes,
+    },
+
+    GetMiningStatus,
+
     #[allow(dead_code)]
     PrintInfo,
 
@@ -43,15 +105,51 @@ pub enum Payload {
     UpdateBlockchainFromKnownNodes,
 
     RequestBlockchain {
-        sender_addr: actix::Addr<Node>,
+        sender_addr: Recipient<GenericMessage>,
     },
 
     Blockchain {
-        blockchain: BlockChain,
+        blockchain: Box<BlockChain>,
+        sender_addr: Recipient<GenericMessage>,
     },
 
     Block {
         block: Block,
+        sender_addr: Recipient<GenericMessage>,
+    },
+
+    RequestBlock {
+        hash: Bytes,
+        sender_addr: Recipient<GenericMessage>,
+    },
+
+    RequestHeaders {
+        locator: Vec<Bytes>,
+        sender_addr: Recipient<GenericMessage>,
+    },
+
+    Headers {
+        headers: Vec<BlockHeader>,
+        sender_addr: Recipient<GenericMessage>,
+    },
+
+    Inventory {
+        items: Vec<InventoryItem>,
+        sender_addr: Recipient<GenericMessage>,
+    },
+
+    RequestData {
+        items: Vec<InventoryItem>,
+        sender_addr: Recipient<GenericMessage>,
+    },
+
+    RequestAddresses {
+        sender_addr: Recipient<GenericMessage>,
+    },
+
+    Addresses {
+        addresses: Vec<(SocketAddr, i64)>,
+        sender_addr: Recipient<GenericMessage>,
     },
 
     PrintWalletBalance {
@@ -61,17 +159,77 @@ pub enum Payload {
 
 pub enum GenericResponse {
     OK,
+    MiningStatus { mining: bool, queued: usize },
 }
 
 #[derive(Message)]
 #[rtype(result = "Result<GenericResponse, String>")]
 pub struct GenericMessage(pub Payload);
 
+/// Sent from the miner's background thread when a mining job finishes
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MiningFinished {
+    job_id: u64,
+    block: Option<Block>,
+    stats: MiningStats,
+}
+
+/// Who spends the outputs of a `TransactionRequest` and signs its inputs
+enum Payer {
+    Wallet(Bytes),
+    Multisig {
+        multisig: Multisig,
+        signers: Vec<Bytes>,
+    },
+}
+
+/// An `AddTransactionAndMine` or `AddMultisigTransactionAndMine` request that
+/// waits for the running mining job
+struct TransactionRequest {
+    from: Payer,
+    to: Bytes,
+    amount: i32,
+    fee: i32,
+    options: TransactionOptions,
+}
+
+/// A connected node in another process
+struct PeerHandle {
+    name: String,
+    peer: Addr<Peer>,
+    recipient: Recipient<GenericMessage>,
+    remote: SocketAddr,
+    listen_address: Option<SocketAddr>,
+    inbound: bool, // whether the peer opened the connection
+}
+
+/// The mining job a node is running, and the address it pays the reward to
+struct MiningState {
+    job_id: u64,
+    job: MiningJob,
+    address: Bytes,
+}
+
 pub struct Node {
     pub address: String,
     pub server_addr: Addr<Server>,
     pub known_nodes: Vec<Recipient<GenericMessage>>,
     pub blockchain: BlockChain,
+    pub mempool: Mempool,
+    store: Box<dyn BlockStore>,
+    miner: Miner,
+    mining: Option<MiningState>,
+    next_job_id: u64,
+    queued_requests: VecDeque<TransactionRequest>,
+    address_book: AddressBook,
+    listen_port: u16,                       // 0 if the node doesn't listen
+    peers: HashMap<SocketAddr, PeerHandle>, // nodes in other processes, by remote address
+    pending_connections: HashSet<SocketAddr>,
+    pending_handshakes: usize, // incoming connections that haven't finished the handshake
+    sync: BlockSync,
+    seen: SeenInventory,
+    scores: PeerScores,
 }
 
 impl Node {
@@ -81,30 +239,282 @@ impl Node {
             server_addr,
             known_nodes: vec![],
             blockchain: BlockChain::new_placeholder(),
+            mempool: Mempool::default(),
+            store: Box::new(MemoryStore::default()),
+            miner: Miner::new(MINING_THREADS),
+            mining: None,
+            next_job_id: 0,
+            queued_requests: VecDeque::new(),
+            address_book: AddressBook::default(),
+            listen_port: 0,
+            peers: HashMap::new(),
+            pending_connections: HashSet::new(),
+            pending_handshakes: 0,
+            sync: BlockSync::default(),
+            seen: SeenInventory::default(),
+            scores: PeerScores::default(),
         }
     }
 
+    pub fn set_mining_threads(&mut self, threads: usize) {
+        self.miner = Miner::new(threads);
+    }
+
+    /// Creates a node that stores its blocks in `data_dir`, and rebuilds the
+    /// blockchain and the UTXO set from the blocks already stored there
+    pub fn with_data_dir(
+        address: String,
+        server_addr: Addr<Server>,
+        data_dir: &Path,
+    ) -> io::Result<Self> {
+        let mut store = FileStore::open(data_dir)?;
+        let blocks = store.load_blocks()?;
+        let n_blocks = blocks.len();
+
+        let mut node = Node::default(address, server_addr);
+
+        for block in blocks.into_iter() {
+            let hash = hex::encode(&block.hash);
+            if let Err(err) = node.blockchain.accept_block(block) {
+                warn!(
+                    "[{}] Couldn't reload stored block {}: {}",
+                    &node.address, hash, err
+                );
+            }
+        }
+
+        info!(
+            "[{}] Loaded {} blocks from {}, blockchain length = {}",
+            &node.address,
+            n_blocks,
+            data_dir.display(),
+            node.blockchain.length
+        );
+
+        node.store = Box::new(store);
+        Ok(node)
+    }
+
     pub fn create_blockchain(&mut self, address: &Bytes) {
+        if !self.blockchain.blocks.is_empty() {
+            info!(
+                "[{}] Keeping the blockchain loaded from storage",
+                &self.address
+            );
+            return;
+        }
+
         self.blockchain = BlockChain::new(address);
+        let blocks = self.blockchain.blocks.clone();
+        self.store_blocks(&blocks);
+    }
+
+    /// Writes blocks that were added to the block tree to the block store
+    fn store_blocks(&mut self, blocks: &[Block]) {
+        for block in blocks.iter() {
+            if let Err(err) = self.store.put_block(block) {
+                warn!(
+                    "[{}] Couldn't store block {}: {}",
+                    &self.address,
+                    hex::encode(&block.hash),
+                    err
+                );
+            }
+        }
+    }
+
+    fn make_transaction_and_mine(&mut self, request: TransactionRequest, ctx: &mut Context<Self>) {
+        // The transaction may spend the outputs of the block being mined
+        if self.mining.is_some() {
+            info!(
+                "[{}] Queueing transaction until the current block is mined",
+                &self.address
+            );
+            self.queued_requests.push_back(request);
+            return;
+        }
+
+        let TransactionRequest {
+            from,
+            to,
+            amount,
+            fee,
+            options,
+        } = request;
+
+        // The block reward goes back to the payer
+        let (result, miner_address) = match from {
+            Payer::Wallet(from) => (
+                Transaction::new(
+                    &from,
+                    &to,
+                    amount,
+                    fee,
+                    options,
+                    &self.blockchain,
+                    &self.mempool,
+                ),
+                from,
+            ),
+            Payer::Multisig { multisig, signers } => {
+                let address = multisig.address();
+                let result = Transaction::new_unsigned(
+                    &address,
+                    &to,
+                    amount,
+                    fee,
+                    options,
+                    &self.blockchain,
+                    &self.mempool,
+                )
+                .and_then(|mut txn| {
+                    txn.sign_multisig(&multisig, &signers, options.sighash_type, &self.blockchain)
+                        .map(|()| txn)
+                });
+                (result, address)
+            }
+        };
+
+        let txn = match result {
+            Ok(txn) => txn,
+            Err(err) => {
+                warn!("[{}] Couldn't create transaction: {}", &self.address, err);
+                return;
+            }
+        };
+
+        if let Err(err) = self.submit_transaction(txn, ctx) {
+            warn!("[{}] Couldn't submit transaction: {}", &self.address, err);
+            return;
+        }
+
+        self.mine_block(&miner_address, ctx);
+    }
+
+    /// Validates a transaction, adds it to the mempool and announces it to
+    /// the known nodes
+    pub fn submit_transaction(
+        &mut self,
+        txn: Transaction,
+        ctx: &mut Context<Self>,
+    ) -> Result<(), MempoolError> {
+        let item = InventoryItem::Transaction(txn.id.clone());
+        if let Err(err) = self.mempool.add(txn, &self.blockchain) {
+            self.seen.mark_rejected(&item);
+            return Err(err);
+        }
+
+        self.seen.mark_seen(item.clone());
+        self.announce(vec![item], None, ctx);
+        Ok(())
+    }
+
+    /// Sends the hashes of new blocks or transactions to the known nodes,
+    /// except the one they came from, which request the ones they don't have
+    fn announce(
+        &self,
+        items: Vec<InventoryItem>,
+        except: Option<&Recipient<GenericMessage>>,
+        ctx: &mut Context<Self>,
+    ) {
+        if items.is_empty() {
+            return;
+        }
+
+        for addr in self.known_nodes.iter() {
+            if Some(addr) == except {
+                continue;
+            }
+
+            let result = addr.try_send(GenericMessage(Payload::Inventory {
+                items: items.clone(),
+                sender_addr: ctx.address().recipient(),
+            }));
+            handle_result(result, "Inventory");
+        }
     }
 
-    pub fn make_transaction_and_mine(&mut self, from: Bytes, to: Bytes, amount: i32) {
-        let txn = Transaction::new(&from, &to, amount, &self.blockchain);
-        let coinbase_txn = Transaction::create_coinbase_txn(&from);
+    /// Starts mining a block with transactions from the mempool that pays
+    /// the reward to `address`, replacing the running job if there is one
+    ///
+    /// Mining happens on the miner's threads, and the node is sent a
+    /// `MiningFinished` message when a block is found.
+    pub fn mine_block(&mut self, address: &Bytes, ctx: &mut Context<Self>) {
+        if let Some(state) = self.mining.take() {
+            state.job.cancel();
+        }
+
+        let subsidy = self.blockchain.params.block_subsidy(self.blockchain.length);
+
+        // Leave room for the block header and the coinbase transaction
+        let reserved = Block::new(
+            vec![Transaction::create_coinbase_txn(address, subsidy)],
+            self.blockchain.length,
+            self.blockchain.last_hash.clone(),
+            0,
+        )
+        .encode()
+        .len();
+
+        let (mut txns, fees) = self.mempool.select(
+            MAX_BLOCK_SIZE - reserved,
+            self.blockchain.length,
+            Utc::now().timestamp(),
+            subsidy,
+        );
+        txns.push(Transaction::create_coinbase_txn(address, subsidy + fees));
 
-        info!("[{}] Mining new block", &self.address);
-        let now = Instant::now();
+        info!(
+            "[{}] Mining new block with {} transactions ({} in fees) on {} threads",
+            &self.address,
+            txns.len(),
+            fees,
+            self.miner.threads()
+        );
 
-        let block = Block::create(
-            vec![txn, coinbase_txn],
+        let block = Block::new(
+            txns,
             self.blockchain.length,
             self.blockchain.last_hash.clone(),
+            self.blockchain.next_difficulty(&self.blockchain.last_hash),
         );
 
+        let job_id = self.next_job_id;
+        self.next_job_id += 1;
+
+        let node_addr = ctx.address();
+        let job = self.miner.start(block, move |block, stats| {
+            node_addr.do_send(MiningFinished {
+                job_id,
+                block,
+                stats,
+            });
+        });
+
+        self.mining = Some(MiningState {
+            job_id,
+            job,
+            address: address.clone(),
+        });
+    }
+
+    /// Restarts the running mining job on top of the current tip
+    fn restart_mining(&mut self, ctx: &mut Context<Self>) {
+        if let Some(state) = self.mining.take() {
+            info!("[{}] Restarting mining on the new tip", &self.address);
+            self.mine_block(&state.address, ctx);
+        }
+    }
+
+    /// Adds a mined block to the blockchain and announces it to the known
+    /// nodes
+    fn handle_mined_block(&mut self, block: Block, stats: MiningStats, ctx: &mut Context<Self>) {
         info!(
-            "[{}] Mined successfully in {} seconds",
+            "[{}] Mined successfully in {:.2} seconds ({} hashes, {:.0} hashes/s)",
             &self.address,
-            now.elapsed().as_secs()
+            stats.elapsed.as_secs_f64(),
+            stats.hashes,
+            stats.hash_rate()
         );
 
         let payload = format!(
@@ -112,34 +522,219 @@ impl Node {
                 "nodeId":"{}",
                 "eventId":"{:?}",
                 "details": {{
-                    "timeTaken": {}
+                    "timeTaken": {},
+                    "hashRate": {:.0}
                 }}
             }}"#,
             &self.address,
             Events::MinedTransaction,
-            now.elapsed().as_secs()
+            stats.elapsed.as_secs(),
+            stats.hash_rate()
         );
 
         broadcast!(self.server_addr, payload);
 
-        self.blockchain.add_block(block.clone());
+        match self.blockchain.accept_block(block.clone()) {
+            Ok(update) => {
+                self.store_blocks(&update.accepted);
+                self.mempool.update(&self.blockchain, &update);
+            }
+            Err(err) => {
+                warn!("[{}] Mined an invalid block: {}", &self.address, err);
+                return;
+            }
+        }
 
-        for addr in self.known_nodes.iter() {
+        let item = InventoryItem::Block(block.hash);
+        self.seen.mark_seen(item.clone());
+        self.announce(vec![item], None, ctx);
+    }
+}
+
+impl Node {
+    /// Connects to the node listening at `address`
+    fn connect(&mut self, address: SocketAddr, ctx: &mut Context<Self>) {
+        info!("[{}] Connecting to {}", &self.address, address);
+        self.pending_connections.insert(address);
+        peer::connect(
+            address,
+            self.address.clone(),
+            self.listen_port,
+            ctx.address(),
+        );
+    }
+
+    /// Forgets stale addresses, and connects to the most recently seen
+    /// addresses while the node has fewer than `TARGET_PEERS` peers, which
+    /// also replaces peers that went away
+    ///
+    /// If the address book runs out of addresses to try, the peers are asked
+    /// for theirs.
+    fn maintain_peers(&mut self, ctx: &mut Context<Self>) {
+        self.address_book.expire(Utc::now().timestamp());
+
+        let connections = self.peers.len() + self.pending_connections.len();
+        if connections >= TARGET_PEERS {
+            return;
+        }
+
+        let now = Utc::now().timestamp();
+        let missing = TARGET_PEERS - connections;
+        let candidates = self
+            .address_book
+            .candidates(missing, &self.pending_connections)
+            .into_iter()
+            .filter(|address| !self.scores.is_banned(&address.ip(), now))
+            .collect::<Vec<_>>();
+
+        if candidates.len() < missing {
+            for handle in self.peers.values() {
+                self.scores.expect_addresses(&handle.recipient, now);
+                let result = handle
+                    .recipient
+                    .try_send(GenericMessage(Payload::RequestAddresses {
+                        sender_addr: ctx.address().recipient(),
+                    }));
+                handle_result(result, "RequestAddresses");
+            }
+        }
+
+        for address in candidates.into_iter() {
+            self.connect(address, ctx);
+        }
+    }
+}
+
+impl Node {
+    /// Adds to the misbehavior score of a peer, and bans it once the score
+    /// reaches `BAN_SCORE`
+    ///
+    /// A banned peer in another process is disconnected, and connections
+    /// from and to its IP address are refused until the ban expires. A node
+    /// in this process is only forgotten, as it has no address to ban.
+    fn misbehaving(&mut self, peer: &Recipient<GenericMessage>, misbehavior: Misbehavior) {
+        let connected = self
+            .peers
+            .values()
+            .find(|handle| &handle.recipient == peer)
+            .map(|handle| (handle.name.clone(), handle.peer.clone(), handle.remote));
+
+        let name = connected.as_ref().map_or_else(
+            || "a node in this process".to_string(),
+            |(name, ..)| name.clone(),
+        );
+        warn!("[{}] Peer {} {}", &self.address, name, misbehavior);
+
+        if !self.scores.add(peer, misbehavior.score()) {
+            return;
+        }
+
+        match connected {
+            Some((name, handle, remote)) => {
+                let until = self.scores.ban(remote.ip(), Utc::now().timestamp());
+                warn!(
+                    "[{}] Banning {} at {} until {}",
+                    &self.address,
+                    name,
+                    remote.ip(),
+                    until
+                );
+                handle.do_send(Disconnect);
+
+                let payload = format!(
+                    r#"{{
+                        "nodeId":"{}",
+                        "eventId":"{:?}",
+                        "details":{{
+                            "peer": "{}",
+                            "address": "{}",
+                            "reason": {:?},
+                            "bannedUntil": {}
+                        }}
+                    }}"#,
+                    &self.address,
+                    Events::BannedPeer,
+                    name,
+                    remote.ip(),
+                    misbehavior.to_string(),
+                    until
+                );
+
+                broadcast!(self.server_addr, payload);
+            }
+            None => {
+                self.known_nodes.retain(|addr| addr != peer);
+                self.sync.remove_peer(peer);
+                self.scores.remove(peer);
+
+                let payload = format!(
+                    r#"{{
+                        "nodeId":"{}",
+                        "eventId":"{:?}",
+                        "details":{{
+                            "peer": "{}",
+                            "reason": {:?}
+                        }}
+                    }}"#,
+                    &self.address,
+                    Events::DisconnectedPeer,
+                    name,
+                    misbehavior.to_string()
+                );
+
+                broadcast!(self.server_addr, payload);
+            }
+        }
+    }
+}
+
+impl Node {
+    /// Asks a peer for the headers that follow the node's best header
+    fn request_headers(&self, peer: &Recipient<GenericMessage>, ctx: &mut Context<Self>) {
+        let result = peer.try_send(GenericMessage(Payload::RequestHeaders {
+            locator: self.blockchain.locator(),
+            sender_addr: ctx.address().recipient(),
+        }));
+        handle_result(result, "RequestHeaders");
+    }
+
+    /// Requests the missing blocks of the best header chain from the peers
+    /// that sent headers, a window of `BLOCK_DOWNLOAD_WINDOW` blocks at a time
+    fn request_blocks(&mut self, ctx: &mut Context<Self>) {
+        let missing = self.blockchain.missing_blocks(BLOCK_DOWNLOAD_WINDOW);
+
+        for (peer, hashes) in self.sync.schedule(missing, Utc::now().timestamp()) {
             trace!(
-                "[{}] Sending block to {:?} in `make_transaction_and_mine`",
+                "[{}] Requesting {} blocks from {:?}",
                 &self.address,
-                &addr
+                hashes.len(),
+                &peer
             );
-            addr.try_send(GenericMessage(Payload::Block {
-                block: block.clone(),
-            }))
-            .expect(&format!("Couldn't send updated block to {:?}", addr));
+
+            let result = peer.try_send(GenericMessage(Payload::RequestData {
+                items: hashes.into_iter().map(InventoryItem::Block).collect(),
+                sender_addr: ctx.address().recipient(),
+            }));
+            handle_result(result, "RequestData");
         }
     }
 }
 
 impl Actor for Node {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(
+            Duration::from_secs(PEER_MAINTENANCE_INTERVAL),
+            |node, ctx| node.maintain_peers(ctx),
+        );
+
+        // Requests that timed out are sent again
+        ctx.run_interval(
+            Duration::from_secs(BLOCK_DOWNLOAD_TIMEOUT as u64),
+            |node, ctx| node.request_blocks(ctx),
+        );
+    }
 }
 
 impl Handler<GenericMessage> for Node {
@@ -153,10 +748,11 @@ impl Handler<GenericMessage> for Node {
                 self.create_blockchain(&address);
 
                 for addr in self.known_nodes.iter() {
-                    addr.try_send(GenericMessage(Payload::Blockchain {
-                        blockchain: self.blockchain.clone(),
-                    }))
-                    .expect(&format!("Couldn't send blockchain to {:?}", addr));
+                    let result = addr.try_send(GenericMessage(Payload::Blockchain {
+                        blockchain: Box::new(self.blockchain.clone()),
+                        sender_addr: ctx.address().recipient(),
+                    }));
+                    handle_result(result, "Blockchain");
                 }
 
                 let j = serde_json::to_string(&self.blockchain).unwrap();
@@ -207,14 +803,36 @@ impl Handler<GenericMessage> for Node {
                 broadcast!(self.server_addr, payload);
             }
 
-            Payload::UpdateBlockchainFromKnownNodes => {
-                let random_node = &self.known_nodes[0];
+            Payload::Listen { port } => {
+                let listener = TcpListener::bind(("0.0.0.0", port))
+                    .map_err(|err| format!("Couldn't listen on port {}: {}", port, err))?;
+
+                info!("[{}] Listening for peers on port {}", &self.address, port);
+                self.listen_port = port;
+                peer::listen(listener, ctx.address());
+            }
+
+            Payload::Connect { address } => {
+                let resolved = address
+                    .to_socket_addrs()
+                    .map_err(|err| format!("Couldn't resolve {}: {}", address, err))?
+                    .next()
+                    .ok_or_else(|| format!("Couldn't resolve {}", address))?;
+
+                let now = Utc::now().timestamp();
+                if self.scores.is_banned(&resolved.ip(), now) {
+                    return Err(format!("{} is banned", resolved.ip()));
+                }
+
+                // Seed peers go into the address book like any other address
+                self.address_book.add(resolved, now, None);
+                self.connect(resolved, ctx);
+            }
 
-                random_node
-                    .try_send(GenericMessage(Payload::RequestBlockchain {
-                        sender_addr: ctx.address(),
-                    }))
-                    .expect("Could not send the get blockchain request");
+            Payload::UpdateBlockchainFromKnownNodes => {
+                for addr in self.known_nodes.iter() {
+                    self.request_headers(addr, ctx);
+                }
 
                 let j = serde_json::to_string(&self.blockchain).unwrap();
 
@@ -239,20 +857,178 @@ impl Handler<GenericMessage> for Node {
                 println!("{}", self.blockchain);
             }
 
-            Payload::AddTransactionAndMine { from, to, amt } => {
-                self.make_transaction_and_mine(from.clone(), to.clone(), amt);
+            Payload::AddTransactionAndMine {
+                from,
+                to,
+                amt,
+                fee,
+                options,
+            } => {
+                let request = TransactionRequest {
+                    from: Payer::Wallet(from),
+                    to,
+                    amount: amt,
+                    fee,
+                    options,
+                };
+                self.make_transaction_and_mine(request, ctx);
+            }
+
+            Payload::AddMultisigTransactionAndMine {
+                multisig,
+                signers,
+                to,
+                amt,
+                fee,
+                options,
+            } => {
+                let request = TransactionRequest {
+                    from: Payer::Multisig { multisig, signers },
+                    to,
+                    amount: amt,
+                    fee,
+                    options,
+                };
+                self.make_transaction_and_mine(request, ctx);
+            }
+
+            Payload::SubmitTransaction {
+                transaction,
+                sender_addr,
+            } => {
+                let txn_id = hex::encode(&transaction.id);
+
+                let payload = match self.submit_transaction(transaction, ctx) {
+                    Ok(()) => format!(
+                        r#"{{
+                            "nodeId":"{}",
+                            "eventId":"{:?}",
+                            "details":{{
+                                "txnId": "{}",
+                                "mempoolSize": {}
+                            }}
+                        }}"#,
+                        &self.address,
+                        Events::AcceptedTransaction,
+                        txn_id,
+                        self.mempool.len()
+                    ),
+                    // Already seen, most likely gossiped back to us
+                    Err(MempoolError::AlreadyKnown) => return Ok(GenericResponse::OK),
+                    Err(err) => {
+                        warn!(
+                            "[{}] Rejected transaction {}: {}",
+                            &self.address, txn_id, err
+                        );
+
+                        let reason = err.to_string();
+                        if let Some(sender_addr) = sender_addr {
+                            if let Some(misbehavior) = Misbehavior::from_transaction_error(err) {
+                                self.misbehaving(&sender_addr, misbehavior);
+                            }
+                        }
+
+                        format!(
+                            r#"{{
+                                "nodeId":"{}",
+                                "eventId":"{:?}",
+                                "details":{{
+                                    "txnId": "{}",
+                                    "reason": {:?}
+                                }}
+                            }}"#,
+                            &self.address,
+                            Events::RejectedTransaction,
+                            txn_id,
+                            reason
+                        )
+                    }
+                };
+
+                broadcast!(self.server_addr, payload);
+            }
+
+            Payload::MineBlock { address } => {
+                if self.mempool.is_empty() {
+                    info!(
+                        "[{}] Mempool is empty, mining an empty block",
+                        &self.address
+                    );
+                }
+
+                self.mine_block(&address, ctx);
+            }
+
+            Payload::GetMiningStatus => {
+                return Ok(GenericResponse::MiningStatus {
+                    mining: self.mining.is_some(),
+                    queued: self.queued_requests.len(),
+                });
             }
 
             Payload::RequestBlockchain { sender_addr } => {
-                sender_addr
-                    .try_send(GenericMessage(Payload::Blockchain {
-                        blockchain: self.blockchain.clone(),
-                    }))
-                    .expect(&format!("Couldn't send blockchain to {:?}", sender_addr));
+                // Sending the whole blockchain is expensive
+                if !self
+                    .scores
+                    .allow_chain_request(&sender_addr, Utc::now().timestamp())
+                {
+                    self.misbehaving(&sender_addr, Misbehavior::TooManyRequests);
+                    return Ok(GenericResponse::OK);
+                }
+
+                let result = sender_addr.try_send(GenericMessage(Payload::Blockchain {
+                    blockchain: Box::new(self.blockchain.clone()),
+                    sender_addr: ctx.address().recipient(),
+                }));
+                handle_result(result, "Blockchain");
             }
 
-            Payload::Blockchain { blockchain } => {
-                if self.blockchain.blocks.len() < blockchain.blocks.len() {
+            Payload::Blockchain {
+                blockchain,
+                sender_addr,
+            } => {
+                // Validating a chain is as expensive as sending it
+                if !self
+                    .scores
+                    .allow_chain(&sender_addr, Utc::now().timestamp())
+                {
+                    self.misbehaving(&sender_addr, Misbehavior::TooManyChains);
+                    return Ok(GenericResponse::OK);
+                }
+
+                // The work is recomputed from the blocks themselves, which
+                // `validate_chain` then checks against this node's rules
+                let received_work = blockchain
+                    .blocks
+                    .iter()
+                    .fold(0u128, |acc, b| acc.saturating_add(b.work()));
+
+                if self.blockchain.total_work() < received_work {
+                    let params = self.blockchain.params.clone();
+                    let blockchain = match BlockChain::validate_chain(&blockchain.blocks, params) {
+                        Ok(blockchain) => blockchain,
+                        Err(err) => {
+                            warn!("[{}] Rejected a fresher blockchain: {}", &self.address, err);
+                            self.misbehaving(&sender_addr, Misbehavior::InvalidChain(err.clone()));
+
+                            let payload = format!(
+                                r#"{{
+                                    "nodeId":"{}",
+                                    "eventId":"{:?}",
+                                    "details":{{
+                                        "reason": {:?}
+                                    }}
+                                }}"#,
+                                &self.address,
+                                Events::RejectedBlockchain,
+                                err.to_string()
+                            );
+
+                            broadcast!(self.server_addr, payload);
+                            return Ok(GenericResponse::OK);
+                        }
+                    };
+
                     let old_blockchain_length = self.blockchain.length;
 
                     info!(
@@ -260,7 +1036,10 @@ impl Handler<GenericMessage> for Node {
                         self.address, old_blockchain_length,
                     );
 
-                    self.blockchain = blockchain;
+                    let update = self.blockchain.adopt(blockchain);
+                    self.store_blocks(&update.accepted);
+                    self.mempool.update(&self.blockchain, &update);
+                    self.restart_mining(ctx);
                     let new_blockchain_length = self.blockchain.length;
 
                     info!(
@@ -291,42 +1070,597 @@ impl Handler<GenericMessage> for Node {
                 }
             }
 
-            Payload::Block { block } => {
+            Payload::RequestBlock { hash, sender_addr } => {
+                match self.blockchain.find_block(&hash) {
+                    Some(block) => {
+                        let result = sender_addr.try_send(GenericMessage(Payload::Block {
+                            block: block.clone(),
+                            sender_addr: ctx.address().recipient(),
+                        }));
+                        handle_result(result, "RequestBlock");
+                    }
+                    None => info!(
+                        "[{}] Requested block {} is unknown",
+                        &self.address,
+                        hex::encode(&hash)
+                    ),
+                }
+            }
+
+            Payload::Block { block, sender_addr } => {
                 info!(
                     "[{}] Received a block to add to the blockchain",
                     &self.address
                 );
-                self.blockchain.add_block_to_memory_pool(block);
+                let raw_block_hash = block.hash.clone();
+                let block_hash = hex::encode(&block.hash);
+                let requested = self.sync.received(&raw_block_hash);
+                let item = InventoryItem::Block(raw_block_hash.clone());
 
-                let payload = format!(
-                    r#"{{
-                        "nodeId":"{}",
-                        "eventId":"{:?}"
-                    }}"#,
-                    &self.address,
-                    Events::ReceivedNewBlock
-                );
+                let payload = match self.blockchain.accept_block(block) {
+                    Ok(update) => {
+                        self.seen.mark_seen(item);
+                        self.store_blocks(&update.accepted);
+
+                        if !update.connected.is_empty() {
+                            self.mempool.update(&self.blockchain, &update);
+                            self.restart_mining(ctx);
+                        }
+
+                        // Forward new tips, but not the blocks of a download
+                        if !requested {
+                            let items = update
+                                .connected
+                                .iter()
+                                .map(|b| InventoryItem::Block(b.hash.clone()))
+                                .collect();
+                            self.announce(items, Some(&sender_addr), ctx);
+                        }
+
+                        format!(
+                            r#"{{
+                                "nodeId":"{}",
+                                "eventId":"{:?}",
+                                "details":{{
+                                    "connectedBlocks": {},
+                                    "disconnectedBlocks": {}
+                                }}
+                            }}"#,
+                            &self.address,
+                            Events::ReceivedNewBlock,
+                            update.connected.len(),
+                            update.disconnected.len()
+                        )
+                    }
+                    Err(BlockValidationError::UnknownParent) => {
+                        // Ask the sender for the first block we are missing,
+                        // unless the block is being downloaded with its
+                        // ancestors after its header
+                        let missing = if self.blockchain.has_header(&raw_block_hash) {
+                            None
+                        } else {
+                            self.blockchain.missing_ancestor(&raw_block_hash)
+                        };
+
+                        if let Some(missing) = missing {
+                            info!(
+                                "[{}] Requesting missing block {} for orphan {}",
+                                &self.address,
+                                hex::encode(&missing),
+                                block_hash
+                            );
+
+                            let result =
+                                sender_addr.try_send(GenericMessage(Payload::RequestBlock {
+                                    hash: missing,
+                                    sender_addr: ctx.address().recipient(),
+                                }));
+                            handle_result(result, "RequestBlock");
+                        }
+
+                        format!(
+                            r#"{{
+                                "nodeId":"{}",
+                                "eventId":"{:?}",
+                                "details":{{
+                                    "blockHash": "{}"
+                                }}
+                            }}"#,
+                            &self.address,
+                            Events::ReceivedOrphanBlock,
+                            block_hash
+                        )
+                    }
+                    Err(err) => {
+                        warn!("[{}] Rejected block {}: {}", &self.address, block_hash, err);
+                        self.seen.mark_rejected(&item);
+
+                        if let Some(misbehavior) = Misbehavior::from_block_error(err.clone()) {
+                            self.misbehaving(&sender_addr, misbehavior);
+                        }
+
+                        format!(
+                            r#"{{
+                                "nodeId":"{}",
+                                "eventId":"{:?}",
+                                "details":{{
+                                    "blockHash": "{}",
+                                    "reason": {:?}
+                                }}
+                            }}"#,
+                            &self.address,
+                            Events::RejectedBlock,
+                            block_hash,
+                            err.to_string()
+                        )
+                    }
+                };
 
                 broadcast!(self.server_addr, payload);
+
+                // Keep the download window full
+                if requested {
+                    self.request_blocks(ctx);
+
+                    if self.sync.in_flight() == 0 {
+                        info!(
+                            "[{}] Downloaded the blocks of the best header chain, blockchain length = {}",
+                            &self.address, self.blockchain.length
+                        );
+                    }
+                }
             }
 
-            Payload::PrintWalletBalance { public_key_hash } => {
-                let unspent_txns = self.blockchain.find_unspent_transactions(&public_key_hash);
-                let mut balance: i32 = 0;
-                for txn in unspent_txns.iter() {
-                    for output in txn.outputs.iter() {
-                        if output.is_locked_with_key(&public_key_hash) {
-                            balance += output.value;
+            Payload::RequestHeaders {
+                locator,
+                sender_addr,
+            } => {
+                let headers = self
+                    .blockchain
+                    .headers_after(&locator, MAX_HEADERS_PER_MESSAGE);
+
+                let result = sender_addr.try_send(GenericMessage(Payload::Headers {
+                    headers,
+                    sender_addr: ctx.address().recipient(),
+                }));
+                handle_result(result, "Headers");
+            }
+
+            Payload::Headers {
+                headers,
+                sender_addr,
+            } => {
+                let n_headers = headers.len();
+                let mut n_new = 0;
+
+                if n_headers > MAX_HEADERS_PER_MESSAGE {
+                    let misbehavior = Misbehavior::OversizedMessage {
+                        items: n_headers,
+                        max: MAX_HEADERS_PER_MESSAGE,
+                    };
+                    self.misbehaving(&sender_addr, misbehavior);
+                    return Ok(GenericResponse::OK);
+                }
+
+                for header in headers.into_iter() {
+                    let hash = hex::encode(&header.hash);
+
+                    match self.blockchain.accept_header(header) {
+                        Ok(true) => n_new += 1,
+                        Ok(false) => (),
+                        Err(err) => {
+                            warn!("[{}] Rejected header {}: {}", &self.address, hash, err);
+
+                            if let Some(misbehavior) = Misbehavior::from_header_error(err) {
+                                self.misbehaving(&sender_addr, misbehavior);
+                            }
+                            break;
                         }
                     }
                 }
-                println!("{}", balance);
+
+                if n_new > 0 {
+                    info!(
+                        "[{}] Received {} new headers, best header is at index {}",
+                        &self.address,
+                        n_new,
+                        self.blockchain.best_header().map_or(-1, |h| h.index)
+                    );
+                }
+
+                if n_headers > 0 {
+                    self.sync.add_peer(sender_addr.clone());
+                }
+
+                // A full message means the peer has more headers
+                if n_headers == MAX_HEADERS_PER_MESSAGE {
+                    self.request_headers(&sender_addr, ctx);
+                }
+
+                self.request_blocks(ctx);
+            }
+
+            Payload::Inventory { items, sender_addr } => {
+                if items.len() > MAX_INVENTORY_PER_MESSAGE {
+                    let misbehavior = Misbehavior::OversizedMessage {
+                        items: items.len(),
+                        max: MAX_INVENTORY_PER_MESSAGE,
+                    };
+                    self.misbehaving(&sender_addr, misbehavior);
+                    return Ok(GenericResponse::OK);
+                }
+
+                let now = Utc::now().timestamp();
+                let mut wanted = Vec::new();
+
+                for item in items.into_iter() {
+                    let known = match &item {
+                        InventoryItem::Transaction(id) => self.mempool.contains(id),
+                        InventoryItem::Block(hash) => self.blockchain.find_block(hash).is_some(),
+                    };
+
+                    if !known && self.seen.should_request(&item, now) {
+                        wanted.push(item);
+                    }
+                }
+
+                if !wanted.is_empty() {
+                    let result = sender_addr.try_send(GenericMessage(Payload::RequestData {
+                        items: wanted,
+                        sender_addr: ctx.address().recipient(),
+                    }));
+                    handle_result(result, "RequestData");
+                }
+            }
+
+            Payload::RequestData { items, sender_addr } => {
+                if items.len() > MAX_INVENTORY_PER_MESSAGE {
+                    let misbehavior = Misbehavior::OversizedMessage {
+                        items: items.len(),
+                        max: MAX_INVENTORY_PER_MESSAGE,
+                    };
+                    self.misbehaving(&sender_addr, misbehavior);
+                    return Ok(GenericResponse::OK);
+                }
+
+                for item in items.iter() {
+                    let payload = match item {
+                        InventoryItem::Transaction(id) => {
+                            self.mempool.get(id).map(|txn| Payload::SubmitTransaction {
+                                transaction: txn.clone(),
+                                sender_addr: Some(ctx.address().recipient()),
+                            })
+                        }
+                        InventoryItem::Block(hash) => {
+                            self.blockchain
+                                .find_block(hash)
+                                .map(|block| Payload::Block {
+                                    block: block.clone(),
+                                    sender_addr: ctx.address().recipient(),
+                                })
+                        }
+                    };
+
+                    // Items the node doesn't have any more are skipped
+                    if let Some(payload) = payload {
+                        let result = sender_addr.try_send(GenericMessage(payload));
+                        handle_result(result, "RequestData");
+                    }
+                }
+            }
+
+            Payload::RequestAddresses { sender_addr } => {
+                let addresses = self
+                    .address_book
+                    .recent(MAX_ADDRESSES_PER_MESSAGE, Utc::now().timestamp());
+
+                let result = sender_addr.try_send(GenericMessage(Payload::Addresses {
+                    addresses,
+                    sender_addr: ctx.address().recipient(),
+                }));
+                handle_result(result, "Addresses");
+            }
+
+            Payload::Addresses {
+                addresses,
+                sender_addr,
+            } => {
+                if addresses.len() > MAX_ADDRESSES_PER_MESSAGE {
+                    let misbehavior = Misbehavior::OversizedMessage {
+                        items: addresses.len(),
+                        max: MAX_ADDRESSES_PER_MESSAGE,
+                    };
+                    self.misbehaving(&sender_addr, misbehavior);
+                    return Ok(GenericResponse::OK);
+                }
+
+                let now = Utc::now().timestamp();
+                let known = self.address_book.len();
+
+                // Nodes in this process are trusted with their addresses
+                let source = self
+                    .peers
+                    .values()
+                    .find(|handle| handle.recipient == sender_addr)
+                    .map(|handle| SourceGroup::from(handle.remote.ip()));
+                let allowed = match source {
+                    Some(_) => self
+                        .scores
+                        .allow_addresses(&sender_addr, addresses.len(), now),
+                    None => addresses.len(),
+                };
+
+                // Addresses can't have been seen in the future
+                for (address, last_seen) in addresses.into_iter().take(allowed) {
+                    if !self.scores.is_banned(&address.ip(), now) {
+                        self.address_book.add(address, last_seen.min(now), source);
+                    }
+                }
+
+                if self.address_book.len() > known {
+                    info!(
+                        "[{}] Learned {} new addresses, {} known",
+                        &self.address,
+                        self.address_book.len() - known,
+                        self.address_book.len()
+                    );
+                }
+            }
+
+            Payload::PrintWalletBalance { public_key_hash } => {
+                match self.blockchain.get_balance(&public_key_hash) {
+                    Ok(balance) => println!("{}", balance),
+                    Err(err) => println!("{}", err),
+                }
             }
         }
         Ok(GenericResponse::OK)
     }
 }
 
+impl Handler<MiningFinished> for Node {
+    type Result = ();
+
+    fn handle(&mut self, msg: MiningFinished, ctx: &mut Context<Self>) -> Self::Result {
+        // Results of cancelled or replaced jobs are dropped
+        match &self.mining {
+            Some(state) if state.job_id == msg.job_id => (),
+            _ => return,
+        }
+
+        let block = match msg.block {
+            Some(block) => block,
+            None => return,
+        };
+
+        self.mining = None;
+        self.handle_mined_block(block, msg.stats, ctx);
+
+        while self.mining.is_none() {
+            match self.queued_requests.pop_front() {
+                Some(request) => self.make_transaction_and_mine(request, ctx),
+                None => break,
+            }
+        }
+    }
+}
+
+impl Handler<PeerConnected> for Node {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerConnected, ctx: &mut Context<Self>) -> Self::Result {
+        match msg.dialed {
+            Some(address) => {
+                self.pending_connections.remove(&address);
+            }
+            None => self.pending_handshakes = self.pending_handshakes.saturating_sub(1),
+        }
+
+        // Peers share the node's own address with it as well
+        if msg.name == self.address {
+            info!(
+                "[{}] Closing a connection to this node at {}",
+                &self.address, msg.remote
+            );
+            if let Some(address) = msg.listen_address {
+                self.address_book.exclude(address);
+            }
+            let _ = msg.stream.shutdown(Shutdown::Both);
+            return;
+        }
+
+        if self
+            .scores
+            .is_banned(&msg.remote.ip(), Utc::now().timestamp())
+        {
+            info!(
+                "[{}] Closing a connection to banned peer {} at {}",
+                &self.address, msg.name, msg.remote
+            );
+            let _ = msg.stream.shutdown(Shutdown::Both);
+            return;
+        }
+
+        // Two nodes that dial each other at once end up with two connections
+        let connected = msg.listen_address.is_some()
+            && self
+                .peers
+                .values()
+                .any(|handle| handle.listen_address == msg.listen_address);
+        if connected {
+            info!(
+                "[{}] Already connected to {}, closing the connection at {}",
+                &self.address, msg.name, msg.remote
+            );
+            let _ = msg.stream.shutdown(Shutdown::Both);
+            return;
+        }
+
+        info!(
+            "[{}] Connected to {} at {}",
+            &self.address, msg.name, msg.remote
+        );
+
+        if let Some(address) = msg.listen_address {
+            self.address_book
+                .set_connected(address, true, Utc::now().timestamp());
+        }
+
+        let handle = Peer::new(
+            msg.name.clone(),
+            msg.remote,
+            msg.listen_address,
+            msg.stream,
+            ctx.address(),
+        )
+        .start();
+        let peer = handle.clone().recipient();
+
+        // The peer may have a chain with more work, and know other nodes
+        self.request_headers(&peer, ctx);
+
+        self.scores.expect_addresses(&peer, Utc::now().timestamp());
+        let result = peer.try_send(GenericMessage(Payload::RequestAddresses {
+            sender_addr: ctx.address().recipient(),
+        }));
+        handle_result(result, "RequestAddresses");
+
+        self.peers.insert(
+            msg.remote,
+            PeerHandle {
+                name: msg.name,
+                peer: handle,
+                recipient: peer.clone(),
+                remote: msg.remote,
+                listen_address: msg.listen_address,
+                inbound: msg.dialed.is_none(),
+            },
+        );
+        self.known_nodes.push(peer);
+    }
+}
+
+impl Handler<PeerDisconnected> for Node {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerDisconnected, ctx: &mut Context<Self>) -> Self::Result {
+        self.known_nodes.retain(|addr| addr != &msg.peer);
+        self.scores.remove(&msg.peer);
+        let banned = match self.peers.remove(&msg.remote) {
+            Some(handle) => self
+                .scores
+                .is_banned(&handle.remote.ip(), Utc::now().timestamp()),
+            None => false,
+        };
+
+        // The blocks requested from the peer are requested from the others
+        self.sync.remove_peer(&msg.peer);
+        self.request_blocks(ctx);
+
+        // The address stays in the address book, and is dialed again later
+        if let Some(address) = msg.listen_address {
+            self.address_book
+                .set_connected(address, false, Utc::now().timestamp());
+        }
+
+        info!(
+            "[{}] Disconnected from {}, {} known nodes left",
+            &self.address,
+            msg.name,
+            self.known_nodes.len()
+        );
+
+        let payload = format!(
+            r#"{{
+                "nodeId":"{}",
+                "eventId":"{:?}",
+                "details":{{
+                    "peer": "{}",
+                    "reason": {:?},
+                    "banned": {}
+                }}
+            }}"#,
+            &self.address,
+            Events::DisconnectedPeer,
+            msg.name,
+            msg.reason,
+            banned
+        );
+
+        broadcast!(self.server_addr, payload);
+    }
+}
+
+impl Handler<PeerMisbehaved> for Node {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerMisbehaved, _ctx: &mut Context<Self>) -> Self::Result {
+        self.misbehaving(&msg.peer, msg.misbehavior);
+    }
+}
+
+impl Handler<IncomingConnection> for Node {
+    type Result = ();
+
+    /// Closes connections from banned IP addresses, and connections beyond
+    /// `MAX_INBOUND_PEERS`, before the handshake
+    fn handle(&mut self, msg: IncomingConnection, ctx: &mut Context<Self>) -> Self::Result {
+        if self
+            .scores
+            .is_banned(&msg.remote.ip(), Utc::now().timestamp())
+        {
+            info!(
+                "[{}] Refusing a connection from banned address {}",
+                &self.address, msg.remote
+            );
+            let _ = msg.stream.shutdown(Shutdown::Both);
+            return;
+        }
+
+        let inbound = self.peers.values().filter(|handle| handle.inbound).count();
+        if inbound + self.pending_handshakes >= MAX_INBOUND_PEERS {
+            info!(
+                "[{}] Refusing a connection from {}, {} peers are connecting or connected to this node",
+                &self.address, msg.remote, MAX_INBOUND_PEERS
+            );
+            let _ = msg.stream.shutdown(Shutdown::Both);
+            return;
+        }
+
+        self.pending_handshakes += 1;
+        peer::accept(
+            msg.stream,
+            msg.remote,
+            self.address.clone(),
+            self.listen_port,
+            ctx.address(),
+        );
+    }
+}
+
+impl Handler<HandshakeFailed> for Node {
+    type Result = ();
+
+    fn handle(&mut self, msg: HandshakeFailed, _ctx: &mut Context<Self>) -> Self::Result {
+        trace!(
+            "[{}] Handshake with {} failed, {} handshakes in progress",
+            &self.address,
+            msg.remote,
+            self.pending_handshakes.saturating_sub(1)
+        );
+        self.pending_handshakes = self.pending_handshakes.saturating_sub(1);
+    }
+}
+
+impl Handler<PeerUnreachable> for Node {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerUnreachable, _ctx: &mut Context<Self>) -> Self::Result {
+        self.pending_connections.remove(&msg.address);
+        self.address_book.remove(&msg.address);
+    }
+}
+
 impl std::fmt::Debug for Node {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -340,3 +1674,132 @@ impl std::fmt::Debug for Node {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::script::Script;
+    use crate::blockchain::sighash::SigHashType;
+    use crate::blockchain::wallet::Wallet;
+    use crate::util::constants::VERSION;
+
+    use secp256k1::{Secp256k1, SecretKey};
+    use std::sync::{Arc, Mutex};
+
+    /// Records the messages a node sends to it
+    struct Recorder(Arc<Mutex<Vec<Payload>>>);
+
+    impl Actor for Recorder {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<GenericMessage> for Recorder {
+        type Result = Result<GenericResponse, String>;
+
+        fn handle(&mut self, msg: GenericMessage, _ctx: &mut Context<Self>) -> Self::Result {
+            self.0.lock().unwrap().push(msg.0);
+            Ok(GenericResponse::OK)
+        }
+    }
+
+    /// Returns a private key and its address, without saving a wallet file
+    fn key_pair() -> (SecretKey, Bytes) {
+        let mut rng = rand::rngs::OsRng::new().unwrap();
+        let (private_key, public_key) = Secp256k1::new().generate_keypair(&mut rng);
+        let public_key_hash =
+            Wallet::generate_sha256_ripemd160_hash(&public_key.serialize().to_vec());
+        (
+            private_key,
+            Wallet::encode_address(VERSION, &public_key_hash),
+        )
+    }
+
+    #[test]
+    fn transactions_are_requested_again_after_an_invalid_copy() {
+        let (private_key, from) = key_pair();
+        let (_, to) = key_pair();
+
+        System::new("test").block_on(async move {
+            let mut node = Node::default(String::from("test"), Server::offline().start());
+            node.create_blockchain(&from);
+
+            let mut txn = Transaction::new_unsigned(
+                &from,
+                &to,
+                5,
+                1,
+                TransactionOptions::default(),
+                &node.blockchain,
+                &Mempool::default(),
+            )
+            .unwrap();
+            node.blockchain
+                .sign_transaction(&mut txn, private_key, SigHashType::All)
+                .unwrap();
+
+            // Same id, since the id doesn't cover the signatures
+            let mut copy = txn.clone();
+            copy.inputs[0].script_sig = Script::default();
+
+            let node = node.start();
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let peer = Recorder(received.clone()).start();
+            let recipient = peer.clone().recipient::<GenericMessage>();
+            let item = InventoryItem::Transaction(txn.id.clone());
+
+            let send = |payload| node.send(GenericMessage(payload));
+            // The peer handles messages in order, so this returns once it has
+            // handled everything the node sent it before
+            let flush = || peer.send(GenericMessage(Payload::PrintInfo));
+            let requests = |received: &Arc<Mutex<Vec<Payload>>>| {
+                received
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|payload| match payload {
+                        Payload::RequestData { items, .. } => items.contains(&item),
+                        _ => false,
+                    })
+                    .count()
+            };
+
+            send(Payload::UpdateRoutingInfo {
+                addresses: vec![recipient.clone()],
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+            for (i, transaction) in vec![copy, txn].into_iter().enumerate() {
+                send(Payload::Inventory {
+                    items: vec![item.clone()],
+                    sender_addr: recipient.clone(),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+                flush().await.unwrap().unwrap();
+                assert_eq!(requests(&received), i + 1);
+
+                send(Payload::SubmitTransaction {
+                    transaction,
+                    sender_addr: Some(recipient.clone()),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            }
+
+            // The valid copy is accepted and announced
+            flush().await.unwrap().unwrap();
+            assert!(received
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|payload| match payload {
+                    Payload::Inventory { items, .. } => items.contains(&item),
+                    _ => false,
+                }));
+        });
+    }
+}
@@ -0,0 +1,401 @@
+//# Connections to nodes in other processes
+
+use crate::blockchain::BlockChain;
+use crate::network::misbehavior::Misbehavior;
+use crate::network::node::{GenericMessage, GenericResponse, Node, Payload};
+use crate::network::wire::{self, Message};
+use crate::util::constants::{HANDSHAKE_TIMEOUT, MAX_QUEUED_MESSAGES, PEER_TIMEOUT};
+
+use actix::prelude::*;
+use log::{info, warn};
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+/// Sent to a node when a connection has completed the handshake
+///
+/// `listen_address` is where the peer accepts connections, if it does, and
+/// `dialed` is the address the node connected to, if it opened the connection.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PeerConnected {
+    pub name: String,
+    pub remote: SocketAddr,
+    pub listen_address: Option<SocketAddr>,
+    pub dialed: Option<SocketAddr>,
+    pub stream: TcpStream,
+}
+
+/// Sent to a node when a connection to a peer was closed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PeerDisconnected {
+    pub peer: Recipient<GenericMessage>,
+    pub name: String,
+    pub remote: SocketAddr,
+    pub listen_address: Option<SocketAddr>,
+    pub reason: String,
+}
+
+/// Sent to a node when a peer broke the wire protocol
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PeerMisbehaved {
+    pub peer: Recipient<GenericMessage>,
+    pub misbehavior: Misbehavior,
+}
+
+/// Sent to a peer to close its connection
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Disconnect;
+
+/// Sent to a node when another node connected to it, before the handshake
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct IncomingConnection {
+    pub remote: SocketAddr,
+    pub stream: TcpStream,
+}
+
+/// Sent to a node when the handshake on an incoming connection failed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct HandshakeFailed {
+    pub remote: SocketAddr,
+}
+
+/// Sent to a node when connecting to an address failed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PeerUnreachable {
+    pub address: SocketAddr,
+}
+
+/// A node in another process, connected over TCP
+///
+/// The node sends a peer the same payloads as a node in its own process, and
+/// the peer queues them for a thread that writes them to the connection, so
+/// a slow peer doesn't hold up the node. Another thread reads the messages
+/// from the connection, and passes them to the node as payloads whose sender
+/// is the peer, so replies go back over the same connection.
+///
+/// A peer that doesn't finish a frame, or accept one, within `PEER_TIMEOUT`
+/// seconds is disconnected, and so is a peer with more than
+/// `MAX_QUEUED_MESSAGES` messages waiting to be written.
+pub struct Peer {
+    name: String,
+    remote: SocketAddr,
+    listen_address: Option<SocketAddr>,
+    stream: TcpStream,
+    node: Addr<Node>,
+    outbox: Option<SyncSender<Message>>, // none until the writer thread is started
+}
+
+impl Peer {
+    pub fn new(
+        name: String,
+        remote: SocketAddr,
+        listen_address: Option<SocketAddr>,
+        stream: TcpStream,
+        node: Addr<Node>,
+    ) -> Self {
+        Peer {
+            name,
+            remote,
+            listen_address,
+            stream,
+            node,
+            outbox: None,
+        }
+    }
+
+    /// Returns the message for a payload, if it can be sent to another process
+    fn to_message(payload: Payload) -> Option<Message> {
+        match payload {
+            Payload::SubmitTransaction { transaction, .. } => {
+                Some(Message::Transaction(transaction))
+            }
+            Payload::RequestBlockchain { .. } => Some(Message::RequestBlockchain),
+            Payload::Blockchain { blockchain, .. } => Some(Message::Blockchain(blockchain.blocks)),
+            Payload::Block { block, .. } => Some(Message::Block(block)),
+            Payload::RequestBlock { hash, .. } => Some(Message::RequestBlock { hash }),
+            Payload::RequestAddresses { .. } => Some(Message::RequestAddresses),
+            Payload::Addresses { addresses, .. } => Some(Message::Addresses(addresses)),
+            Payload::RequestHeaders { locator, .. } => Some(Message::RequestHeaders { locator }),
+            Payload::Headers { headers, .. } => Some(Message::Headers(headers)),
+            Payload::Inventory { items, .. } => Some(Message::Inventory(items)),
+            Payload::RequestData { items, .. } => Some(Message::RequestData(items)),
+            _ => None,
+        }
+    }
+
+    /// Returns the payload for a message received from `peer`
+    fn to_payload(message: Message, peer: Recipient<GenericMessage>) -> Option<Payload> {
+        match message {
+            Message::Transaction(transaction) => Some(Payload::SubmitTransaction {
+                transaction,
+                sender_addr: Some(peer),
+            }),
+            Message::RequestBlockchain => Some(Payload::RequestBlockchain { sender_addr: peer }),
+            Message::Blockchain(blocks) => {
+                // The node validates the chain before it uses it
+                let mut blockchain = BlockChain::new_placeholder();
+                blockchain.blocks = blocks;
+
+                Some(Payload::Blockchain {
+                    blockchain: Box::new(blockchain),
+                    sender_addr: peer,
+                })
+            }
+            Message::Block(block) => Some(Payload::Block {
+                block,
+                sender_addr: peer,
+            }),
+            Message::RequestBlock { hash } => Some(Payload::RequestBlock {
+                hash,
+                sender_addr: peer,
+            }),
+            Message::RequestAddresses => Some(Payload::RequestAddresses { sender_addr: peer }),
+            Message::Addresses(addresses) => Some(Payload::Addresses {
+                addresses,
+                sender_addr: peer,
+            }),
+            Message::RequestHeaders { locator } => Some(Payload::RequestHeaders {
+                locator,
+                sender_addr: peer,
+            }),
+            Message::Headers(headers) => Some(Payload::Headers {
+                headers,
+                sender_addr: peer,
+            }),
+            Message::Inventory(items) => Some(Payload::Inventory {
+                items,
+                sender_addr: peer,
+            }),
+            Message::RequestData(items) => Some(Payload::RequestData {
+                items,
+                sender_addr: peer,
+            }),
+            Message::Version { .. } | Message::Verack => None,
+        }
+    }
+}
+
+impl Actor for Peer {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        let timeout = Duration::from_secs(PEER_TIMEOUT);
+        let streams = self.stream.try_clone().and_then(|reader| {
+            let writer = self.stream.try_clone()?;
+            writer.set_write_timeout(Some(timeout))?;
+            Ok((reader, writer))
+        });
+        let (mut stream, mut writer) = match streams {
+            Ok(streams) => streams,
+            Err(err) => {
+                warn!(
+                    "[Network] Couldn't use the connection to {}: {}",
+                    self.remote, err
+                );
+                ctx.stop();
+                return;
+            }
+        };
+
+        let (outbox, queue) = mpsc::sync_channel::<Message>(MAX_QUEUED_MESSAGES);
+        self.outbox = Some(outbox);
+
+        // Ends when the peer stops, or a write fails. Shutting the connection
+        // down ends the reading thread too, which reports the disconnection.
+        let name = self.name.clone();
+        thread::spawn(move || {
+            for message in queue.iter() {
+                if let Err(err) = wire::write_message(&mut writer, &message) {
+                    warn!("[Network] Couldn't send a message to {}: {}", name, err);
+                    let _ = writer.shutdown(Shutdown::Both);
+                    break;
+                }
+            }
+        });
+
+        let peer = ctx.address().recipient();
+        let node = self.node.clone();
+        let name = self.name.clone();
+        let remote = self.remote;
+        let listen_address = self.listen_address;
+
+        thread::spawn(move || {
+            let reason = loop {
+                let message = match wire::read_message_within(&mut stream, timeout) {
+                    Ok(message) => message,
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                        break "connection closed".to_string();
+                    }
+                    Err(err) => {
+                        // The rest of the stream can't be framed any more
+                        if err.kind() == io::ErrorKind::InvalidData {
+                            node.do_send(PeerMisbehaved {
+                                peer: peer.clone(),
+                                misbehavior: Misbehavior::MalformedMessage(err.to_string()),
+                            });
+                        }
+                        break err.to_string();
+                    }
+                };
+
+                match Peer::to_payload(message, peer.clone()) {
+                    Some(payload) => node.do_send(GenericMessage(payload)),
+                    None => node.do_send(PeerMisbehaved {
+                        peer: peer.clone(),
+                        misbehavior: Misbehavior::UnexpectedMessage,
+                    }),
+                }
+            };
+
+            info!(
+                "[Network] Connection to {} at {} closed: {}",
+                name, remote, reason
+            );
+            node.do_send(PeerDisconnected {
+                peer,
+                name,
+                remote,
+                listen_address,
+                reason,
+            });
+        });
+    }
+
+    fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl Handler<Disconnect> for Peer {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Disconnect, ctx: &mut Context<Self>) -> Self::Result {
+        ctx.stop();
+    }
+}
+
+impl Handler<GenericMessage> for Peer {
+    type Result = Result<GenericResponse, String>;
+
+    fn handle(&mut self, msg: GenericMessage, ctx: &mut Context<Self>) -> Self::Result {
+        let message = match Peer::to_message(msg.0) {
+            Some(message) => message,
+            None => return Err(format!("{} is in another process", self.name)),
+        };
+
+        let result = match &self.outbox {
+            Some(outbox) => outbox.try_send(message),
+            None => return Err(format!("{} is not connected", self.name)),
+        };
+
+        match result {
+            Ok(()) => Ok(GenericResponse::OK),
+            Err(TrySendError::Full(_)) => {
+                warn!(
+                    "[Network] {} has {} messages waiting, disconnecting",
+                    self.name, MAX_QUEUED_MESSAGES
+                );
+                ctx.stop();
+                Err(format!("{} is too slow", self.name))
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                ctx.stop();
+                Err(format!("{} is disconnected", self.name))
+            }
+        }
+    }
+}
+
+/// Performs the handshake on a new connection, and hands it to the node
+///
+/// `dialed` is the address the node connected to, for outgoing connections.
+fn establish(
+    mut stream: TcpStream,
+    dialed: Option<SocketAddr>,
+    name: &str,
+    listen_port: u16,
+    node: &Addr<Node>,
+) -> io::Result<()> {
+    let remote = stream.peer_addr()?;
+    let (peer_name, peer_port) = wire::handshake(&mut stream, name, listen_port)?;
+
+    let listen_address = match (dialed, peer_port) {
+        (Some(address), _) => Some(address),
+        (None, 0) => None,
+        (None, port) => Some(SocketAddr::new(remote.ip(), port)),
+    };
+
+    node.do_send(PeerConnected {
+        name: peer_name,
+        remote,
+        listen_address,
+        dialed,
+        stream,
+    });
+
+    Ok(())
+}
+
+/// Accepts connections from other nodes on a background thread
+///
+/// The node is sent an `IncomingConnection` for each connection, and decides
+/// whether to `accept` it.
+pub fn listen(listener: TcpListener, node: Addr<Node>) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| Ok((stream.peer_addr()?, stream)));
+            match result {
+                Ok((remote, stream)) => node.do_send(IncomingConnection { remote, stream }),
+                Err(err) => warn!("[Network] Error accepting a connection: {}", err),
+            }
+        }
+    });
+}
+
+/// Performs the handshake on an incoming connection on a background thread,
+/// so a slow handshake doesn't hold up the other connections
+///
+/// `listen_port` is the port the node listens on, which it tells its peers.
+/// The node is sent a `HandshakeFailed` if the handshake fails.
+pub fn accept(
+    stream: TcpStream,
+    remote: SocketAddr,
+    name: String,
+    listen_port: u16,
+    node: Addr<Node>,
+) {
+    thread::spawn(move || {
+        if let Err(err) = establish(stream, None, &name, listen_port, &node) {
+            warn!(
+                "[Network] Handshake with incoming peer {} failed: {}",
+                remote, err
+            );
+            node.do_send(HandshakeFailed { remote });
+        }
+    });
+}
+
+/// Connects to the node at `address` on a background thread
+///
+/// The node is sent a `PeerUnreachable` if the connection or the handshake
+/// fails.
+pub fn connect(address: SocketAddr, name: String, listen_port: u16, node: Addr<Node>) {
+    thread::spawn(move || {
+        let result = TcpStream::connect_timeout(&address, Duration::from_secs(HANDSHAKE_TIMEOUT))
+            .and_then(|stream| establish(stream, Some(address), &name, listen_port, &node));
+
+        if let Err(err) = result {
+            warn!("[Network] Couldn't connect to {}: {}", address, err);
+            node.do_send(PeerUnreachable { address });
+        }
+    });
+}
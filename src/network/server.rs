@@ -6,7 +6,7 @@ use std::net::TcpListener;
 use tungstenite::{accept, Message};
 
 pub struct Server {
-    listener: TcpListener,
+    listener: Option<TcpListener>,
     connections: Vec<tungstenite::protocol::WebSocket<std::net::TcpStream>>,
 }
 
@@ -15,17 +15,31 @@ impl Server {
         let listener = TcpListener::bind("127.0.0.1:3012").unwrap();
 
         Server {
-            listener,
+            listener: Some(listener),
+            connections: vec![],
+        }
+    }
+
+    /// Creates a server that doesn't accept web socket connections, so that
+    /// several nodes can run on the same machine
+    pub fn offline() -> Self {
+        Server {
+            listener: None,
             connections: vec![],
         }
     }
 
     pub fn listen(&mut self) {
+        let listener = match &self.listener {
+            Some(listener) => listener,
+            None => return,
+        };
+
         println!(
             "The TCP server is listening for incoming web socket connections on 127.0.0.1:3012"
         );
 
-        for stream in self.listener.incoming() {
+        for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
                     let socket = accept(stream).unwrap();
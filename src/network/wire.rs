@@ -0,0 +1,396 @@
+//# The protocol that nodes in separate processes speak over TCP
+
+use crate::blockchain::block::{Block, BlockHeader};
+use crate::blockchain::transaction::Transaction;
+use crate::network::inventory::InventoryItem;
+use crate::util::constants::{HANDSHAKE_TIMEOUT, MAX_MESSAGE_SIZE, PROTOCOL_VERSION};
+use crate::util::encoding::{DecodeError, Decoder, Encoder};
+use crate::util::traits::{Decodable, Hashable};
+use crate::util::types::Bytes;
+
+use std::convert::TryFrom;
+use std::io::{self, prelude::*};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+const MAGIC: &[u8; 4] = b"RBCN";
+const FRAME_HEADER_LENGTH: usize = 16; // magic + protocol version + payload length + checksum
+
+const VERSION: u8 = 0x00;
+const VERACK: u8 = 0x01;
+const TRANSACTION: u8 = 0x02;
+const REQUEST_BLOCKCHAIN: u8 = 0x03;
+const BLOCKCHAIN: u8 = 0x04;
+const BLOCK: u8 = 0x05;
+const REQUEST_BLOCK: u8 = 0x06;
+const REQUEST_ADDRESSES: u8 = 0x07;
+const ADDRESSES: u8 = 0x08;
+const REQUEST_HEADERS: u8 = 0x09;
+const HEADERS: u8 = 0x0a;
+const INVENTORY: u8 = 0x0b;
+const REQUEST_DATA: u8 = 0x0c;
+
+/// A message between two nodes
+///
+/// Apart from the handshake, the messages mirror the payloads that nodes send
+/// each other in the simulation. Replies go back over the same connection, so
+/// the messages don't carry the sender.
+///
+/// `Version` carries the port the node listens on, or 0 if it doesn't, and
+/// `Addresses` carries listening addresses with when they were last seen.
+#[derive(Clone, Debug)]
+pub enum Message {
+    Version { node: String, listen_port: u16 },
+    Verack,
+    Transaction(Transaction),
+    RequestBlockchain,
+    Blockchain(Vec<Block>),
+    Block(Block),
+    RequestBlock { hash: Bytes },
+    RequestAddresses,
+    Addresses(Vec<(SocketAddr, i64)>),
+    RequestHeaders { locator: Vec<Bytes> },
+    Headers(Vec<BlockHeader>),
+    Inventory(Vec<InventoryItem>),
+    RequestData(Vec<InventoryItem>),
+}
+
+impl Hashable for Message {
+    fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+
+        match self {
+            Message::Version { node, listen_port } => {
+                encoder.put_u8(VERSION);
+                encoder.put_bytes(node.as_bytes());
+                encoder.put_u32(u32::from(*listen_port));
+            }
+            Message::Verack => encoder.put_u8(VERACK),
+            Message::Transaction(txn) => {
+                encoder.put_u8(TRANSACTION);
+                encoder.put_encoded(&txn.encode());
+            }
+            Message::RequestBlockchain => encoder.put_u8(REQUEST_BLOCKCHAIN),
+            Message::Blockchain(blocks) => {
+                encoder.put_u8(BLOCKCHAIN);
+                encoder.put_len(blocks.len());
+                for block in blocks.iter() {
+                    encoder.put_encoded(&block.encode());
+                }
+            }
+            Message::Block(block) => {
+                encoder.put_u8(BLOCK);
+                encoder.put_encoded(&block.encode());
+            }
+            Message::RequestBlock { hash } => {
+                encoder.put_u8(REQUEST_BLOCK);
+                encoder.put_bytes(hash);
+            }
+            Message::RequestAddresses => encoder.put_u8(REQUEST_ADDRESSES),
+            Message::Addresses(addresses) => {
+                encoder.put_u8(ADDRESSES);
+                encoder.put_len(addresses.len());
+                for (address, last_seen) in addresses.iter() {
+                    encoder.put_bytes(address.to_string().as_bytes());
+                    encoder.put_i64(*last_seen);
+                }
+            }
+            Message::RequestHeaders { locator } => {
+                encoder.put_u8(REQUEST_HEADERS);
+                encoder.put_len(locator.len());
+                for hash in locator.iter() {
+                    encoder.put_bytes(hash);
+                }
+            }
+            Message::Headers(headers) => {
+                encoder.put_u8(HEADERS);
+                encoder.put_len(headers.len());
+                for header in headers.iter() {
+                    encoder.put_encoded(&header.encode());
+                }
+            }
+            Message::Inventory(items) => {
+                encoder.put_u8(INVENTORY);
+                put_inventory(&mut encoder, items);
+            }
+            Message::RequestData(items) => {
+                encoder.put_u8(REQUEST_DATA);
+                put_inventory(&mut encoder, items);
+            }
+        }
+
+        encoder.finish()
+    }
+}
+
+impl Decodable for Message {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let message = match decoder.get_u8()? {
+            VERSION => Message::Version {
+                node: String::from_utf8_lossy(&decoder.get_bytes()?).into_owned(),
+                listen_port: get_port(decoder)?,
+            },
+            VERACK => Message::Verack,
+            TRANSACTION => Message::Transaction(Transaction::decode_from(decoder)?),
+            REQUEST_BLOCKCHAIN => Message::RequestBlockchain,
+            BLOCKCHAIN => {
+                let mut blocks = Vec::new();
+                for _ in 0..decoder.get_len()? {
+                    blocks.push(Block::decode_from(decoder)?);
+                }
+                Message::Blockchain(blocks)
+            }
+            BLOCK => Message::Block(Block::decode_from(decoder)?),
+            REQUEST_BLOCK => Message::RequestBlock {
+                hash: decoder.get_bytes()?,
+            },
+            REQUEST_ADDRESSES => Message::RequestAddresses,
+            ADDRESSES => {
+                let mut addresses = Vec::new();
+                for _ in 0..decoder.get_len()? {
+                    let address = String::from_utf8_lossy(&decoder.get_bytes()?).into_owned();
+                    let address = address
+                        .parse::<SocketAddr>()
+                        .map_err(|_| DecodeError::InvalidAddress { address })?;
+                    addresses.push((address, decoder.get_i64()?));
+                }
+                Message::Addresses(addresses)
+            }
+            REQUEST_HEADERS => {
+                let mut locator = Vec::new();
+                for _ in 0..decoder.get_len()? {
+                    locator.push(decoder.get_bytes()?);
+                }
+                Message::RequestHeaders { locator }
+            }
+            HEADERS => {
+                let mut headers = Vec::new();
+                for _ in 0..decoder.get_len()? {
+                    headers.push(BlockHeader::decode_from(decoder)?);
+                }
+                Message::Headers(headers)
+            }
+            INVENTORY => Message::Inventory(get_inventory(decoder)?),
+            REQUEST_DATA => Message::RequestData(get_inventory(decoder)?),
+            command => return Err(DecodeError::UnknownMessage { command }),
+        };
+
+        Ok(message)
+    }
+}
+
+fn put_inventory(encoder: &mut Encoder, items: &[InventoryItem]) {
+    encoder.put_len(items.len());
+    for item in items.iter() {
+        encoder.put_encoded(&item.encode());
+    }
+}
+
+fn get_inventory(decoder: &mut Decoder) -> Result<Vec<InventoryItem>, DecodeError> {
+    let mut items = Vec::new();
+    for _ in 0..decoder.get_len()? {
+        items.push(InventoryItem::decode_from(decoder)?);
+    }
+    Ok(items)
+}
+
+/// Decodes a port, which is encoded as a u32
+fn get_port(decoder: &mut Decoder) -> Result<u16, DecodeError> {
+    let port = decoder.get_u32()?;
+    u16::try_from(port).map_err(|_| DecodeError::InvalidPort { port })
+}
+
+fn checksum(payload: &[u8]) -> Bytes {
+    crypto_hash::digest(crypto_hash::Algorithm::SHA256, payload)[0..4].to_vec()
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Writes a message as one frame
+///
+/// A frame is `MAGIC`, the protocol version (4 bytes, little endian), the
+/// length of the encoded message (4 bytes, little endian), the first 4 bytes
+/// of the SHA256 hash of the encoded message, and the encoded message.
+pub fn write_message<W: Write>(writer: &mut W, message: &Message) -> io::Result<()> {
+    let payload = message.encode();
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LENGTH + payload.len());
+    frame.extend(MAGIC);
+    frame.extend(&PROTOCOL_VERSION.to_le_bytes());
+    frame.extend(&(payload.len() as u32).to_le_bytes());
+    frame.extend(checksum(&payload));
+    frame.extend(payload);
+
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// Reads the next frame written by `write_message`
+///
+/// Frames of other protocol versions, and frames that are too large or
+/// corrupted, are rejected with an `InvalidData` error.
+pub fn read_message<R: Read>(reader: &mut R) -> io::Result<Message> {
+    let mut header = [0u8; FRAME_HEADER_LENGTH];
+    reader.read_exact(&mut header)?;
+
+    if &header[0..4] != MAGIC {
+        return Err(invalid_data("invalid frame magic".to_string()));
+    }
+
+    let mut field = [0u8; 4];
+
+    field.copy_from_slice(&header[4..8]);
+    let version = u32::from_le_bytes(field);
+    if version != PROTOCOL_VERSION {
+        return Err(invalid_data(format!(
+            "unsupported protocol version {}, expected {}",
+            version, PROTOCOL_VERSION
+        )));
+    }
+
+    field.copy_from_slice(&header[8..12]);
+    let length = u32::from_le_bytes(field) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(invalid_data(format!(
+            "message of {} bytes exceeds the maximum of {}",
+            length, MAX_MESSAGE_SIZE
+        )));
+    }
+
+    // The buffer grows as the payload arrives, rather than to the length the
+    // header claims
+    let mut payload = Vec::new();
+    reader
+        .by_ref()
+        .take(length as u64)
+        .read_to_end(&mut payload)?;
+    if payload.len() < length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed in the middle of a frame",
+        ));
+    }
+
+    if header[12..16] != checksum(&payload)[..] {
+        return Err(invalid_data("message checksum mismatch".to_string()));
+    }
+
+    Message::decode(&payload).map_err(|err| invalid_data(err.to_string()))
+}
+
+/// Waits for the next frame on `stream` for as long as it takes, then reads
+/// it within `timeout`
+///
+/// Peers may stay quiet, but not stop in the middle of a frame.
+pub fn read_message_within(stream: &mut TcpStream, timeout: Duration) -> io::Result<Message> {
+    stream.set_read_timeout(None)?;
+    if stream.peek(&mut [0u8; 1])? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed",
+        ));
+    }
+
+    let deadline = Instant::now() + timeout;
+    read_message(&mut Deadline { stream, deadline })
+}
+
+/// Reads from a stream until a deadline
+///
+/// A stream's read timeout applies to each read on its own, so a peer that
+/// sends a byte at a time could take as long as it likes. Each read here only
+/// waits for the time that's left.
+struct Deadline<'a> {
+    stream: &'a mut TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "deadline passed"));
+        }
+
+        self.stream.set_read_timeout(Some(self.deadline - now))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Introduces a node to the node at the other end of `stream`, and returns
+/// the name of the other node and the port it listens on
+///
+/// Both sides send a `Version` and answer the other side's with a `Verack`.
+/// As every frame carries the protocol version, nodes that speak different
+/// versions fail here. The handshake has to finish within
+/// `HANDSHAKE_TIMEOUT` seconds.
+pub fn handshake(
+    stream: &mut TcpStream,
+    node: &str,
+    listen_port: u16,
+) -> io::Result<(String, u16)> {
+    let deadline = Instant::now() + Duration::from_secs(HANDSHAKE_TIMEOUT);
+    stream.set_write_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT)))?;
+
+    write_message(
+        stream,
+        &Message::Version {
+            node: node.to_string(),
+            listen_port,
+        },
+    )?;
+
+    let peer = match read_message(&mut Deadline { stream, deadline })? {
+        Message::Version { node, listen_port } => (node, listen_port),
+        _ => return Err(invalid_data("expected a version message".to_string())),
+    };
+
+    write_message(stream, &Message::Verack)?;
+
+    match read_message(&mut Deadline { stream, deadline })? {
+        Message::Verack => (),
+        _ => return Err(invalid_data("expected a verack message".to_string())),
+    }
+
+    stream.set_read_timeout(None)?;
+    Ok(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a frame with `payload`, whose header claims it's `length`
+    /// bytes long
+    fn frame(payload: &[u8], length: u32) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend(MAGIC);
+        frame.extend(&PROTOCOL_VERSION.to_le_bytes());
+        frame.extend(&length.to_le_bytes());
+        frame.extend(checksum(payload));
+        frame.extend(payload);
+        frame
+    }
+
+    #[test]
+    fn ports_out_of_range_are_rejected() {
+        let mut encoder = Encoder::new();
+        encoder.put_u8(VERSION);
+        encoder.put_bytes(b"node");
+        encoder.put_u32(65536 + 80);
+        let payload = encoder.finish();
+
+        let frame = frame(&payload, payload.len() as u32);
+        let err = read_message(&mut &frame[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn frames_shorter_than_their_header_claims_are_rejected() {
+        let frame = frame(&Message::Verack.encode(), MAX_MESSAGE_SIZE as u32);
+        let err = read_message(&mut &frame[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -1,11 +1,267 @@
 pub mod traits {
+    use super::encoding::{DecodeError, Decoder};
+
     pub trait Hashable {
+        /// Returns the canonical binary encoding, see `encoding::Encoder`
         fn encode(&self) -> Vec<u8>;
 
         fn hash(&self) -> Vec<u8> {
             crypto_hash::digest(crypto_hash::Algorithm::SHA256, &self.encode())
         }
     }
+
+    /// The inverse of `Hashable::encode`
+    pub trait Decodable: Sized {
+        fn decode_from(decoder: &mut Decoder) -> Result<Self, DecodeError>;
+
+        /// Decodes a value that takes up all of `bytes`
+        fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+            let mut decoder = Decoder::new(bytes);
+            let value = Self::decode_from(&mut decoder)?;
+            decoder.finish()?;
+            Ok(value)
+        }
+    }
+}
+
+pub mod encoding {
+    use super::types::Bytes;
+    use std::convert::TryInto;
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum DecodeError {
+        UnexpectedEnd { needed: usize, remaining: usize },
+        TrailingBytes { remaining: usize },
+        UnknownOpcode { opcode: u8 },
+        UnknownMessage { command: u8 },
+        InvalidAddress { address: String },
+        UnknownInventoryType { kind: u8 },
+        InvalidPort { port: u32 },
+    }
+
+    impl std::fmt::Display for DecodeError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                Self::UnexpectedEnd { needed, remaining } => write!(
+                    f,
+                    "unexpected end of data: needed {} bytes, {} remaining",
+                    needed, remaining
+                ),
+                Self::TrailingBytes { remaining } => {
+                    write!(f, "{} unexpected bytes after the encoded value", remaining)
+                }
+                Self::UnknownOpcode { opcode } => {
+                    write!(f, "unknown script opcode {:#04x}", opcode)
+                }
+                Self::UnknownMessage { command } => {
+                    write!(f, "unknown message type {:#04x}", command)
+                }
+                Self::InvalidAddress { address } => {
+                    write!(f, "invalid network address {:?}", address)
+                }
+                Self::UnknownInventoryType { kind } => {
+                    write!(f, "unknown inventory type {:#04x}", kind)
+                }
+                Self::InvalidPort { port } => write!(f, "invalid port {}", port),
+            }
+        }
+    }
+
+    /// Writes values in the canonical binary encoding
+    ///
+    /// Integers are written in little endian. Byte vectors and lists are
+    /// prefixed with their length as a u32, so every encoded value is self
+    /// delimiting and concatenated values can't be confused with each other.
+    #[derive(Default)]
+    pub struct Encoder {
+        bytes: Bytes,
+    }
+
+    impl Encoder {
+        pub fn new() -> Self {
+            Encoder::default()
+        }
+
+        pub fn put_u8(&mut self, value: u8) {
+            self.bytes.push(value);
+        }
+
+        pub fn put_u32(&mut self, value: u32) {
+            self.bytes.extend(&value.to_le_bytes());
+        }
+
+        pub fn put_i32(&mut self, value: i32) {
+            self.bytes.extend(&value.to_le_bytes());
+        }
+
+        pub fn put_i64(&mut self, value: i64) {
+            self.bytes.extend(&value.to_le_bytes());
+        }
+
+        pub fn put_u64(&mut self, value: u64) {
+            self.bytes.extend(&value.to_le_bytes());
+        }
+
+        pub fn put_u128(&mut self, value: u128) {
+            self.bytes.extend(&value.to_le_bytes());
+        }
+
+        /// Writes the number of items of a list that follows
+        pub fn put_len(&mut self, len: usize) {
+            self.bytes.extend(&(len as u32).to_le_bytes());
+        }
+
+        pub fn put_bytes(&mut self, bytes: &[u8]) {
+            self.put_len(bytes.len());
+            self.bytes.extend(bytes);
+        }
+
+        /// Writes a value that is already encoded
+        pub fn put_encoded(&mut self, bytes: &[u8]) {
+            self.bytes.extend(bytes);
+        }
+
+        pub fn finish(self) -> Bytes {
+            self.bytes
+        }
+    }
+
+    /// Reads values written by an `Encoder`
+    pub struct Decoder<'a> {
+        data: &'a [u8],
+        position: usize,
+    }
+
+    impl<'a> Decoder<'a> {
+        pub fn new(data: &'a [u8]) -> Self {
+            Decoder { data, position: 0 }
+        }
+
+        fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+            let remaining = self.data.len() - self.position;
+            if n > remaining {
+                return Err(DecodeError::UnexpectedEnd {
+                    needed: n,
+                    remaining,
+                });
+            }
+
+            let bytes = &self.data[self.position..(self.position + n)];
+            self.position += n;
+            Ok(bytes)
+        }
+
+        pub fn get_u8(&mut self) -> Result<u8, DecodeError> {
+            Ok(self.take(1)?[0])
+        }
+
+        pub fn get_u32(&mut self) -> Result<u32, DecodeError> {
+            Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+        }
+
+        pub fn get_i32(&mut self) -> Result<i32, DecodeError> {
+            Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+        }
+
+        pub fn get_i64(&mut self) -> Result<i64, DecodeError> {
+            Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+        }
+
+        pub fn get_u64(&mut self) -> Result<u64, DecodeError> {
+            Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+        }
+
+        pub fn get_u128(&mut self) -> Result<u128, DecodeError> {
+            Ok(u128::from_le_bytes(self.take(16)?.try_into().unwrap()))
+        }
+
+        pub fn get_len(&mut self) -> Result<usize, DecodeError> {
+            Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+        }
+
+        pub fn get_bytes(&mut self) -> Result<Bytes, DecodeError> {
+            let len = self.get_len()?;
+            Ok(self.take(len)?.to_vec())
+        }
+
+        /// Checks that all of the data was read
+        pub fn finish(&self) -> Result<(), DecodeError> {
+            match self.data.len() - self.position {
+                0 => Ok(()),
+                remaining => Err(DecodeError::TrailingBytes { remaining }),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn values_round_trip() {
+            let mut encoder = Encoder::new();
+            encoder.put_u8(0xab);
+            encoder.put_u32(u32::max_value());
+            encoder.put_i32(-42);
+            encoder.put_i64(i64::min_value());
+            encoder.put_u64(1 << 40);
+            encoder.put_u128(u128::max_value() - 1);
+            encoder.put_len(7);
+            encoder.put_bytes(&[1, 2, 3]);
+            encoder.put_bytes(&[]);
+            let bytes = encoder.finish();
+
+            let mut decoder = Decoder::new(&bytes);
+            assert_eq!(decoder.get_u8(), Ok(0xab));
+            assert_eq!(decoder.get_u32(), Ok(u32::max_value()));
+            assert_eq!(decoder.get_i32(), Ok(-42));
+            assert_eq!(decoder.get_i64(), Ok(i64::min_value()));
+            assert_eq!(decoder.get_u64(), Ok(1 << 40));
+            assert_eq!(decoder.get_u128(), Ok(u128::max_value() - 1));
+            assert_eq!(decoder.get_len(), Ok(7));
+            assert_eq!(decoder.get_bytes(), Ok(vec![1, 2, 3]));
+            assert_eq!(decoder.get_bytes(), Ok(vec![]));
+            assert_eq!(decoder.finish(), Ok(()));
+        }
+
+        #[test]
+        fn truncated_values_are_rejected() {
+            let mut decoder = Decoder::new(&[1, 2, 3]);
+            assert_eq!(
+                decoder.get_u32(),
+                Err(DecodeError::UnexpectedEnd {
+                    needed: 4,
+                    remaining: 3
+                })
+            );
+        }
+
+        #[test]
+        fn lengths_past_the_end_are_rejected() {
+            let mut encoder = Encoder::new();
+            encoder.put_len(1000);
+            encoder.put_u8(0);
+            let bytes = encoder.finish();
+
+            assert_eq!(
+                Decoder::new(&bytes).get_bytes(),
+                Err(DecodeError::UnexpectedEnd {
+                    needed: 1000,
+                    remaining: 1
+                })
+            );
+        }
+
+        #[test]
+        fn unread_bytes_are_rejected() {
+            let mut decoder = Decoder::new(&[1, 2]);
+            decoder.get_u8().unwrap();
+            assert_eq!(
+                decoder.finish(),
+                Err(DecodeError::TrailingBytes { remaining: 1 })
+            );
+        }
+    }
 }
 
 pub mod constants {
@@ -13,13 +269,73 @@ pub mod constants {
     pub const VERSION: u8 = 0x00;
     // pub const DIFFICULTY: u128 = 0x0000ffffffffffffffffffffffffffff;
     pub const DIFFICULTY: u128 = 0x0fffffffffffffffffffffffffffffff; // during development
-    pub const BLOCK_MEMORY_POOL_SIZE: usize = 2;
+    pub const SCRIPT_VERSION: u8 = 0x05; // the version of pay to script hash addresses
+    pub const TARGET_BLOCK_TIME: i64 = 10; // seconds
+    pub const RETARGET_INTERVAL: i32 = 10; // blocks
+    pub const MAX_RETARGET_FACTOR: u128 = 4;
+    pub const INITIAL_SUBSIDY: i32 = 20;
+    pub const HALVING_INTERVAL: i32 = 100; // blocks
+    pub const TERMINAL_SUBSIDY: Option<i32> = None; // the subsidy never falls below this
+
+    // pub const COINBASE_MATURITY: i32 = 100; // blocks
+    pub const COINBASE_MATURITY: i32 = 1; // during development, so wallets can spend rewards right away
+    pub const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60; // seconds
+    pub const MAX_ORPHAN_BLOCKS: usize = 64;
+    pub const ORPHAN_BLOCK_EXPIRY: i64 = 20 * 60; // seconds
+    pub const MAX_MEMPOOL_TRANSACTIONS: usize = 5000;
+    pub const MAX_BLOCK_SIZE: usize = 100_000; // bytes, in the binary encoding
+    pub const MINING_THREADS: usize = 2;
+    pub const MAX_MULTISIG_KEYS: usize = 20;
+    pub const LOCKTIME_THRESHOLD: i64 = 500_000_000; // lock times below are heights, above timestamps
+    pub const SEQUENCE_FINAL: u32 = 0xffffffff;
+    pub const SEQUENCE_MAX_REPLACEABLE: u32 = 0xfffffffd; // inputs with a sequence up to this signal replace-by-fee
+    pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31; // the input has no relative lock
+    pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22; // the relative lock is in seconds, not blocks
+    pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+    pub const PROTOCOL_VERSION: u32 = 4;
+    pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024; // bytes, a whole blockchain fits
+    pub const HANDSHAKE_TIMEOUT: u64 = 10; // seconds
+    pub const PEER_TIMEOUT: u64 = 30; // seconds a peer has to finish a frame it started, and to accept one
+    pub const MAX_QUEUED_MESSAGES: usize = 100; // messages waiting to be written to a peer
+    pub const TARGET_PEERS: usize = 8; // nodes connect to more peers while they have fewer
+    pub const MAX_INBOUND_PEERS: usize = 32; // connections other nodes may open to a node, with the handshakes in progress
+    pub const MAX_KNOWN_ADDRESSES: usize = 1000;
+    pub const MAX_ADDRESSES_PER_MESSAGE: usize = 100;
+    pub const MAX_ADDRESSES_PER_SOURCE: usize = 64; // addresses from the peers of one network in the address book
+    pub const ADDRESS_RATE_LIMIT: f64 = 0.1; // addresses per second a peer may send, after the first message
+    pub const ADDRESS_EXPIRY: i64 = 60 * 60; // seconds
+    pub const PEER_MAINTENANCE_INTERVAL: u64 = 10; // seconds
+    pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+    pub const BLOCK_DOWNLOAD_WINDOW: usize = 32; // blocks past the tip requested at once, fits the orphan pool
+    pub const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 8;
+    pub const BLOCK_DOWNLOAD_TIMEOUT: i64 = 30; // seconds
+    pub const MAX_INVENTORY_PER_MESSAGE: usize = 1000;
+    pub const MAX_SEEN_INVENTORY: usize = 50_000;
+    pub const INVENTORY_REQUEST_TIMEOUT: i64 = 30; // seconds
+    pub const BAN_SCORE: u32 = 100; // peers are banned once their misbehavior adds up to this
+    pub const BAN_DURATION: i64 = 24 * 60 * 60; // seconds
+    pub const REQUEST_BLOCKCHAIN_INTERVAL: i64 = 60; // seconds a peer has to wait between requesting or sending the blockchain
 }
 
 pub mod types {
     pub type Bytes = Vec<u8>;
 }
 
+/// Serializes byte vectors as hex strings, for use with `#[serde(with)]`
+pub mod hex_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        hex::decode(&s)
+            .map_err(|err| D::Error::custom(format!("invalid hex string {:?}: {}", s, err)))
+    }
+}
+
 pub mod helper_functions {
     use log::warn;
 
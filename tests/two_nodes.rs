@@ -0,0 +1,97 @@
+//# Two nodes in separate processes, one of which mines, sync over TCP
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SYNC_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Kills the node processes when the test ends, also when it fails
+struct Nodes(Vec<Child>);
+
+impl Drop for Nodes {
+    fn drop(&mut self) {
+        for node in self.0.iter_mut() {
+            let _ = node.kill();
+            let _ = node.wait();
+        }
+    }
+}
+
+/// Returns a port that nothing listens on
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Starts a node in `dir`, where it keeps its log and wallet files
+fn start_node(dir: &Path, name: &str, port: u16, seeds: &[String]) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_rust-blockchain"))
+        .arg("node")
+        .arg(name)
+        .arg(port.to_string())
+        .args(seeds)
+        .current_dir(dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+/// Returns the hashes of the blocks a node logged adding to its blockchain
+fn added_blocks(dir: &Path, name: &str) -> HashSet<String> {
+    let log = fs::read_to_string(dir.join("log").join(format!("{}.log", name))).unwrap_or_default();
+    log.lines()
+        .filter_map(|line| line.split("Adding block ").nth(1))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .map(|hash| hash.to_string())
+        .collect()
+}
+
+fn test_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rust-blockchain-sync-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("log")).unwrap();
+    fs::create_dir_all(dir.join("tmp")).unwrap();
+    dir
+}
+
+#[test]
+fn a_node_downloads_the_blocks_of_its_seed() {
+    let dir = test_dir();
+    let miner_port = free_port();
+    let follower_port = free_port();
+
+    let mut nodes = Nodes(vec![start_node(&dir, "A", miner_port, &[])]);
+    thread::sleep(Duration::from_secs(1));
+    let seed = format!("127.0.0.1:{}", miner_port);
+    nodes.0.push(start_node(&dir, "B", follower_port, &[seed]));
+
+    // The follower doesn't mine, so every block it adds came from the miner
+    let start = Instant::now();
+    loop {
+        let mined = added_blocks(&dir, "A");
+        let synced = added_blocks(&dir, "B");
+        if !synced.is_empty() && synced.is_subset(&mined) {
+            break;
+        }
+
+        assert!(
+            start.elapsed() < SYNC_TIMEOUT,
+            "B added {} blocks, of which {} were mined by A",
+            synced.len(),
+            synced.intersection(&mined).count()
+        );
+        thread::sleep(Duration::from_millis(500));
+    }
+
+    drop(nodes);
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -175,8 +175,7 @@ function App() {
                                   >
                                     <p>ID: {ip.id}</p>
                                     <p>Out: {ip.out}</p>
-                                    <p>Signature: {ip.signature}</p>
-                                    <p>Public Key: {ip.publicKey}</p>
+                                    <p>Script Sig: {ip.scriptSig}</p>
                                   </div>
                                 );
                               })}
@@ -189,7 +188,7 @@ function App() {
                                     key={op_idx}
                                   >
                                     <p>Value: {op.value}</p>
-                                    <p>Public Key: {op.publicKeyHash}</p>
+                                    <p>Script Pub Key: {op.scriptPubKey}</p>
                                   </div>
                                 );
                               })}
@@ -13,19 +13,20 @@ export interface Wallet {
 export interface TransactionInput {
   id: string;
   out: number;
-  signature: string;
-  publicKey: string;
+  scriptSig: string;
+  sequence: number;
 }
 
 export interface TransactionOutput {
   value: number;
-  publicKeyHash: string;
+  scriptPubKey: string;
 }
 
 export interface Transaction {
   id: string;
   inputs: TransactionInput[];
   outputs: TransactionOutput[];
+  lockTime: number;
 }
 
 export interface Block {
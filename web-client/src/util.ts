@@ -6,16 +6,13 @@ import {
   Blockchain,
 } from "./models";
 
-function toHexString(byteArray: Array<32>) {
-  return Array.from(byteArray, function (byte) {
-    return ("0" + (byte & 0xff).toString(16)).slice(-2);
-  }).join("");
-}
+// Byte fields (hashes, keys, signatures) are sent as hex encoded strings, and
+// scripts as their operations separated by spaces
 
 export function parseBlock(data: any) {
   let index: number = data.index;
   let timestamp: number = data.timestamp;
-  let hash: string = toHexString(data.hash);
+  let hash: string = data.hash;
   let proofOfWork: number = data.difficulty;
   let nonce: number = data.nonce;
 
@@ -24,10 +21,10 @@ export function parseBlock(data: any) {
       let transactionInputs: TransactionInput[] = txn.inputs.map(
         (i: any, _idx: any) => {
           let ip: TransactionInput = {
-            id: toHexString(i.id),
+            id: i.id,
             out: i.out,
-            signature: toHexString(i.signature),
-            publicKey: toHexString(i.public_key),
+            scriptSig: i.script_sig,
+            sequence: i.sequence,
           };
 
           return ip;
@@ -38,7 +35,7 @@ export function parseBlock(data: any) {
         (i: any, _idx: any) => {
           let op: TransactionOutput = {
             value: i.value,
-            publicKeyHash: toHexString(i.public_key_hash),
+            scriptPubKey: i.script_pubkey,
           };
 
           return op;
@@ -49,6 +46,7 @@ export function parseBlock(data: any) {
         id: txn.id,
         inputs: transactionInputs,
         outputs: transactionOutputs,
+        lockTime: txn.lock_time,
       };
 
       return t;